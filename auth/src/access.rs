@@ -0,0 +1,149 @@
+//! `auth access`: who may log in on which hosts.
+
+use crate::{
+    config::Server,
+    connect::{connect, connect_as_admin, generous},
+};
+use argh::FromArgs;
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Manage who may log in where
+#[argh(subcommand, name = "access")]
+pub struct Access {
+    #[argh(subcommand)]
+    action: AccessAction,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+enum AccessAction {
+    Show(AccessShow),
+    Allow(AccessAllow),
+    Revoke(AccessRevoke),
+    HostGroup(AccessHostGroup),
+    Check(AccessCheck),
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Print the host groups and rules
+#[argh(subcommand, name = "show")]
+struct AccessShow {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Let members of a group log in on some hosts
+#[argh(subcommand, name = "allow")]
+struct AccessAllow {
+    #[argh(option)]
+    /// group whose members may log in
+    group: String,
+    #[argh(option)]
+    /// host name, pattern like lab*.cosi.clarkson.edu, or @host-group
+    on: String,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Remove a rule added with allow
+#[argh(subcommand, name = "revoke")]
+struct AccessRevoke {
+    #[argh(option)]
+    /// group the rule is for
+    group: String,
+    #[argh(option)]
+    /// hosts the rule is for, exactly as given to allow
+    on: String,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Add hosts to or remove hosts from a host group
+#[argh(subcommand, name = "host-group")]
+struct AccessHostGroup {
+    #[argh(option)]
+    /// name of the host group, which rules refer to as @name
+    name: String,
+    #[argh(option)]
+    /// host name or pattern to add, can be given more than once
+    add: Vec<String>,
+    #[argh(option)]
+    /// host name or pattern to remove, can be given more than once
+    remove: Vec<String>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Check whether a user may log in on a host
+#[argh(subcommand, name = "check")]
+struct AccessCheck {
+    #[argh(option)]
+    /// username
+    user: String,
+    #[argh(option)]
+    /// host name
+    on: String,
+}
+
+impl Access {
+    pub async fn run(self, server: &Server) -> anyhow::Result<()> {
+        if let AccessAction::Check(check) = &self.action {
+            let cl = connect(server).await?;
+            let allowed = cl
+                .check_access(generous(), check.user.clone(), check.on.clone())
+                .await??;
+            println!(
+                "{} {} log in on {}",
+                check.user,
+                if allowed { "may" } else { "may not" },
+                check.on
+            );
+            return Ok(());
+        }
+
+        let cl = connect_as_admin(server).await?;
+        let mut rules = cl.get_access_rules(generous()).await??;
+        match self.action {
+            AccessAction::Show(_) => {
+                for (name, hosts) in &rules.host_groups {
+                    println!("@{}: {}", name, hosts.join(" "));
+                }
+                for rule in &rules.rules {
+                    println!("{} may log in on {}", rule.group, rule.host);
+                }
+                return Ok(());
+            }
+            AccessAction::Allow(allow) => {
+                let rule = authd::access::AccessRule {
+                    group: allow.group,
+                    host: allow.on,
+                };
+                if rules.rules.contains(&rule) {
+                    println!("{} may already log in on {}", rule.group, rule.host);
+                    return Ok(());
+                }
+                rules.rules.push(rule);
+            }
+            AccessAction::Revoke(revoke) => {
+                let before = rules.rules.len();
+                rules
+                    .rules
+                    .retain(|r| !(r.group == revoke.group && r.host == revoke.on));
+                if rules.rules.len() == before {
+                    anyhow::bail!("there is no rule for {} on {}", revoke.group, revoke.on);
+                }
+            }
+            AccessAction::HostGroup(hg) => {
+                let hosts = rules.host_groups.entry(hg.name.clone()).or_default();
+                hosts.retain(|h| !hg.remove.contains(h));
+                for host in hg.add {
+                    if !hosts.contains(&host) {
+                        hosts.push(host);
+                    }
+                }
+                if hosts.is_empty() {
+                    rules.host_groups.remove(&hg.name);
+                }
+            }
+            AccessAction::Check(_) => unreachable!("handled above"),
+        }
+        cl.set_access_rules(generous(), rules).await??;
+        println!("access rules updated");
+        Ok(())
+    }
+}
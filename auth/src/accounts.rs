@@ -0,0 +1,291 @@
+//! Making accounts and managing their credentials: `auth create-user`, `enroll-host`, `admin`,
+//! `redeem-reset`, `enroll` and `totp`.
+
+use crate::{
+    config::Server,
+    connect::{connect, connect_as_admin, connect_with_password, enroll_totp, generous},
+    prompt::{new_password, prompt_line},
+};
+use argh::FromArgs;
+use authd::rpc::{AuthdClient, DefaultCipherSuite};
+use opaque_ke::ClientRegistrationFinishParameters;
+use std::{io::Write, os::unix::fs::OpenOptionsExt, path::PathBuf};
+use zeroize::Zeroizing;
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Create a new user
+#[argh(subcommand, name = "create-user")]
+pub struct CreateUser {
+    #[argh(switch)]
+    /// read the password from the first line of stdin instead of asking
+    password_stdin: bool,
+    #[argh(option)]
+    /// read the password from the first line of this file instead of asking
+    password_file: Option<PathBuf>,
+    #[argh(option)]
+    /// username
+    name: String,
+    #[argh(option)]
+    /// uid
+    uid: u32,
+    #[argh(option)]
+    /// name of shell to use
+    shell: String,
+    #[argh(option)]
+    /// path to home directory
+    homedir: String,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Give a lab machine its own login, so it can read shadow data
+#[argh(subcommand, name = "enroll-host")]
+pub struct EnrollHost {
+    #[argh(option)]
+    /// name the host will log in as
+    name: String,
+    #[argh(option)]
+    /// where to write the host's secret, e.g. /etc/auth/host.secret on the new host
+    secret_out: PathBuf,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Administrative tasks that need an admin login
+#[argh(subcommand, name = "admin")]
+pub struct Admin {
+    #[argh(subcommand)]
+    action: AdminAction,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+enum AdminAction {
+    IssueReset(IssueReset),
+    CreateInvite(CreateInvite),
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Make an invite code new members can enroll with
+#[argh(subcommand, name = "create-invite")]
+struct CreateInvite {
+    #[argh(option, default = "1")]
+    /// how many accounts the code can make, 1 by default
+    uses: u32,
+    #[argh(option, default = "168")]
+    /// how many hours the code is good for, a week by default
+    hours: u64,
+    #[argh(option)]
+    /// group to put new accounts in, can be given more than once
+    group: Vec<String>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Mint a one-time token that lets a user set a new password with redeem-reset
+#[argh(subcommand, name = "issue-reset")]
+struct IssueReset {
+    #[argh(positional)]
+    /// username
+    user: String,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Set a new password with a reset token from an admin
+#[argh(subcommand, name = "redeem-reset")]
+pub struct RedeemReset {
+    #[argh(switch)]
+    /// read the password from the first line of stdin instead of asking
+    password_stdin: bool,
+    #[argh(option)]
+    /// read the password from the first line of this file instead of asking
+    password_file: Option<PathBuf>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Make your own account with an invite code from an admin
+#[argh(subcommand, name = "enroll")]
+pub struct Enroll {
+    #[argh(switch)]
+    /// read the password from the first line of stdin instead of asking
+    password_stdin: bool,
+    #[argh(option)]
+    /// read the password from the first line of this file instead of asking
+    password_file: Option<PathBuf>,
+    #[argh(option)]
+    /// the invite code
+    invite: String,
+    #[argh(option)]
+    /// username to ask for, prompted for if not given
+    username: Option<String>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Manage TOTP second factors
+#[argh(subcommand, name = "totp")]
+pub struct Totp {
+    #[argh(subcommand)]
+    action: TotpAction,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+enum TotpAction {
+    Enroll(TotpEnroll),
+    Remove(TotpRemove),
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Log in and enroll (or re-enroll) an authenticator app
+#[argh(subcommand, name = "enroll")]
+struct TotpEnroll {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Forget a user's TOTP secret, so they can enroll again
+#[argh(subcommand, name = "remove")]
+struct TotpRemove {
+    #[argh(positional)]
+    /// username
+    user: String,
+}
+
+/// Register an OPAQUE credential for `name` on an admin connection.
+pub async fn register(
+    cl: &AuthdClient,
+    name: &str,
+    uid: Option<u32>,
+    password: &[u8],
+) -> anyhow::Result<()> {
+    let mut rng = opaque_ke::rand::rngs::OsRng;
+    let reg = opaque_ke::ClientRegistration::<DefaultCipherSuite>::start(&mut rng, password)
+        .expect("starting registration");
+    let reg_resp = cl
+        .register_new_user(generous(), name.to_owned(), uid, reg.message)
+        .await??;
+
+    let completed_reg = reg
+        .state
+        .finish(
+            &mut rng,
+            password,
+            reg_resp,
+            ClientRegistrationFinishParameters::default(),
+        )
+        .expect("finishing registration");
+    cl.finish_registration(generous(), completed_reg.message)
+        .await??;
+    Ok(())
+}
+
+impl CreateUser {
+    pub async fn run(self, server: &Server) -> anyhow::Result<()> {
+        let cl = connect_as_admin(server).await?;
+
+        let pwbytes = new_password(self.password_stdin, &self.password_file)?;
+        register(&cl, &self.name, Some(self.uid), &pwbytes).await?;
+        println!("registered new user {}!", self.name);
+        Ok(())
+    }
+}
+
+impl EnrollHost {
+    pub async fn run(self, server: &Server) -> anyhow::Result<()> {
+        let cl = connect_as_admin(server).await?;
+
+        let mut secret = [0u8; 32];
+        opaque_ke::rand::RngCore::fill_bytes(&mut opaque_ke::rand::rngs::OsRng, &mut secret);
+        let secret = Zeroizing::new(
+            secret
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>(),
+        );
+        register(&cl, &self.name, None, secret.as_bytes()).await?;
+
+        let mut f = std::fs::File::options()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&self.secret_out)?;
+        writeln!(f, "{}", *secret)?;
+        println!(
+            "enrolled host {}, now add it to the {} group",
+            self.name,
+            authd::policy::HOSTS_GROUP
+        );
+        Ok(())
+    }
+}
+
+impl Admin {
+    pub async fn run(self, server: &Server) -> anyhow::Result<()> {
+        let cl = connect_as_admin(server).await?;
+        match self.action {
+            AdminAction::IssueReset(issue) => {
+                let token = cl
+                    .issue_reset_token(generous(), issue.user.clone())
+                    .await??;
+                println!("reset token for {}, good for one use:", issue.user);
+                println!("{}", token);
+            }
+            AdminAction::CreateInvite(invite) => {
+                let code = cl
+                    .create_invite(generous(), invite.uses, invite.hours, invite.group)
+                    .await??;
+                println!(
+                    "invite code, good for {} accounts over {} hours:",
+                    invite.uses, invite.hours
+                );
+                println!("{}", code);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RedeemReset {
+    pub async fn run(self, server: &Server) -> anyhow::Result<()> {
+        let cl = connect(server).await?;
+        let token = Zeroizing::new(rpassword::prompt_password("reset token: ")?);
+        let pwbytes = new_password(self.password_stdin, &self.password_file)?;
+        let user = authd::client_redeem_reset(&cl, &token, &pwbytes).await?;
+        println!("new password set for {}", user);
+        Ok(())
+    }
+}
+
+impl Enroll {
+    pub async fn run(self, server: &Server) -> anyhow::Result<()> {
+        let cl = connect(server).await?;
+        let username = match self.username {
+            Some(username) => username,
+            None => prompt_line("username: ")?,
+        };
+        if let Err(why) = authd::invite::check_username(&username) {
+            anyhow::bail!(why);
+        }
+        let pwbytes = new_password(self.password_stdin, &self.password_file)?;
+        let passwd = authd::client_enroll(&cl, &self.invite, &username, &pwbytes).await?;
+        println!(
+            "welcome, {}! your uid is {} and your home is {}",
+            passwd.name, passwd.id, passwd.dir
+        );
+        Ok(())
+    }
+}
+
+impl Totp {
+    pub async fn run(self, server: &Server) -> anyhow::Result<()> {
+        match self.action {
+            TotpAction::Enroll(_) => {
+                let (cl, _) = connect_with_password(server).await?;
+                enroll_totp(&cl).await?;
+            }
+            TotpAction::Remove(remove) => {
+                let cl = connect_as_admin(server).await?;
+                if !cl.remove_totp(generous(), remove.user.clone()).await?? {
+                    anyhow::bail!("{} isn't enrolled in TOTP", remove.user);
+                }
+                println!("removed TOTP for {}", remove.user);
+            }
+        }
+        Ok(())
+    }
+}
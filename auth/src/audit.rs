@@ -0,0 +1,181 @@
+//! `auth audit`: reading authd's audit log, and checking nobody has tampered with it.
+
+use crate::{
+    config::Server,
+    connect::{connect_as_admin, generous},
+};
+use argh::FromArgs;
+use authd::{
+    audit::{AuditEntry, Outcome},
+    rpc::AuthdClient,
+};
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Read and verify authd's audit log
+#[argh(subcommand, name = "audit")]
+pub struct AuditCmd {
+    #[argh(subcommand)]
+    action: AuditAction,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+enum AuditAction {
+    List(AuditList),
+    Verify(AuditVerify),
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// List audit log entries, optionally only some of them
+#[argh(subcommand, name = "list")]
+struct AuditList {
+    #[argh(option, default = "0")]
+    /// only entries after this sequence number
+    after: u64,
+    #[argh(option)]
+    /// only things this user did
+    actor: Option<String>,
+    #[argh(option)]
+    /// only this RPC, e.g. finish_login or create_account
+    action: Option<String>,
+    #[argh(option)]
+    /// only things done to this user or host
+    target: Option<String>,
+    #[argh(switch)]
+    /// only failures
+    failures: bool,
+    #[argh(switch)]
+    /// print JSON instead of a table
+    json: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Check the audit log's hash chain, printing its head to check against next time
+#[argh(subcommand, name = "verify")]
+struct AuditVerify {
+    #[argh(option)]
+    /// seq:hash printed by an earlier verify, which has to still be in the log
+    head: Option<String>,
+}
+
+impl AuditCmd {
+    pub async fn run(self, server: &Server) -> anyhow::Result<()> {
+        let cl = connect_as_admin(server).await?;
+        match self.action {
+            AuditAction::List(list) => {
+                let filter = AuditFilter {
+                    actor: list.actor,
+                    action: list.action,
+                    target: list.target,
+                    failures: list.failures,
+                };
+                let entries = self::list(&cl, list.after, &filter).await?;
+                if list.json {
+                    println!("{}", serde_json::to_string_pretty(&entries)?);
+                } else {
+                    print_entries(&entries);
+                }
+            }
+            AuditAction::Verify(verify) => {
+                let head = self::verify(&cl, verify.head.as_deref()).await?;
+                println!("audit log intact, head is {}", head);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What `auth audit list` should leave out.
+pub struct AuditFilter {
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    pub target: Option<String>,
+    pub failures: bool,
+}
+
+impl AuditFilter {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        let is = |want: &Option<String>, got: &Option<String>| match want {
+            Some(want) => got.as_ref() == Some(want),
+            None => true,
+        };
+        is(&self.actor, &entry.actor)
+            && is(&self.target, &entry.target)
+            && is(&self.action, &Some(entry.action.clone()))
+            && (!self.failures || matches!(entry.outcome, Outcome::Failure(_)))
+    }
+}
+
+pub async fn list(
+    cl: &AuthdClient,
+    after: u64,
+    filter: &AuditFilter,
+) -> anyhow::Result<Vec<AuditEntry>> {
+    let entries = cl.get_audit_log(generous(), after).await??;
+    Ok(entries.into_iter().filter(|e| filter.matches(e)).collect())
+}
+
+/// Fetch the whole log and check its chain. With `head` (`seq:hash`, as printed by an earlier
+/// run), also check that entry is still there unchanged, which catches the tail being rewritten.
+/// Returns the head to remember for next time.
+pub async fn verify(cl: &AuthdClient, head: Option<&str>) -> anyhow::Result<String> {
+    let entries = cl.get_audit_log(generous(), 0).await??;
+    authd::audit::verify(&entries, authd::audit::GENESIS).map_err(|e| anyhow::anyhow!(e))?;
+    if let Some(head) = head {
+        let (seq, hash) = head
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("--head should be seq:hash"))?;
+        let seq: u64 = seq.parse()?;
+        match entries.iter().find(|e| e.seq == seq) {
+            Some(entry) if entry.hash == hash => {}
+            Some(_) => anyhow::bail!(
+                "entry {} is not the one recorded, the log was rewritten",
+                seq
+            ),
+            None => anyhow::bail!("entry {} is gone, the log was truncated", seq),
+        }
+    }
+    Ok(match entries.last() {
+        Some(last) => format!("{}:{}", last.seq, last.hash),
+        None => format!("0:{}", authd::audit::GENESIS),
+    })
+}
+
+fn time(secs: u64) -> String {
+    chrono::NaiveDateTime::from_timestamp(secs as i64, 0)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
+}
+
+pub fn print_entries(entries: &[AuditEntry]) {
+    let or_dash = |x: &Option<String>| x.clone().unwrap_or_else(|| "-".into());
+    let rows: Vec<Vec<String>> = entries
+        .iter()
+        .map(|e| {
+            vec![
+                e.seq.to_string(),
+                time(e.time),
+                or_dash(&e.actor),
+                e.peer.clone(),
+                e.action.clone(),
+                or_dash(&e.target),
+                match &e.outcome {
+                    Outcome::Success => "ok".into(),
+                    Outcome::Failure(why) => why.clone(),
+                },
+            ]
+        })
+        .collect();
+    crate::directory::print_table(
+        &[
+            "SEQ",
+            "TIME (UTC)",
+            "ACTOR",
+            "PEER",
+            "ACTION",
+            "TARGET",
+            "OUTCOME",
+        ],
+        &rows,
+    );
+}
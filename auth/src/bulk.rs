@@ -0,0 +1,193 @@
+//! `auth bulk-create`: make a semester's worth of accounts from a roster, and report what happened
+//! to each.
+
+use crate::{
+    accounts::register,
+    config::Server,
+    connect::{connect_as_admin, generous},
+};
+use argh::FromArgs;
+use authd::rpc::{AuthdClient, NewAccount};
+use serde::{Deserialize, Serialize};
+use std::{
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+use zeroize::Zeroizing;
+
+/// Characters for generated passwords, without the ones that look like each other.
+const PASSWORD_ALPHABET: &[u8] = b"abcdefghijkmnpqrstuvwxyzABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const PASSWORD_LEN: usize = 16;
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Create many accounts from a CSV or TOML roster
+#[argh(subcommand, name = "bulk-create")]
+pub struct BulkCreate {
+    #[argh(positional)]
+    /// roster of accounts, a .csv or .toml file
+    roster: PathBuf,
+    #[argh(option)]
+    /// where to write the report of what happened, which has the new passwords in it
+    report: PathBuf,
+    #[argh(switch)]
+    /// give each account a reset token instead of a random password
+    reset_tokens: bool,
+}
+
+impl BulkCreate {
+    pub async fn run(self, server: &Server) -> anyhow::Result<()> {
+        let roster = load_roster(&self.roster)?;
+        if self.report.exists() {
+            anyhow::bail!("{} already exists", self.report.display());
+        }
+        let cl = connect_as_admin(server).await?;
+        let outcomes = create_all(&cl, roster, self.reset_tokens).await;
+        write_report(&self.report, &outcomes)?;
+        let failed = outcomes.iter().filter(|o| o.error.is_some()).count();
+        println!(
+            "created {} of {} accounts, see {}",
+            outcomes.len() - failed,
+            outcomes.len(),
+            self.report.display()
+        );
+        if failed > 0 {
+            anyhow::bail!("{} accounts failed", failed);
+        }
+        Ok(())
+    }
+}
+
+/// One roster line in a CSV file. `groups` is separated by spaces, since commas are taken.
+#[derive(Debug, Deserialize)]
+struct CsvEntry {
+    name: String,
+    uid: Option<u32>,
+    #[serde(default)]
+    gecos: String,
+    shell: Option<String>,
+    home: Option<String>,
+    #[serde(default)]
+    groups: String,
+}
+
+/// A TOML roster: a `[[users]]` table per account, with the same fields as [`NewAccount`].
+#[derive(Debug, Deserialize)]
+struct TomlRoster {
+    users: Vec<NewAccount>,
+}
+
+/// What happened to one account, as a line of the report.
+#[derive(Debug, Serialize)]
+pub struct Outcome {
+    pub name: String,
+    pub uid: Option<u32>,
+    /// The generated password, if the account got one.
+    pub password: Option<String>,
+    /// The reset token, if the account got one instead.
+    pub reset_token: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Read a roster, going by its extension: `.csv` with a header line, or `.toml`.
+pub fn load_roster(path: &Path) -> anyhow::Result<Vec<NewAccount>> {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("csv") => {
+            let mut reader = csv::ReaderBuilder::new()
+                .trim(csv::Trim::All)
+                .from_path(path)?;
+            reader
+                .deserialize::<CsvEntry>()
+                .map(|entry| {
+                    let entry = entry?;
+                    Ok(NewAccount {
+                        name: entry.name,
+                        uid: entry.uid,
+                        gecos: entry.gecos,
+                        home: entry.home.filter(|h| !h.is_empty()),
+                        shell: entry.shell.filter(|s| !s.is_empty()),
+                        groups: entry.groups.split_whitespace().map(Into::into).collect(),
+                    })
+                })
+                .collect()
+        }
+        Some("toml") => Ok(toml::from_slice::<TomlRoster>(&std::fs::read(path)?)?.users),
+        _ => anyhow::bail!("{} should be a .csv or .toml roster", path.display()),
+    }
+}
+
+fn random_password() -> Zeroizing<String> {
+    let mut rng = opaque_ke::rand::rngs::OsRng;
+    let mut password = Zeroizing::new(String::with_capacity(PASSWORD_LEN));
+    // throwing away the bytes past the last whole alphabet keeps every character equally likely
+    let limit = 256 - 256 % PASSWORD_ALPHABET.len();
+    while password.len() < PASSWORD_LEN {
+        let mut byte = [0u8; 1];
+        opaque_ke::rand::RngCore::fill_bytes(&mut rng, &mut byte);
+        let byte = byte[0] as usize;
+        if byte < limit {
+            password.push(PASSWORD_ALPHABET[byte % PASSWORD_ALPHABET.len()] as char);
+        }
+    }
+    password
+}
+
+/// Make one account and give it a credential, or a reset token to set one with.
+async fn create_one(
+    cl: &AuthdClient,
+    account: NewAccount,
+    reset_tokens: bool,
+    outcome: &mut Outcome,
+) -> anyhow::Result<()> {
+    let passwd = cl.create_account(generous(), account).await??;
+    outcome.uid = Some(passwd.id);
+    if reset_tokens {
+        let token = cl
+            .issue_reset_token(generous(), passwd.name.clone())
+            .await??;
+        outcome.reset_token = Some(token);
+    } else {
+        let password = random_password();
+        register(cl, &passwd.name, Some(passwd.id), password.as_bytes()).await?;
+        outcome.password = Some(password.to_string());
+    }
+    Ok(())
+}
+
+/// Create every account in `roster`, carrying on past failures, and say what happened to each.
+pub async fn create_all(
+    cl: &AuthdClient,
+    roster: Vec<NewAccount>,
+    reset_tokens: bool,
+) -> Vec<Outcome> {
+    let mut outcomes = vec![];
+    for account in roster {
+        let mut outcome = Outcome {
+            name: account.name.clone(),
+            uid: account.uid,
+            password: None,
+            reset_token: None,
+            error: None,
+        };
+        if let Err(e) = create_one(cl, account, reset_tokens, &mut outcome).await {
+            eprintln!("{}: {}", outcome.name, e);
+            outcome.error = Some(e.to_string());
+        }
+        outcomes.push(outcome);
+    }
+    outcomes
+}
+
+/// Write the report as CSV, readable only by whoever ran this, since it has passwords in it.
+pub fn write_report(path: &Path, outcomes: &[Outcome]) -> anyhow::Result<()> {
+    let f = std::fs::File::options()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    let mut writer = csv::Writer::from_writer(f);
+    for outcome in outcomes {
+        writer.serialize(outcome)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
@@ -0,0 +1,110 @@
+//! `auth.toml`: named authd servers, so the connection flags don't have to be typed every time.
+
+use authd::{tls::ServerTrust, SocketName};
+use serde::Deserialize;
+use std::{collections::BTreeMap, path::PathBuf};
+
+#[derive(Debug, Default, Deserialize)]
+pub struct AuthConfig {
+    /// Profile used when `--profile` isn't given. Not needed if there is only one.
+    pub default_profile: Option<String>,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Profile {
+    /// authd address and port.
+    pub host: Option<SocketName>,
+    /// How to trust it, with the same keys as `nss_cosiauthd.toml`.
+    #[serde(flatten)]
+    pub trust: ServerTrust,
+    /// Who to log in as, instead of asking.
+    pub username: Option<String>,
+}
+
+impl AuthConfig {
+    /// Read `auth.toml` from the config directory. No file is the same as an empty one.
+    pub fn load() -> anyhow::Result<Self> {
+        let path = authd::find_config_dir()?.join("auth.toml");
+        let mut config: AuthConfig = match std::fs::read(&path) {
+            Ok(contents) => toml::from_slice(&contents)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e.into()),
+        };
+        for profile in config.profiles.values_mut() {
+            profile.trust.expand();
+        }
+        Ok(config)
+    }
+
+    /// Take out the profile called `name`, or the default one.
+    pub fn take_profile(&mut self, name: Option<&str>) -> anyhow::Result<Profile> {
+        let name = match name.or(self.default_profile.as_deref()) {
+            Some(name) => name.to_owned(),
+            None if self.profiles.len() == 1 => self.profiles.keys().next().unwrap().clone(),
+            None => return Ok(Profile::default()),
+        };
+        self.profiles
+            .remove(&name)
+            .ok_or_else(|| anyhow::anyhow!("no profile called {} in auth.toml", name))
+    }
+}
+
+/// Where and how to reach authd, from the flags and `auth.toml`.
+pub struct Server {
+    pub host: SocketName,
+    pub trust: ServerTrust,
+    pub username: Option<String>,
+}
+
+impl Server {
+    /// `host:port`, to tell servers apart by.
+    pub fn key(&self) -> String {
+        match &self.host {
+            SocketName::Dns(host, port) => format!("{}:{}", host, port),
+            SocketName::Addr(addr) => addr.to_string(),
+        }
+    }
+
+    /// Combine the connection flags given before the subcommand with the profile they pick.
+    /// Flags win: any of `cert`, `spki_pin` or `ca_bundle` replaces all of the profile's trust
+    /// settings, since pins and CAs can't be mixed.
+    pub fn from_flags(
+        profile: &Option<String>,
+        host: &Option<SocketName>,
+        cert: &[PathBuf],
+        spki_pin: &[String],
+        ca_bundle: &Option<PathBuf>,
+        server_name: &Option<String>,
+    ) -> anyhow::Result<Self> {
+        let profile = AuthConfig::load()?.take_profile(profile.as_deref())?;
+        let host = match (host, profile.host) {
+            (Some(host), _) => host.clone(),
+            (None, Some(host)) => host,
+            (None, None) => {
+                anyhow::bail!("no authd to talk to: pass --host or set up a profile in auth.toml")
+            }
+        };
+        let mut trust = if cert.is_empty() && spki_pin.is_empty() && ca_bundle.is_none() {
+            profile.trust
+        } else {
+            let path = |p: &PathBuf| p.to_string_lossy().into_owned();
+            ServerTrust {
+                server_name: profile.trust.server_name,
+                cert: None,
+                certs: cert.iter().map(path).collect(),
+                spki_pins: spki_pin.to_vec(),
+                ca_bundle: ca_bundle.as_ref().map(path),
+            }
+        };
+        if server_name.is_some() {
+            trust.server_name = server_name.clone();
+        }
+        Ok(Server {
+            host,
+            trust,
+            username: profile.username,
+        })
+    }
+}
@@ -0,0 +1,146 @@
+//! Reaching authd and logging in to it, for the commands that need to.
+
+use crate::{config::Server, prompt::prompt_line, session::SessionCache};
+use anyhow::Context;
+use authd::rpc::AuthdClient;
+use std::net::ToSocketAddrs;
+use zeroize::Zeroizing;
+
+/// Connect to authd without logging in.
+pub async fn connect(server: &Server) -> anyhow::Result<AuthdClient> {
+    let addr = server
+        .host
+        .to_socket_addrs()
+        .with_context(|| format!("resolving {}", server.key()))?
+        .next()
+        .with_context(|| format!("{} has no addresses", server.key()))?;
+    authd::client_connect(
+        addr,
+        &server.trust,
+        &server.trust.server_name_for(&server.host),
+    )
+    .await
+    .with_context(|| format!("connecting to authd at {}", addr))
+}
+
+/// Who to log in as: the profile's username, or whoever is at the keyboard says.
+pub fn username_for(server: &Server, prompt: &str) -> anyhow::Result<String> {
+    match &server.username {
+        Some(username) => Ok(username.clone()),
+        None => rpassword::prompt_password(prompt).context("reading the username"),
+    }
+}
+
+/// Log in with the session `auth login` left for this server, if it still works, returning who
+/// that is.
+pub async fn resume_session(cl: &AuthdClient, server: &Server) -> Option<String> {
+    let mut cache = SessionCache::load().ok()?;
+    let cached = cache.get(&server.key())?.clone();
+    match cl.resume_session(generous(), cached.token).await.ok()? {
+        Ok(username) => Some(username),
+        Err(_) => {
+            // revoked, or authd restarted
+            cache.sessions.remove(&server.key());
+            let _ = cache.save();
+            None
+        }
+    }
+}
+
+/// Connect to authd and log in as an admin, with the cached session or else by prompting for the
+/// credentials.
+pub async fn connect_as_admin(server: &Server) -> anyhow::Result<AuthdClient> {
+    let cl = connect(server).await?;
+    if let Some(admin_user) = resume_session(&cl, server).await {
+        println!("welcome back to authd, {}", admin_user);
+        return Ok(cl);
+    }
+
+    let admin_user = username_for(server, "admin username: ")?;
+    let admin_pass = Zeroizing::new(
+        rpassword::prompt_password("admin password: ")
+            .context("reading the admin password")?
+            .into_bytes(),
+    );
+
+    login_interactively(&cl, &admin_user, &admin_pass)
+        .await
+        .with_context(|| format!("logging in as {}", admin_user))?;
+
+    println!("welcome back to authd, {}", admin_user);
+    Ok(cl)
+}
+
+/// Connect to authd and log in as whoever is at the keyboard, returning who that was. The cached
+/// session is used if there is one.
+pub async fn connect_as_user(server: &Server) -> anyhow::Result<(AuthdClient, String)> {
+    let cl = connect(server).await?;
+    if let Some(user) = resume_session(&cl, server).await {
+        return Ok((cl, user));
+    }
+    log_in_as_user(cl, server).await
+}
+
+/// Like `connect_as_user`, but always asking for the password, which authd wants before
+/// changing credentials.
+pub async fn connect_with_password(server: &Server) -> anyhow::Result<(AuthdClient, String)> {
+    let cl = connect(server).await?;
+    log_in_as_user(cl, server).await
+}
+
+pub async fn log_in_as_user(
+    cl: AuthdClient,
+    server: &Server,
+) -> anyhow::Result<(AuthdClient, String)> {
+    let user = username_for(server, "username: ")?;
+    let pass = Zeroizing::new(
+        rpassword::prompt_password("password: ")
+            .context("reading the password")?
+            .into_bytes(),
+    );
+    login_interactively(&cl, &user, &pass).await?;
+    Ok((cl, user))
+}
+
+/// Log in, asking for a TOTP code or enrolling if the account needs that too.
+pub async fn login_interactively(cl: &AuthdClient, user: &str, pass: &[u8]) -> anyhow::Result<()> {
+    match authd::client_login(cl, user, pass).await {
+        Ok(()) => Ok(()),
+        Err(authd::LoginError::TotpRequired) => {
+            let code = prompt_line("TOTP code: ")?;
+            cl.verify_totp(generous(), code).await??;
+            Ok(())
+        }
+        Err(authd::LoginError::TotpEnrollmentRequired) => {
+            println!("{} has to enroll in TOTP before logging in.", user);
+            enroll_totp(cl).await
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Enroll whoever is (or is partway through being) logged in on `cl` in TOTP.
+pub async fn enroll_totp(cl: &AuthdClient) -> anyhow::Result<()> {
+    let uri = cl.start_totp_enrollment(generous()).await??;
+    println!("Add this to your authenticator app, or turn it into a QR code for it to scan:");
+    println!("{}", uri);
+    loop {
+        let code = prompt_line("code from the app: ")?;
+        match cl.confirm_totp_enrollment(generous(), code).await? {
+            Ok(()) => break,
+            Err(authd::rpc::RpcError::AuthenticationFailure) => {
+                eprintln!("That code isn't right, try the next one")
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    println!("enrolled in TOTP");
+    Ok(())
+}
+
+/// A deadline long enough for someone to type a password or TOTP code.
+pub fn generous() -> tarpc::context::Context {
+    let mut ctx = tarpc::context::current();
+    ctx.deadline = std::time::SystemTime::now() + std::time::Duration::from_secs(60);
+    ctx
+}
@@ -0,0 +1,439 @@
+//! `auth user` and `auth group`: looking at the directory without reading authd's files or running
+//! `getent` on a host.
+
+use crate::{
+    config::Server,
+    connect::{connect, generous, resume_session},
+};
+use argh::FromArgs;
+use authd::{
+    rpc::{AuthdClient, RpcError},
+    types::{AccountStatus, Group, Passwd, Shadow},
+};
+use serde::Serialize;
+
+/// Shadow's "never", which `auth local-create-user` and enrollment write for the maximum age.
+const NEVER: i64 = 99999;
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Look at users in the directory
+#[argh(subcommand, name = "user")]
+pub struct UserCmd {
+    #[argh(switch)]
+    /// print JSON instead of a table
+    json: bool,
+    #[argh(subcommand)]
+    action: UserAction,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+enum UserAction {
+    List(UserList),
+    Show(UserShow),
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// List users, optionally only some of them
+#[argh(subcommand, name = "list")]
+struct UserList {
+    #[argh(option)]
+    /// only members of this group
+    group: Option<String>,
+    #[argh(option)]
+    /// only UIDs at least this
+    min_uid: Option<u32>,
+    #[argh(option)]
+    /// only UIDs at most this
+    max_uid: Option<u32>,
+    #[argh(switch)]
+    /// only accounts that are expired or whose password is
+    expired: bool,
+    #[argh(switch)]
+    /// only locked accounts
+    locked: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Show everything about one user
+#[argh(subcommand, name = "show")]
+struct UserShow {
+    #[argh(positional)]
+    /// username
+    name: String,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Look at groups in the directory
+#[argh(subcommand, name = "group")]
+pub struct GroupCmd {
+    #[argh(switch)]
+    /// print JSON instead of a table
+    json: bool,
+    #[argh(subcommand)]
+    action: GroupAction,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+enum GroupAction {
+    List(GroupList),
+    Show(GroupShow),
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// List groups
+#[argh(subcommand, name = "list")]
+struct GroupList {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Show one group and who is in it
+#[argh(subcommand, name = "show")]
+struct GroupShow {
+    #[argh(positional)]
+    /// group name
+    name: String,
+}
+
+impl UserCmd {
+    pub async fn run(self, server: &Server) -> anyhow::Result<()> {
+        let cl = connect(server).await?;
+        // shadow is only there for whoever auth login made us
+        resume_session(&cl, server).await;
+        match self.action {
+            UserAction::List(list) => {
+                let filter = UserFilter {
+                    group: list.group,
+                    min_uid: list.min_uid,
+                    max_uid: list.max_uid,
+                    expired: list.expired,
+                    locked: list.locked,
+                };
+                let users = list_users(&cl, &filter).await?;
+                if self.json {
+                    println!("{}", serde_json::to_string_pretty(&users)?);
+                } else {
+                    print_users(&users);
+                }
+            }
+            UserAction::Show(show) => {
+                let info = show_user(&cl, &show.name).await?;
+                if self.json {
+                    println!("{}", serde_json::to_string_pretty(&info)?);
+                } else {
+                    print_user(&info);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl GroupCmd {
+    pub async fn run(self, server: &Server) -> anyhow::Result<()> {
+        let cl = connect(server).await?;
+        resume_session(&cl, server).await;
+        match self.action {
+            GroupAction::List(_) => {
+                let groups = list_groups(&cl).await?;
+                if self.json {
+                    println!("{}", serde_json::to_string_pretty(&groups)?);
+                } else {
+                    print_groups(&groups);
+                }
+            }
+            GroupAction::Show(show) => {
+                let info = show_group(&cl, &show.name).await?;
+                if self.json {
+                    println!("{}", serde_json::to_string_pretty(&info)?);
+                } else {
+                    print_group(&info);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A user the way `auth user` shows them.
+#[derive(Debug, Serialize)]
+pub struct UserInfo {
+    #[serde(flatten)]
+    pub passwd: Passwd,
+    pub groups: Vec<String>,
+    /// Left out if this session may not read shadow.
+    pub aging: Option<Aging>,
+    /// Only filled in by `show`, and only if this session may read shadow.
+    pub has_credential: Option<bool>,
+}
+
+/// Shadow's aging fields, with days since 1970 turned into dates.
+#[derive(Debug, Serialize)]
+pub struct Aging {
+    /// `None` if the password has to be changed at the next login.
+    pub last_change: Option<String>,
+    pub min_days: i64,
+    /// `None` if the password never has to be changed.
+    pub max_days: Option<i64>,
+    pub warn_days: i64,
+    pub inactive_days: Option<i64>,
+    pub password_expires: Option<String>,
+    pub account_expires: Option<String>,
+    pub locked: bool,
+    pub status: AccountStatus,
+}
+
+impl Aging {
+    fn new(shadow: &Shadow) -> Self {
+        let max_days = Some(shadow.change_max_days).filter(|&d| (0..NEVER).contains(&d));
+        // 0 means a change is due, and an empty (negative) one that there's no aging at all
+        let last_change = Some(shadow.last_change).filter(|&d| d > 0);
+        Aging {
+            last_change: last_change.map(date),
+            min_days: shadow.change_min_days,
+            max_days,
+            warn_days: shadow.change_warn_days,
+            inactive_days: shadow.change_inactive_days,
+            password_expires: last_change.zip(max_days).map(|(l, m)| date(l + m)),
+            account_expires: shadow.expire_date.map(date),
+            locked: shadow.is_locked(),
+            status: shadow.aging_status(authd::types::today()),
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        matches!(
+            self.status,
+            AccountStatus::PasswordExpired | AccountStatus::AccountExpired
+        )
+    }
+}
+
+/// A group the way `auth group` shows it.
+#[derive(Debug, Serialize)]
+pub struct GroupInfo {
+    pub name: String,
+    pub gid: u32,
+    /// Listed members.
+    pub members: Vec<String>,
+    /// Users whose own group this is.
+    pub primary_for: Vec<String>,
+}
+
+/// What `auth user list` should leave out.
+pub struct UserFilter {
+    pub group: Option<String>,
+    pub min_uid: Option<u32>,
+    pub max_uid: Option<u32>,
+    pub expired: bool,
+    pub locked: bool,
+}
+
+/// Day `day` since 1970 as a date.
+fn date(day: i64) -> String {
+    (chrono::NaiveDate::from_ymd(1970, 1, 1) + chrono::Duration::days(day)).to_string()
+}
+
+/// `Some` if the read policy let us see it, `None` if it didn't.
+fn readable<T>(result: Result<T, RpcError>) -> anyhow::Result<Option<T>> {
+    match result {
+        Ok(data) => Ok(Some(data)),
+        Err(RpcError::NotAuthorized) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Groups read back from the group file have one empty member when they have none.
+fn members(group: &Group) -> Vec<String> {
+    group
+        .members
+        .iter()
+        .filter(|m| !m.is_empty())
+        .cloned()
+        .collect()
+}
+
+fn groups_of(passwd: &Passwd, groups: &[Group]) -> Vec<String> {
+    groups
+        .iter()
+        .filter(|g| g.gid == passwd.id || g.members.contains(&passwd.name))
+        .map(|g| g.name.clone())
+        .collect()
+}
+
+fn user_info(passwd: Passwd, groups: &[Group], shadow: Option<&[Shadow]>) -> UserInfo {
+    let aging = shadow
+        .and_then(|shadow| shadow.iter().find(|s| s.name == passwd.name))
+        .map(Aging::new);
+    UserInfo {
+        groups: groups_of(&passwd, groups),
+        passwd,
+        aging,
+        has_credential: None,
+    }
+}
+
+fn group_info(group: &Group, passwd: &[Passwd]) -> GroupInfo {
+    GroupInfo {
+        name: group.name.clone(),
+        gid: group.gid,
+        members: members(group),
+        primary_for: passwd
+            .iter()
+            .filter(|p| p.id == group.gid)
+            .map(|p| p.name.clone())
+            .collect(),
+    }
+}
+
+pub async fn list_users(cl: &AuthdClient, filter: &UserFilter) -> anyhow::Result<Vec<UserInfo>> {
+    let passwd = cl.get_all_passwd(generous()).await??;
+    let groups = cl.get_all_groups(generous()).await??;
+    let shadow = readable(cl.get_all_shadow(generous()).await?)?;
+    if shadow.is_none() && (filter.expired || filter.locked) {
+        anyhow::bail!("filtering on aging needs shadow, log in as an admin or host first");
+    }
+
+    Ok(passwd
+        .into_iter()
+        .filter(|p| filter.min_uid.map(|min| p.id >= min).unwrap_or(true))
+        .filter(|p| filter.max_uid.map(|max| p.id <= max).unwrap_or(true))
+        .map(|p| user_info(p, &groups, shadow.as_deref()))
+        .filter(|u| match &filter.group {
+            Some(group) => u.groups.contains(group),
+            None => true,
+        })
+        .filter(|u| !filter.expired || u.aging.as_ref().map(Aging::is_expired).unwrap_or(false))
+        .filter(|u| !filter.locked || u.aging.as_ref().map(|a| a.locked).unwrap_or(false))
+        .collect())
+}
+
+pub async fn show_user(cl: &AuthdClient, name: &str) -> anyhow::Result<UserInfo> {
+    let passwd = cl
+        .get_passwd_by_name(generous(), name.into())
+        .await??
+        .ok_or_else(|| anyhow::anyhow!("no user called {}", name))?;
+    let groups = cl.get_all_groups(generous()).await??;
+    let shadow = readable(cl.get_shadow_by_name(generous(), name.into()).await?)?
+        .map(|s| s.into_iter().collect::<Vec<_>>());
+    let mut info = user_info(passwd, &groups, shadow.as_deref());
+    info.has_credential = readable(cl.has_credential(generous(), name.into()).await?)?;
+    Ok(info)
+}
+
+pub async fn list_groups(cl: &AuthdClient) -> anyhow::Result<Vec<GroupInfo>> {
+    let passwd = cl.get_all_passwd(generous()).await??;
+    let groups = cl.get_all_groups(generous()).await??;
+    Ok(groups.iter().map(|g| group_info(g, &passwd)).collect())
+}
+
+pub async fn show_group(cl: &AuthdClient, name: &str) -> anyhow::Result<GroupInfo> {
+    let group = cl
+        .get_group_by_name(generous(), name.into())
+        .await??
+        .ok_or_else(|| anyhow::anyhow!("no group called {}", name))?;
+    let passwd = cl.get_all_passwd(generous()).await??;
+    Ok(group_info(&group, &passwd))
+}
+
+fn status(aging: Option<&Aging>) -> String {
+    match aging.map(|a| a.status) {
+        None => "-".into(),
+        Some(AccountStatus::Ok) => "ok".into(),
+        Some(AccountStatus::PasswordExpiresSoon(days)) => format!("expires in {} days", days),
+        Some(AccountStatus::PasswordExpired) => "password expired".into(),
+        Some(AccountStatus::AccountExpired) => "expired".into(),
+        Some(AccountStatus::Locked) => "locked".into(),
+        Some(other) => format!("{:?}", other),
+    }
+}
+
+/// Print `rows` under `header`, in columns as wide as their widest cell.
+pub fn print_table(header: &[&str], rows: &[Vec<String>]) {
+    let mut widths: Vec<usize> = header.iter().map(|h| h.len()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let line = |cells: Vec<&str>| {
+        let padded: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = *width))
+            .collect();
+        println!("{}", padded.join("  ").trim_end());
+    };
+    line(header.to_vec());
+    for row in rows {
+        line(row.iter().map(String::as_str).collect());
+    }
+}
+
+pub fn print_users(users: &[UserInfo]) {
+    let rows: Vec<Vec<String>> = users
+        .iter()
+        .map(|u| {
+            vec![
+                u.passwd.name.clone(),
+                u.passwd.id.to_string(),
+                u.passwd.dir.clone(),
+                u.passwd.shell.clone(),
+                status(u.aging.as_ref()),
+                u.groups.join(","),
+            ]
+        })
+        .collect();
+    print_table(&["NAME", "UID", "HOME", "SHELL", "STATUS", "GROUPS"], &rows);
+}
+
+pub fn print_user(user: &UserInfo) {
+    let or_dash = |x: &Option<String>| x.clone().unwrap_or_else(|| "-".into());
+    println!("name:             {}", user.passwd.name);
+    println!("uid:              {}", user.passwd.id);
+    println!("gecos:            {}", user.passwd.gecos);
+    println!("home:             {}", user.passwd.dir);
+    println!("shell:            {}", user.passwd.shell);
+    println!("groups:           {}", user.groups.join(", "));
+    if let Some(has_credential) = user.has_credential {
+        let yes_no = if has_credential { "yes" } else { "no" };
+        println!("credential:       {}", yes_no);
+    }
+    match &user.aging {
+        Some(aging) => {
+            let last_change = match &aging.last_change {
+                Some(day) => day.clone(),
+                None => "never, must change at next login".into(),
+            };
+            println!("status:           {}", status(user.aging.as_ref()));
+            println!("last change:      {}", last_change);
+            println!("password expires: {}", or_dash(&aging.password_expires));
+            println!("account expires:  {}", or_dash(&aging.account_expires));
+            println!(
+                "min/max/warn:     {}/{}/{} days",
+                aging.min_days,
+                aging
+                    .max_days
+                    .map(|d| d.to_string())
+                    .unwrap_or_else(|| "-".into()),
+                aging.warn_days
+            );
+        }
+        None => println!("aging:            not readable without logging in as an admin or host"),
+    }
+}
+
+pub fn print_groups(groups: &[GroupInfo]) {
+    let rows: Vec<Vec<String>> = groups
+        .iter()
+        .map(|g| vec![g.name.clone(), g.gid.to_string(), g.members.join(",")])
+        .collect();
+    print_table(&["NAME", "GID", "MEMBERS"], &rows);
+}
+
+pub fn print_group(group: &GroupInfo) {
+    println!("name:        {}", group.name);
+    println!("gid:         {}", group.gid);
+    println!("members:     {}", group.members.join(", "));
+    println!("primary for: {}", group.primary_for.join(", "));
+}
@@ -0,0 +1,370 @@
+//! `auth doctor`: the checks we used to do by hand when a lab machine couldn't see users, in the
+//! order things break in. Everything goes through the same config, connection and RPCs the NSS
+//! module uses, so a pass here means the module should work too.
+
+use crate::connect::generous;
+use argh::FromArgs;
+use authd::{nss::NssConfig, rpc::AuthdClient};
+use std::{net::SocketAddr, net::ToSocketAddrs, path::PathBuf, time::Duration};
+
+/// How long to wait for authd, since `client_connect` retries forever.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// What the module has to be called for glibc to find it.
+const MODULE_NAME: &str = "libnss_cosiauthd.so.2";
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Check this host's NSS setup, from nss_cosiauthd.toml to a real lookup
+#[argh(subcommand, name = "doctor")]
+pub struct DoctorCmd {
+    #[argh(option)]
+    /// user to look up through NSS, instead of the first one authd lists
+    user: Option<String>,
+}
+
+impl DoctorCmd {
+    pub async fn run(self) -> anyhow::Result<()> {
+        run(self.user.as_deref()).await
+    }
+}
+
+/// Tally of how the checks went.
+#[derive(Default)]
+struct Doctor {
+    failed: usize,
+}
+
+impl Doctor {
+    fn pass(&self, check: &str, detail: impl std::fmt::Display) {
+        println!("[ ok ] {}: {}", check, detail);
+    }
+
+    fn fail(&mut self, check: &str, problem: impl std::fmt::Display, fix: impl std::fmt::Display) {
+        self.failed += 1;
+        println!("[FAIL] {}: {}", check, problem);
+        println!("       fix: {}", fix);
+    }
+
+    /// A check that can't run because one it depends on failed.
+    fn skip(&self, check: &str, because: &str) {
+        println!("[skip] {}: needs {}", check, because);
+    }
+}
+
+/// Where glibc looks for NSS modules on the distributions we run.
+fn libdirs() -> Vec<PathBuf> {
+    let multiarch = format!("{}-linux-gnu", std::env::consts::ARCH);
+    vec![
+        PathBuf::from("/lib").join(&multiarch),
+        PathBuf::from("/usr/lib").join(&multiarch),
+        "/lib64".into(),
+        "/usr/lib64".into(),
+        "/lib".into(),
+        "/usr/lib".into(),
+    ]
+}
+
+/// The sources nsswitch.conf lists for `database`, if it has a line for it.
+fn nsswitch_sources(conf: &str, database: &str) -> Option<Vec<String>> {
+    conf.lines()
+        .map(|line| line.split('#').next().unwrap_or_default())
+        .filter_map(|line| line.split_once(':'))
+        .find(|(db, _)| db.trim() == database)
+        .map(|(_, sources)| {
+            sources
+                .split_whitespace()
+                // `[NOTFOUND=return]` and friends are actions, not sources
+                .filter(|s| !s.starts_with('['))
+                .map(Into::into)
+                .collect()
+        })
+}
+
+fn check_config(doc: &mut Doctor) -> Option<NssConfig> {
+    const CHECK: &str = "nss_cosiauthd.toml";
+    let path = match NssConfig::path() {
+        Ok(path) => path,
+        Err(e) => {
+            doc.fail(
+                CHECK,
+                e,
+                "create /etc/auth, or point AUTH_CONFIG_DIR at the directory with the config",
+            );
+            return None;
+        }
+    };
+    if !path.exists() {
+        doc.fail(
+            CHECK,
+            format!("{} does not exist", path.display()),
+            "write it with `host` and `cert`, `spki_pins` or `ca_bundle`, see the nss README",
+        );
+        return None;
+    }
+    match NssConfig::load() {
+        Ok(cfg) => {
+            doc.pass(CHECK, format!("{} parses", path.display()));
+            Some(cfg)
+        }
+        Err(e) => {
+            doc.fail(
+                CHECK,
+                format!("{}: {}", path.display(), e),
+                "fix the syntax; `host` must be `name:port` or `ip:port`",
+            );
+            None
+        }
+    }
+}
+
+fn check_trust(doc: &mut Doctor, cfg: &NssConfig) -> bool {
+    const CHECK: &str = "certificates";
+    match cfg.trust.client_config() {
+        Ok(_) => {
+            doc.pass(CHECK, "pins and CA bundle load");
+            true
+        }
+        Err(e) => {
+            doc.fail(
+                CHECK,
+                e,
+                "check the certificate paths are readable and pins come from `auth spki-pin`",
+            );
+            false
+        }
+    }
+}
+
+fn check_resolve(doc: &mut Doctor, cfg: &NssConfig) -> Option<SocketAddr> {
+    const CHECK: &str = "resolve host";
+    match cfg.host.to_socket_addrs().map(|mut addrs| addrs.next()) {
+        Ok(Some(addr)) => {
+            doc.pass(CHECK, format!("{:?} is {}", cfg.host, addr));
+            Some(addr)
+        }
+        Ok(None) => {
+            doc.fail(
+                CHECK,
+                format!("{:?} has no addresses", cfg.host),
+                "add an A or AAAA record, or put the address in `host`",
+            );
+            None
+        }
+        Err(e) => {
+            doc.fail(
+                CHECK,
+                format!("{:?}: {}", cfg.host, e),
+                "check /etc/resolv.conf and the spelling of `host`",
+            );
+            None
+        }
+    }
+}
+
+async fn check_connect(doc: &mut Doctor, cfg: &NssConfig, addr: SocketAddr) -> Option<AuthdClient> {
+    const CHECK: &str = "TLS to authd";
+    let server_name = cfg.trust.server_name_for(&cfg.host);
+    let connecting = authd::client_connect(addr, &cfg.trust, &server_name);
+    let cl = match tokio::time::timeout(CONNECT_TIMEOUT, connecting).await {
+        Ok(Ok(cl)) => cl,
+        Ok(Err(e)) => {
+            doc.fail(
+                CHECK,
+                format!("handshake with {} as {}: {}", addr, server_name, e),
+                "pin the certificate authd actually serves, with a `server_name` that is on it",
+            );
+            return None;
+        }
+        Err(_) => {
+            doc.fail(
+                CHECK,
+                format!("nothing from {} in {:?}", addr, CONNECT_TIMEOUT),
+                "check authd is running and listening there, and no firewall is in the way",
+            );
+            return None;
+        }
+    };
+    match cl.get_cache_ttls(generous()).await {
+        Ok(_) => {
+            doc.pass(CHECK, format!("{} answers as {}", addr, server_name));
+            Some(cl)
+        }
+        Err(e) => {
+            doc.fail(
+                CHECK,
+                format!("connected, but authd did not answer: {}", e),
+                "check authd's log, and that it's the same version as this host's client",
+            );
+            None
+        }
+    }
+}
+
+async fn check_host_login(doc: &mut Doctor, cfg: &NssConfig, cl: &AuthdClient) {
+    const CHECK: &str = "host login";
+    let (principal, secret) = match (&cfg.host_principal, &cfg.host_secret) {
+        (Some(principal), Some(secret)) => (principal, secret),
+        (None, None) => return doc.pass(CHECK, "not configured, shadow stays hidden"),
+        _ => {
+            return doc.fail(
+                CHECK,
+                "only one of `host_principal` and `host_secret` is set",
+                "set both, or neither; `auth enroll-host` gives you the pair",
+            )
+        }
+    };
+    let secret = match std::fs::read_to_string(secret) {
+        Ok(secret) => secret,
+        Err(e) => {
+            return doc.fail(
+                CHECK,
+                format!("reading {}: {}", secret, e),
+                "run auth doctor as root, or enroll the host again with `auth enroll-host`",
+            )
+        }
+    };
+    match authd::client_login(cl, principal, secret.trim_end().as_bytes()).await {
+        Ok(()) => doc.pass(CHECK, format!("logged in as {}", principal)),
+        Err(e) => doc.fail(
+            CHECK,
+            format!("as {}: {}", principal, e),
+            "rerun `auth enroll-host` for this host to get a fresh secret",
+        ),
+    }
+}
+
+fn check_module(doc: &mut Doctor) {
+    const CHECK: &str = "NSS module";
+    let dirs = libdirs();
+    match dirs
+        .iter()
+        .map(|d| d.join(MODULE_NAME))
+        .find(|p| p.exists())
+    {
+        Some(path) => doc.pass(CHECK, format!("found {}", path.display())),
+        None => doc.fail(
+            CHECK,
+            format!("no {} in {}", MODULE_NAME, dirs[0].display()),
+            format!(
+                "cargo build --release -p nss_cosiauthd && cp target/release/libnss_cosiauthd.so {}",
+                dirs[0].join(MODULE_NAME).display()
+            ),
+        ),
+    }
+}
+
+fn check_nsswitch(doc: &mut Doctor) {
+    let conf = match std::fs::read_to_string("/etc/nsswitch.conf") {
+        Ok(conf) => conf,
+        Err(e) => {
+            return doc.fail(
+                "nsswitch.conf",
+                format!("reading /etc/nsswitch.conf: {}", e),
+                "restore it from your distribution's package",
+            )
+        }
+    };
+    for database in ["passwd", "group"] {
+        let check = format!("nsswitch.conf {}", database);
+        match nsswitch_sources(&conf, database) {
+            Some(sources) if sources.iter().any(|s| s == "cosiauthd") => {
+                doc.pass(&check, sources.join(" "))
+            }
+            Some(sources) => doc.fail(
+                &check,
+                format!("cosiauthd is not one of: {}", sources.join(" ")),
+                format!(
+                    "change it to `{}: {} cosiauthd`",
+                    database,
+                    sources.join(" ")
+                ),
+            ),
+            None => doc.fail(
+                &check,
+                format!("no {} line", database),
+                format!("add `{}: files cosiauthd`", database),
+            ),
+        }
+    }
+}
+
+async fn check_lookup(doc: &mut Doctor, cl: Option<&AuthdClient>, user: Option<&str>) {
+    const CHECK: &str = "lookup";
+    let user = match cl {
+        Some(cl) => match cl.get_all_passwd(generous()).await {
+            Ok(Ok(passwd)) if passwd.is_empty() => {
+                return doc.fail(
+                    CHECK,
+                    "authd has no users",
+                    "create one with `auth create-user` or `auth bootstrap-user`",
+                )
+            }
+            Ok(Ok(passwd)) => {
+                doc.pass(CHECK, format!("authd has {} users", passwd.len()));
+                user.map(String::from)
+                    .unwrap_or_else(|| passwd[0].name.clone())
+            }
+            Ok(Err(e)) => return doc.fail(CHECK, e, "check authd's log and its passwd file"),
+            Err(e) => return doc.fail(CHECK, e, "check authd's log"),
+        },
+        None => match user {
+            Some(user) => user.into(),
+            None => return doc.skip(CHECK, "a connection to authd, or --user"),
+        },
+    };
+
+    const GETENT: &str = "getent passwd";
+    match std::process::Command::new("getent")
+        .args(["passwd", &user])
+        .output()
+    {
+        Ok(out) if out.status.success() => {
+            doc.pass(GETENT, String::from_utf8_lossy(&out.stdout).trim_end())
+        }
+        Ok(_) => doc.fail(
+            GETENT,
+            format!("{} not found through NSS", user),
+            "fix the checks above, then `nscd -i passwd` or restart nscd/sssd if one is caching",
+        ),
+        Err(e) => doc.fail(
+            GETENT,
+            format!("running getent: {}", e),
+            "install getent (libc-bin)",
+        ),
+    }
+}
+
+/// Run every check, printing as we go. Fails if any check did.
+pub async fn run(user: Option<&str>) -> anyhow::Result<()> {
+    let mut doc = Doctor::default();
+
+    let cfg = check_config(&mut doc);
+    let mut cl = None;
+    match &cfg {
+        Some(cfg) => {
+            let trusted = check_trust(&mut doc, cfg);
+            let addr = check_resolve(&mut doc, cfg);
+            match (trusted, addr) {
+                (true, Some(addr)) => cl = check_connect(&mut doc, cfg, addr).await,
+                _ => doc.skip("TLS to authd", "certificates and an address"),
+            }
+            match &cl {
+                Some(cl) => check_host_login(&mut doc, cfg, cl).await,
+                None => doc.skip("host login", "a connection to authd"),
+            }
+        }
+        None => {
+            for check in ["certificates", "resolve host", "TLS to authd", "host login"] {
+                doc.skip(check, "nss_cosiauthd.toml");
+            }
+        }
+    }
+    check_module(&mut doc);
+    check_nsswitch(&mut doc);
+    check_lookup(&mut doc, cl.as_ref(), user).await;
+
+    if doc.failed > 0 {
+        anyhow::bail!("{} checks failed", doc.failed);
+    }
+    println!("all checks passed");
+    Ok(())
+}
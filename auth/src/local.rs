@@ -0,0 +1,209 @@
+//! Commands run on the authd host itself, straight on its files instead of over the network:
+//! `auth generate-opaque-secret`, `bootstrap-admin` and `local-create-user`.
+
+use crate::prompt::{new_password, password_from_flags};
+use argh::FromArgs;
+use authd::{rpc::DefaultCipherSuite, AuthdConfig};
+use chrono::Datelike;
+use opaque_ke::ClientRegistrationFinishParameters;
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+use zeroize::Zeroizing;
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Generate OPAQUE private key for the server
+#[argh(subcommand, name = "generate-opaque-secret")]
+pub struct GenOpaque {
+    #[argh(option)]
+    /// where to write the file
+    output: PathBuf,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Bootstrap the first admin user
+#[argh(subcommand, name = "bootstrap-admin")]
+pub struct LetThereBeAdmin {
+    #[argh(switch)]
+    /// read the password from the first line of stdin instead of asking
+    password_stdin: bool,
+    #[argh(option)]
+    /// read the password from the first line of this file instead of asking
+    password_file: Option<PathBuf>,
+    #[argh(option)]
+    /// username
+    name: String,
+    #[argh(option)]
+    /// uid
+    uid: u32,
+    #[argh(option)]
+    /// server config to be bootstrapping for
+    authd_config: PathBuf,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Create a new user in the local files
+#[argh(subcommand, name = "local-create-user")]
+pub struct LocalCreateUser {
+    #[argh(switch)]
+    /// read the password from the first line of stdin instead of asking
+    password_stdin: bool,
+    #[argh(option)]
+    /// read the password from the first line of this file instead of asking
+    password_file: Option<PathBuf>,
+    #[argh(option)]
+    /// username
+    name: String,
+    #[argh(option)]
+    /// name of shell to use
+    authd_config: PathBuf,
+}
+
+fn load_config(path: &Path) -> anyhow::Result<AuthdConfig> {
+    let mut cfg: AuthdConfig = toml::from_slice(&std::fs::read(path)?)?;
+    cfg.expand();
+    Ok(cfg)
+}
+
+/// Run both halves of an OPAQUE registration here, and write the cookie where authd will look
+/// for it.
+fn register_locally(cfg: &AuthdConfig, name: &str, password: &[u8]) -> anyhow::Result<()> {
+    let mut rng = opaque_ke::rand::rngs::OsRng;
+    let srv = opaque_ke::ServerSetup::<DefaultCipherSuite>::deserialize(&std::fs::read(
+        &cfg.opaque_server_setup,
+    )?)
+    .expect("reading opaque server setup");
+    let client_reg = opaque_ke::ClientRegistration::<DefaultCipherSuite>::start(&mut rng, password)
+        .expect("starting registration");
+
+    let server_reg = opaque_ke::ServerRegistration::<DefaultCipherSuite>::start(
+        &srv,
+        client_reg.message,
+        name.as_bytes(),
+    )
+    .unwrap();
+    let completed_reg = client_reg
+        .state
+        .finish(
+            &mut rng,
+            password,
+            server_reg.message,
+            ClientRegistrationFinishParameters::default(),
+        )
+        .expect("finishing registration");
+
+    let password_file =
+        opaque_ke::ServerRegistration::<DefaultCipherSuite>::finish(completed_reg.message);
+    let path = PathBuf::from(&cfg.opaque_cookies).join(name);
+    std::fs::write(path, password_file.serialize()).expect("writing out opaque cookie");
+    Ok(())
+}
+
+impl GenOpaque {
+    pub fn run(self) -> anyhow::Result<()> {
+        let mut rng = opaque_ke::rand::rngs::OsRng;
+        let setup = opaque_ke::ServerSetup::<DefaultCipherSuite>::new(&mut rng);
+        std::fs::write(self.output, &setup.serialize()).expect("writing opaque server setup");
+        Ok(())
+    }
+}
+
+impl LetThereBeAdmin {
+    pub fn run(self) -> anyhow::Result<()> {
+        let cfg = load_config(&self.authd_config)?;
+        let pwbytes = new_password(self.password_stdin, &self.password_file)?;
+        register_locally(&cfg, &self.name, &pwbytes)?;
+
+        println!("welcome to the matrix, {}", self.name);
+
+        // TODO: add user to auth-admins
+        Ok(())
+    }
+}
+
+impl LocalCreateUser {
+    pub fn run(self) -> anyhow::Result<()> {
+        let cfg = load_config(&self.authd_config)?;
+
+        let pwbytes = match password_from_flags(self.password_stdin, &self.password_file)? {
+            Some(pwbytes) => pwbytes,
+            None => loop {
+                let pwbytes = Zeroizing::new(
+                    rpassword::prompt_password(format!("New password for {}:", self.name))
+                        .expect("reading pw1")
+                        .into_bytes(),
+                );
+                let pwbytes2 = Zeroizing::new(
+                    rpassword::prompt_password("Confirm new password:")
+                        .expect("reading pw2")
+                        .into_bytes(),
+                );
+                if pwbytes == pwbytes2 {
+                    break pwbytes;
+                } else {
+                    eprintln!("Passwords don't match, try again");
+                }
+            },
+        };
+        let hash = pwhash::bcrypt::hash_with(
+            pwhash::bcrypt::BcryptSetup {
+                variant: Some(pwhash::bcrypt::BcryptVariant::V2y),
+                cost: Some(12),
+                ..Default::default()
+            },
+            &pwbytes,
+        )
+        .expect("bcrypt failed");
+
+        let files = authd::files::Files::new(&cfg.passwd_file, &cfg.group_file, &cfg.shadow_file);
+        let all_users = files.get_all_passwd()?;
+        let largest_uid = all_users
+            .iter()
+            .max_by_key(|u| u.id)
+            .map(|p| p.id)
+            .unwrap_or(1);
+        let mut f = std::fs::File::options()
+            .write(true)
+            .create(true)
+            .open(PathBuf::from(&cfg.passwd_file))?;
+
+        std::io::Seek::seek(&mut f, std::io::SeekFrom::End(0))?;
+        writeln!(
+            f,
+            "{}",
+            authd::types::Passwd {
+                name: self.name.clone(),
+                id: largest_uid + 1,
+                gecos: "freshly made by auth".into(),
+                dir: "/nonexistent".into(),
+                shell: "/bin/false".into(),
+            }
+        )?;
+        let mut f = std::fs::File::options()
+            .write(true)
+            .create(true)
+            .open(PathBuf::from(&cfg.shadow_file))?;
+
+        std::io::Seek::seek(&mut f, std::io::SeekFrom::End(0))?;
+        let today_days = chrono::Utc::today().num_days_from_ce()
+            - chrono::NaiveDate::from_ymd(1970, 1, 1).num_days_from_ce();
+        writeln!(
+            f,
+            "{}",
+            authd::types::Shadow {
+                name: self.name.clone(),
+                passwd: hash,
+                last_change: today_days as _,
+                change_min_days: 0,
+                // the usual "never", since 0 would mean the password has already expired
+                change_max_days: 99999,
+                change_warn_days: 7,
+                change_inactive_days: None,
+                expire_date: None,
+            }
+        )?;
+
+        register_locally(&cfg, &self.name, &pwbytes)
+    }
+}
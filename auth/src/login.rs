@@ -0,0 +1,112 @@
+//! `auth login`, `logout` and `whoami`: the session other commands use instead of asking for a
+//! password each time.
+
+use crate::{
+    config::Server,
+    connect::{connect, generous, login_interactively, resume_session, username_for},
+    prompt::password_from_flags,
+    session::{CachedSession, SessionCache},
+};
+use argh::FromArgs;
+use std::path::PathBuf;
+use zeroize::Zeroizing;
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Log in once, so other commands don't ask for a password until the session expires
+#[argh(subcommand, name = "login")]
+pub struct Login {
+    #[argh(switch)]
+    /// if the login fails, move an account that only has a crypt(3) hash to OPAQUE and try again,
+    /// if authd allows it
+    migrate: bool,
+    #[argh(switch)]
+    /// read the password from the first line of stdin instead of asking
+    password_stdin: bool,
+    #[argh(option)]
+    /// read the password from the first line of this file instead of asking
+    password_file: Option<PathBuf>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// End the session from auth login
+#[argh(subcommand, name = "logout")]
+pub struct Logout {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Show who authd thinks you are
+#[argh(subcommand, name = "whoami")]
+pub struct Whoami {}
+
+impl Login {
+    pub async fn run(self, server: &Server) -> anyhow::Result<()> {
+        let cl = connect(server).await?;
+        let user = username_for(server, "username: ")?;
+        let pass = match password_from_flags(self.password_stdin, &self.password_file)? {
+            Some(pass) => pass,
+            None => Zeroizing::new(rpassword::prompt_password("password: ")?.into_bytes()),
+        };
+        match login_interactively(&cl, &user, &pass).await {
+            // only send the password when there's no credential that takes it
+            Err(e)
+                if self.migrate
+                    && matches!(e.downcast_ref(), Some(authd::LoginError::BadPassword)) =>
+            {
+                authd::client_migrate_legacy(&cl, &user, &pass).await?;
+                println!("moved {} from a crypt hash to OPAQUE", user);
+                login_interactively(&cl, &user, &pass).await?;
+            }
+            result => result?,
+        }
+        let (token, expires) = cl.create_session_token(generous()).await??;
+
+        let mut cache = SessionCache::load()?;
+        cache.sessions.insert(
+            server.key(),
+            CachedSession {
+                username: user.clone(),
+                token,
+                expires,
+            },
+        );
+        cache.save()?;
+        let until = chrono::TimeZone::timestamp_opt(&chrono::Local, expires as i64, 0)
+            .single()
+            .map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_default();
+        println!("logged in to {} as {} until {}", server.key(), user, until);
+        Ok(())
+    }
+}
+
+impl Logout {
+    pub async fn run(self, server: &Server) -> anyhow::Result<()> {
+        let mut cache = SessionCache::load()?;
+        let cached = match cache.sessions.remove(&server.key()) {
+            Some(cached) => cached,
+            None => anyhow::bail!("not logged in to {}", server.key()),
+        };
+        cache.save()?;
+        if !cached.expired() {
+            let cl = connect(server).await?;
+            cl.revoke_session_token(generous(), cached.token).await??;
+        }
+        println!("logged {} out of {}", cached.username, server.key());
+        Ok(())
+    }
+}
+
+impl Whoami {
+    pub async fn run(self, server: &Server) -> anyhow::Result<()> {
+        let cl = connect(server).await?;
+        resume_session(&cl, server).await;
+        let me = cl.whoami(generous()).await?;
+        match me.username {
+            Some(username) => {
+                println!("{} ({:?})", username, me.principal);
+                println!("groups: {}", me.groups.join(", "));
+            }
+            None => println!("not logged in to {}, run auth login", server.key()),
+        }
+        Ok(())
+    }
+}
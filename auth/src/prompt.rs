@@ -0,0 +1,69 @@
+//! Asking for passwords and the like, or reading them from the flags for scripts.
+
+use std::{
+    io::{BufRead, Write},
+    path::PathBuf,
+};
+use zeroize::Zeroizing;
+
+/// The password from `--password-stdin` or `--password-file`, if either was given. Only the first
+/// line counts, without its line ending.
+pub fn password_from_flags(
+    stdin: bool,
+    file: &Option<PathBuf>,
+) -> anyhow::Result<Option<Zeroizing<Vec<u8>>>> {
+    let mut line = Zeroizing::new(String::new());
+    match (stdin, file) {
+        (true, Some(_)) => anyhow::bail!("pick one of --password-stdin and --password-file"),
+        (true, None) => {
+            std::io::stdin().read_line(&mut line)?;
+        }
+        (false, Some(file)) => {
+            std::io::BufReader::new(std::fs::File::open(file)?).read_line(&mut line)?;
+        }
+        (false, None) => return Ok(None),
+    }
+    let password = line.trim_end_matches(|c| c == '\r' || c == '\n');
+    if password.is_empty() {
+        anyhow::bail!("the password is empty");
+    }
+    Ok(Some(Zeroizing::new(password.as_bytes().to_vec())))
+}
+
+/// A new password, from the flags or else asked for twice.
+pub fn new_password(stdin: bool, file: &Option<PathBuf>) -> anyhow::Result<Zeroizing<Vec<u8>>> {
+    Ok(match password_from_flags(stdin, file)? {
+        Some(password) => password,
+        None => prompt_new_password(),
+    })
+}
+
+/// Ask for a new OPAQUE password twice, until both match.
+fn prompt_new_password() -> Zeroizing<Vec<u8>> {
+    loop {
+        let pwbytes = Zeroizing::new(
+            rpassword::prompt_password("New OPAQUE password:")
+                .expect("reading pw1")
+                .into_bytes(),
+        );
+        let pwbytes2 = Zeroizing::new(
+            rpassword::prompt_password("Confirm new OPAQUE password:")
+                .expect("reading pw2")
+                .into_bytes(),
+        );
+        if pwbytes == pwbytes2 {
+            return pwbytes;
+        } else {
+            eprintln!("Passwords don't match, try again");
+        }
+    }
+}
+
+/// Ask for something that doesn't need hiding.
+pub fn prompt_line(prompt: &str) -> anyhow::Result<String> {
+    print!("{}", prompt);
+    std::io::stdout().flush()?;
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    Ok(line.trim().to_owned())
+}
@@ -0,0 +1,67 @@
+//! Session tokens from `auth login`, cached in a file only the user can read so later commands
+//! don't have to ask for a password.
+
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, path::PathBuf};
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct SessionCache {
+    /// Sessions by the authd they are for, as `host:port`.
+    #[serde(default)]
+    pub sessions: BTreeMap<String, CachedSession>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CachedSession {
+    pub username: String,
+    pub token: String,
+    /// Seconds since 1970.
+    pub expires: u64,
+}
+
+impl CachedSession {
+    pub fn expired(&self) -> bool {
+        self.expires <= unix_time()
+    }
+}
+
+/// `$XDG_RUNTIME_DIR/auth/sessions.toml`, which goes away at logout, or failing that the cache
+/// directory.
+fn cache_path() -> anyhow::Result<PathBuf> {
+    let dir = dirs_next::runtime_dir()
+        .or_else(dirs_next::cache_dir)
+        .ok_or_else(|| anyhow::anyhow!("nowhere to keep sessions"))?;
+    Ok(dir.join("auth").join("sessions.toml"))
+}
+
+impl SessionCache {
+    pub fn load() -> anyhow::Result<Self> {
+        match std::fs::read(cache_path()?) {
+            Ok(contents) => Ok(toml::from_slice(&contents)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(&mut self) -> anyhow::Result<()> {
+        self.sessions.retain(|_, s| !s.expired());
+        let path = cache_path()?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        // 0600, so the tokens are never readable by anyone else
+        authd::files::replace_file(&path, 0o600, &toml::to_string(self)?)
+    }
+
+    /// The unexpired session for `server`, if there is one.
+    pub fn get(&self, server: &str) -> Option<&CachedSession> {
+        self.sessions.get(server).filter(|s| !s.expired())
+    }
+}
+
+fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
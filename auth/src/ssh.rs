@@ -0,0 +1,236 @@
+//! SSH through authd: `auth authorized-keys` and `authorized-principals` for sshd, `ssh-key` for
+//! managing stored keys and `ssh-cert` for getting certificates.
+
+use crate::{
+    config::Server,
+    connect::{connect, connect_as_user, connect_with_password, generous},
+};
+use argh::FromArgs;
+use chrono::Datelike;
+use std::path::PathBuf;
+use zeroize::Zeroizing;
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Print a user's SSH keys for this host, for sshd's AuthorizedKeysCommand
+#[argh(subcommand, name = "authorized-keys")]
+pub struct AuthorizedKeys {
+    #[argh(positional)]
+    /// username
+    user: String,
+    #[argh(option)]
+    /// host name the keys are for, defaults to this host's name
+    on: Option<String>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Print who may use a certificate to log in as a user on this host, for sshd's
+/// AuthorizedPrincipalsCommand
+#[argh(subcommand, name = "authorized-principals")]
+pub struct AuthorizedPrincipals {
+    #[argh(positional)]
+    /// username
+    user: String,
+    #[argh(option)]
+    /// host name to check, defaults to this host's name
+    on: Option<String>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Manage the SSH keys kept in authd
+#[argh(subcommand, name = "ssh-key")]
+pub struct SshKeyCmd {
+    #[argh(option)]
+    /// whose keys to manage, defaults to whoever logs in
+    user: Option<String>,
+    #[argh(subcommand)]
+    action: SshKeyAction,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+enum SshKeyAction {
+    Add(SshKeyAdd),
+    List(SshKeyList),
+    Remove(SshKeyRemove),
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Add a public key, or change where and until when it may be used
+#[argh(subcommand, name = "add")]
+struct SshKeyAdd {
+    #[argh(positional)]
+    /// public key file, e.g. ~/.ssh/id_ed25519.pub
+    key_file: PathBuf,
+    #[argh(option)]
+    /// host name, pattern or @host-group the key may be used on, defaults to everywhere
+    on: Option<String>,
+    #[argh(option)]
+    /// last day the key may be used, as YYYY-MM-DD
+    expires: Option<chrono::NaiveDate>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// List the stored keys
+#[argh(subcommand, name = "list")]
+struct SshKeyList {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Remove a key
+#[argh(subcommand, name = "remove")]
+struct SshKeyRemove {
+    #[argh(positional)]
+    /// fingerprint of the key, as list prints it
+    fingerprint: String,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Log in and get a short-lived SSH certificate for your key, or a host certificate
+#[argh(subcommand, name = "ssh-cert")]
+pub struct SshCert {
+    #[argh(option)]
+    /// public key to sign, defaults to ~/.ssh/id_ed25519.pub
+    key: Option<PathBuf>,
+    #[argh(switch)]
+    /// sign a host key instead, e.g. /etc/ssh/ssh_host_ed25519_key.pub
+    host_cert: bool,
+    #[argh(option)]
+    /// host name to put in a host certificate, defaults to --host-principal or this host's name
+    on: Option<String>,
+    #[argh(option)]
+    /// log in as this host instead of prompting, see enroll-host
+    host_principal: Option<String>,
+    #[argh(option)]
+    /// file holding the host's secret
+    host_secret: Option<PathBuf>,
+}
+
+/// This machine's host name, as the kernel knows it.
+fn this_host() -> anyhow::Result<String> {
+    Ok(std::fs::read_to_string("/proc/sys/kernel/hostname")?
+        .trim()
+        .to_owned())
+}
+
+/// Days since Jan 1st 1970, which is how authd counts days.
+fn days_since_epoch(date: chrono::NaiveDate) -> i64 {
+    (date.num_days_from_ce() - chrono::NaiveDate::from_ymd(1970, 1, 1).num_days_from_ce()) as i64
+}
+
+impl AuthorizedKeys {
+    pub async fn run(self, server: &Server) -> anyhow::Result<()> {
+        let on = match self.on {
+            Some(on) => on,
+            None => this_host()?,
+        };
+        let cl = connect(server).await?;
+        for key in cl.get_authorized_keys(generous(), self.user, on).await?? {
+            println!("{}", key);
+        }
+        Ok(())
+    }
+}
+
+impl AuthorizedPrincipals {
+    pub async fn run(self, server: &Server) -> anyhow::Result<()> {
+        let on = match self.on {
+            Some(on) => on,
+            None => this_host()?,
+        };
+        let cl = connect(server).await?;
+        for principal in cl
+            .get_authorized_principals(generous(), self.user, on)
+            .await??
+        {
+            println!("{}", principal);
+        }
+        Ok(())
+    }
+}
+
+impl SshKeyCmd {
+    pub async fn run(self, server: &Server) -> anyhow::Result<()> {
+        let (cl, me) = connect_as_user(server).await?;
+        let user = self.user.unwrap_or(me);
+        match self.action {
+            SshKeyAction::Add(add) => {
+                let key = authd::ssh::SshKey::parse(
+                    &std::fs::read_to_string(&add.key_file)?,
+                    add.on,
+                    add.expires.map(days_since_epoch),
+                )?;
+                let fingerprint = cl.add_ssh_key(generous(), user.clone(), key).await??;
+                println!("added {} for {}", fingerprint, user);
+            }
+            SshKeyAction::List(_) => {
+                for key in cl.list_ssh_keys(generous(), user).await?? {
+                    let expires = key
+                        .expires
+                        .map(|e| {
+                            let date =
+                                chrono::NaiveDate::from_ymd(1970, 1, 1) + chrono::Duration::days(e);
+                            format!(" until {}", date)
+                        })
+                        .unwrap_or_default();
+                    println!(
+                        "{} on {}{}: {}",
+                        key.fingerprint(),
+                        key.host,
+                        expires,
+                        key.key
+                    );
+                }
+            }
+            SshKeyAction::Remove(remove) => {
+                if !cl
+                    .remove_ssh_key(generous(), user.clone(), remove.fingerprint.clone())
+                    .await??
+                {
+                    anyhow::bail!("{} has no key {}", user, remove.fingerprint);
+                }
+                println!("removed {}", remove.fingerprint);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SshCert {
+    pub async fn run(self, server: &Server) -> anyhow::Result<()> {
+        let key = match self.key {
+            Some(key) => key,
+            None => PathBuf::from(std::env::var("HOME")?).join(".ssh/id_ed25519.pub"),
+        };
+        let public_key = std::fs::read_to_string(&key)?;
+
+        let cl = match (&self.host_principal, &self.host_secret) {
+            (Some(principal), Some(secret)) => {
+                let cl = connect(server).await?;
+                let secret = Zeroizing::new(std::fs::read_to_string(secret)?);
+                authd::client_login(&cl, principal, secret.trim_end().as_bytes()).await?;
+                cl
+            }
+            _ if self.host_cert => connect_as_user(server).await?.0,
+            // authd won't sign a user key for a session resumed from `auth login`
+            _ => connect_with_password(server).await?.0,
+        };
+        let cert = if self.host_cert {
+            let on = match self.on.or(self.host_principal) {
+                Some(on) => on,
+                None => this_host()?,
+            };
+            cl.sign_ssh_host_key(generous(), on, public_key).await??
+        } else {
+            cl.sign_ssh_user_key(generous(), public_key).await??
+        };
+
+        // ssh looks for id_ed25519-cert.pub next to id_ed25519
+        let name = key.to_string_lossy();
+        let cert_path = PathBuf::from(format!(
+            "{}-cert.pub",
+            name.strip_suffix(".pub").unwrap_or(&name)
+        ));
+        std::fs::write(&cert_path, format!("{}\n", cert))?;
+        println!("wrote {}", cert_path.display());
+        Ok(())
+    }
+}
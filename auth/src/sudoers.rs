@@ -0,0 +1,86 @@
+//! `auth render-sudoers`: the sudo rules authd has for a host, as a sudoers drop-in.
+
+use crate::{
+    config::Server,
+    connect::{connect, generous},
+};
+use argh::FromArgs;
+use std::{io::Write, os::unix::fs::OpenOptionsExt, path::PathBuf};
+use zeroize::Zeroizing;
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Write the sudo rules authd has for this host to a sudoers drop-in, checked with visudo
+#[argh(subcommand, name = "render-sudoers")]
+pub struct RenderSudoers {
+    #[argh(option)]
+    /// name this host logs in to authd as, see enroll-host
+    host_principal: Option<String>,
+    #[argh(option)]
+    /// file holding this host's secret
+    host_secret: Option<PathBuf>,
+    #[argh(option)]
+    /// host name to fetch the rules for
+    on: String,
+    #[argh(option, default = "PathBuf::from(\"/etc/sudoers.d/cosiauthd\")")]
+    /// where to write the rules, /etc/sudoers.d/cosiauthd by default
+    output: PathBuf,
+}
+
+impl RenderSudoers {
+    pub async fn run(self, server: &Server) -> anyhow::Result<()> {
+        let cl = connect(server).await?;
+        if let (Some(principal), Some(secret)) = (&self.host_principal, &self.host_secret) {
+            let secret = Zeroizing::new(std::fs::read_to_string(secret)?);
+            authd::client_login(&cl, principal, secret.trim_end().as_bytes()).await?;
+        }
+        let rules = cl
+            .get_sudo_rules(generous(), None, self.on.clone())
+            .await??;
+
+        let mut sudoers = format!(
+            "# sudo rules for {} from authd, written by auth render-sudoers. Don't edit.\n",
+            self.on
+        );
+        for rule in &rules {
+            sudoers.push_str(&rule.to_sudoers()?);
+            sudoers.push('\n');
+        }
+
+        // sudo skips files in sudoers.d with a `.` in their name, so this one is safe to leave
+        // lying around if visudo doesn't like it
+        let name = self
+            .output
+            .file_name()
+            .ok_or_else(|| anyhow::anyhow!("--output needs a file name"))?
+            .to_string_lossy()
+            .into_owned();
+        let tmp = self.output.with_file_name(format!(".{}.new", name));
+        let _ = std::fs::remove_file(&tmp);
+        let mut f = std::fs::File::options()
+            .write(true)
+            .create_new(true)
+            .mode(0o440)
+            .open(&tmp)?;
+        f.write_all(sudoers.as_bytes())?;
+        f.sync_all()?;
+
+        let checked = std::process::Command::new("visudo")
+            .args(["-c", "-q", "-f"])
+            .arg(&tmp)
+            .status()?;
+        if !checked.success() {
+            anyhow::bail!(
+                "visudo rejected the rules, see {}; {} was left alone",
+                tmp.display(),
+                self.output.display()
+            );
+        }
+        std::fs::rename(&tmp, &self.output)?;
+        println!(
+            "wrote {} sudo rules to {}",
+            rules.len(),
+            self.output.display()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,169 @@
+//! Who may log in where.
+//!
+//! Hosts nobody has written a rule for admit everyone, which is how things were before there were
+//! rules at all. Once any rule covers a host, only members of the groups named by rules covering it
+//! may log in there.
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+pub struct AccessRules {
+    /// Named sets of host patterns, which rules refer to as `@name`.
+    #[serde(default)]
+    pub host_groups: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    pub rules: Vec<AccessRule>,
+}
+
+/// Members of `group` may log in on hosts matching `host`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+pub struct AccessRule {
+    pub group: String,
+    /// A host name, a pattern where `*` matches anything (`lab*.cosi.clarkson.edu`), or `@` and the
+    /// name of a host group.
+    pub host: String,
+}
+
+impl AccessRules {
+    /// Does `pattern` cover `host`? Host groups may not contain other host groups.
+    pub fn host_matches(&self, pattern: &str, host: &str) -> bool {
+        match pattern.strip_prefix('@') {
+            Some(group) => self
+                .host_groups
+                .get(group)
+                .map(|members| {
+                    members
+                        .iter()
+                        .any(|m| !m.starts_with('@') && glob_matches(m, host))
+                })
+                .unwrap_or(false),
+            None => glob_matches(pattern, host),
+        }
+    }
+
+    /// May a member of `groups` log in on `host`?
+    pub fn allows(&self, groups: &[String], host: &str) -> bool {
+        let mut covering = self
+            .rules
+            .iter()
+            .filter(|rule| self.host_matches(&rule.host, host))
+            .peekable();
+        if covering.peek().is_none() {
+            return true;
+        }
+        covering.any(|rule| groups.contains(&rule.group))
+    }
+}
+
+/// Case-insensitive match where `*` stands for any run of characters, as host names are
+/// case-insensitive.
+pub(crate) fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    let name = name.to_ascii_lowercase();
+    let mut parts = pattern.split('*');
+    // split always yields at least one part
+    let first = parts.next().unwrap();
+    let mut rest = match name.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    let last = match parts.pop() {
+        Some(last) => last,
+        // no `*` at all
+        None => return rest.is_empty(),
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules() -> AccessRules {
+        AccessRules {
+            host_groups: BTreeMap::from([
+                (
+                    "mirrors".to_string(),
+                    vec!["mirror*.cosi.clarkson.edu".to_string(), "@labs".to_string()],
+                ),
+                ("labs".to_string(), vec!["lab*".to_string()]),
+            ]),
+            rules: vec![
+                AccessRule {
+                    group: "mirror-admins".into(),
+                    host: "@mirrors".into(),
+                },
+                AccessRule {
+                    group: "auth-admins".into(),
+                    host: "*.cosi.clarkson.edu".into(),
+                },
+                AccessRule {
+                    group: "students".into(),
+                    host: "@labs".into(),
+                },
+            ],
+        }
+    }
+
+    fn groups(names: &[&str]) -> Vec<String> {
+        names.iter().map(|&n| n.into()).collect()
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob_matches(
+            "lab1.cosi.clarkson.edu",
+            "LAB1.cosi.clarkson.edu"
+        ));
+        assert!(glob_matches("lab*", "lab12"));
+        assert!(glob_matches("lab*", "lab"));
+        assert!(glob_matches(
+            "*.cosi.clarkson.edu",
+            "mirror.cosi.clarkson.edu"
+        ));
+        assert!(glob_matches("m*r*.edu", "mirror.edu"));
+        assert!(glob_matches("*", "anything"));
+        assert!(!glob_matches("lab*", "mylab1"));
+        assert!(!glob_matches("lab1", "lab12"));
+        assert!(!glob_matches("*.edu", "edu"));
+        // the last part can't reuse what an earlier part matched
+        assert!(!glob_matches("ab*ba", "aba"));
+    }
+
+    #[test]
+    fn uncovered_hosts_admit_everyone() {
+        assert!(rules().allows(&groups(&[]), "desktop.example.com"));
+    }
+
+    #[test]
+    fn covered_hosts_only_admit_the_groups_of_covering_rules() {
+        let rules = rules();
+        let host = "mirror1.cosi.clarkson.edu";
+        assert!(rules.allows(&groups(&["mirror-admins"]), host));
+        assert!(rules.allows(&groups(&["students", "auth-admins"]), host));
+        // a rule for some other host doesn't let students in here
+        assert!(!rules.allows(&groups(&["students"]), host));
+        assert!(!rules.allows(&groups(&[]), host));
+    }
+
+    #[test]
+    fn host_groups() {
+        let rules = rules();
+        assert!(rules.host_matches("@labs", "lab3"));
+        assert!(!rules.host_matches("@labs", "mirror1.cosi.clarkson.edu"));
+        assert!(!rules.host_matches("@nonexistent", "lab3"));
+        // host groups don't nest, so @mirrors doesn't pull in @labs
+        assert!(!rules.host_matches("@mirrors", "lab3"));
+        assert!(rules.allows(&groups(&["students"]), "lab3"));
+        assert!(!rules.allows(&groups(&["mirror-admins"]), "lab3"));
+    }
+}
@@ -0,0 +1,287 @@
+//! The audit log: one JSON line per login, failure, registration and change anyone made through
+//! authd, saying who did what to whom, from where, and how it went.
+//!
+//! Each entry carries the hash of the one before it, and its own hash covers that, so editing,
+//! dropping or reordering entries breaks the chain from there on. [`verify`] walks it. Someone who
+//! can write the file can still rewrite the whole tail consistently, so keep a copy of the latest
+//! hash somewhere else (`auth audit verify` prints it) and check against that.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    io::{BufRead, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+/// What the first entry chains to.
+pub const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AuditEntry {
+    /// Position in the log, from 1.
+    pub seq: u64,
+    /// Seconds since 1970.
+    pub time: u64,
+    /// Who was logged in on the connection, if anyone.
+    pub actor: Option<String>,
+    /// The address the connection came from.
+    pub peer: String,
+    /// The RPC, e.g. `finish_login` or `create_account`.
+    pub action: String,
+    /// The user, host or key the action was about.
+    pub target: Option<String>,
+    pub outcome: Outcome,
+    /// Hash of the previous entry, or [`GENESIS`].
+    pub prev: String,
+    /// Hex SHA-256 of this entry with `hash` left empty.
+    pub hash: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Success,
+    /// Why not, as the client was told.
+    Failure(String),
+}
+
+impl Outcome {
+    pub fn of<T, E: std::fmt::Display>(result: &Result<T, E>) -> Self {
+        match result {
+            Ok(_) => Outcome::Success,
+            Err(e) => Outcome::Failure(e.to_string()),
+        }
+    }
+}
+
+impl AuditEntry {
+    /// What `hash` should be.
+    pub fn digest(&self) -> String {
+        let unhashed = AuditEntry {
+            hash: String::new(),
+            ..self.clone()
+        };
+        let json = serde_json::to_vec(&unhashed).expect("serializing audit entry");
+        Sha256::digest(json)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+/// Check that every entry's hash is right and chains to the one before, starting from `prev`
+/// ([`GENESIS`] for a whole log). Says where the first break is.
+pub fn verify<'a>(
+    entries: impl IntoIterator<Item = &'a AuditEntry>,
+    mut prev: &'a str,
+) -> Result<(), String> {
+    for entry in entries {
+        if entry.prev != prev {
+            return Err(format!(
+                "entry {} does not follow the one before it",
+                entry.seq
+            ));
+        }
+        if entry.digest() != entry.hash {
+            return Err(format!(
+                "entry {} was changed after it was written",
+                entry.seq
+            ));
+        }
+        prev = &entry.hash;
+    }
+    Ok(())
+}
+
+/// Every entry in the log at `path`. No file is an empty log.
+pub fn read(path: &Path) -> anyhow::Result<Vec<AuditEntry>> {
+    let f = match std::fs::File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    std::io::BufReader::new(f)
+        .lines()
+        .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+        .map(|line| Ok(serde_json::from_str(&line?)?))
+        .collect()
+}
+
+/// The log authd appends to.
+#[derive(Debug)]
+pub struct AuditLog {
+    pth: PathBuf,
+    /// Sequence number and hash of the last entry.
+    last: (u64, String),
+}
+
+impl AuditLog {
+    /// Open the log at `pth`, carrying on from its last entry. A broken chain is logged, not
+    /// fatal: new entries chain from whatever is last, and `auth audit verify` will point at the
+    /// break.
+    pub fn open<P: Into<PathBuf>>(pth: P) -> anyhow::Result<Self> {
+        let pth = pth.into();
+        let entries = read(&pth)?;
+        if let Err(e) = verify(&entries, GENESIS) {
+            tracing::error!("audit log {} is broken: {}", pth.display(), e);
+        }
+        let last = entries
+            .last()
+            .map(|e| (e.seq, e.hash.clone()))
+            .unwrap_or((0, GENESIS.into()));
+        Ok(AuditLog { pth, last })
+    }
+
+    /// Append an entry, flushed to disk before returning.
+    pub fn record(
+        &mut self,
+        actor: Option<String>,
+        peer: String,
+        action: &str,
+        target: Option<String>,
+        outcome: Outcome,
+        time: u64,
+    ) -> anyhow::Result<()> {
+        let mut entry = AuditEntry {
+            seq: self.last.0 + 1,
+            time,
+            actor,
+            peer,
+            action: action.into(),
+            target,
+            outcome,
+            prev: self.last.1.clone(),
+            hash: String::new(),
+        };
+        entry.hash = entry.digest();
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        let mut f = std::fs::File::options()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(&self.pth)?;
+        f.write_all(&line)?;
+        f.sync_data()?;
+        self.last = (entry.seq, entry.hash);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A log with `n` entries written through `AuditLog`, and what it reads back as.
+    fn log_with(n: u64) -> (tempfile::TempDir, PathBuf, Vec<AuditEntry>) {
+        let dir = tempfile::tempdir().unwrap();
+        let pth = dir.path().join("audit.log");
+        let mut log = AuditLog::open(&pth).unwrap();
+        for i in 1..=n {
+            let outcome = if i % 2 == 0 {
+                Outcome::Failure("authentication failure".into())
+            } else {
+                Outcome::Success
+            };
+            log.record(
+                Some("alice".into()),
+                "127.0.0.1:1234".into(),
+                "finish_login",
+                Some(format!("user{}", i)),
+                outcome,
+                1_700_000_000 + i,
+            )
+            .unwrap();
+        }
+        let entries = read(&pth).unwrap();
+        (dir, pth, entries)
+    }
+
+    #[test]
+    fn an_untouched_log_verifies() {
+        let (_dir, pth, entries) = log_with(4);
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0].prev, GENESIS);
+        assert_eq!(verify(&entries, GENESIS), Ok(()));
+        // a tail checks out from the hash of the entry before it
+        assert_eq!(verify(&entries[2..], &entries[1].hash), Ok(()));
+
+        // reopening carries on the chain
+        let mut log = AuditLog::open(&pth).unwrap();
+        log.record(
+            None,
+            "127.0.0.1:1".into(),
+            "start_login",
+            None,
+            Outcome::Success,
+            1,
+        )
+        .unwrap();
+        let entries = read(&pth).unwrap();
+        assert_eq!(entries[4].seq, 5);
+        assert_eq!(verify(&entries, GENESIS), Ok(()));
+    }
+
+    #[test]
+    fn edited_entries_are_caught() {
+        let (_dir, _pth, mut entries) = log_with(4);
+        entries[1].outcome = Outcome::Success;
+        assert_eq!(
+            verify(&entries, GENESIS),
+            Err("entry 2 was changed after it was written".into())
+        );
+
+        // fixing up its hash breaks the link to the next one instead
+        entries[1].hash = entries[1].digest();
+        assert_eq!(
+            verify(&entries, GENESIS),
+            Err("entry 3 does not follow the one before it".into())
+        );
+    }
+
+    #[test]
+    fn dropped_and_reordered_entries_are_caught() {
+        let (_dir, _pth, entries) = log_with(4);
+
+        let mut dropped = entries.clone();
+        dropped.remove(1);
+        assert_eq!(
+            verify(&dropped, GENESIS),
+            Err("entry 3 does not follow the one before it".into())
+        );
+
+        let mut reordered = entries.clone();
+        reordered.swap(1, 2);
+        assert_eq!(
+            verify(&reordered, GENESIS),
+            Err("entry 3 does not follow the one before it".into())
+        );
+
+        // cutting off the start leaves the first entry chaining to something that isn't there
+        assert_eq!(
+            verify(&entries[2..], GENESIS),
+            Err("entry 3 does not follow the one before it".into())
+        );
+    }
+
+    #[test]
+    fn a_truncated_tail_needs_the_last_hash_kept_elsewhere() {
+        let (_dir, _pth, entries) = log_with(4);
+        let kept = entries.last().unwrap().hash.clone();
+
+        // what's left is still a valid chain...
+        let truncated = &entries[..2];
+        assert_eq!(verify(truncated, GENESIS), Ok(()));
+        // ...so it's the hash kept elsewhere that gives it away
+        assert_ne!(truncated.last().unwrap().hash, kept);
+    }
+
+    #[test]
+    fn a_half_written_entry_does_not_read() {
+        let (_dir, pth, _entries) = log_with(2);
+        let contents = std::fs::read_to_string(&pth).unwrap();
+        std::fs::write(&pth, &contents[..contents.len() - 10]).unwrap();
+        assert!(read(&pth).is_err());
+    }
+}
@@ -1,7 +1,14 @@
-use crate::types::{Group, Passwd, Shadow};
-use std::io::{BufRead, BufReader};
+use crate::types::{Group, Netgroup, NetgroupMember, NetgroupTriple, Passwd, Shadow};
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::HashSet;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
 use std::time::SystemTime;
-use std::{fs::File, path::PathBuf};
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+};
 
 #[derive(Debug)]
 /// Use database backed by 3 files using the `/etc/passwd` `/etc/group` and `/etc/shadow` file
@@ -10,10 +17,13 @@ use std::{fs::File, path::PathBuf};
 /// man passwd(5)
 /// man group(5)
 /// man shadow(5)
+///
+/// Optionally there is a 4th file in the `/etc/netgroup` format, man netgroup(5).
 pub struct Files {
     pub passwd: Reloadable<Passwd>,
     pub group: Reloadable<Group>,
     pub shadow: Reloadable<Shadow>,
+    pub netgroup: Option<Reloadable<Netgroup>>,
 }
 
 #[derive(Debug)]
@@ -21,6 +31,8 @@ pub struct Reloadable<T> {
     pub latest_ts: Option<SystemTime>,
     pub pth: PathBuf,
     pub data: Vec<T>,
+    /// The file changed into something that doesn't parse, so `data` is older than what's on disk.
+    pub stale: bool,
 }
 
 impl<T> Reloadable<T> {
@@ -29,8 +41,39 @@ impl<T> Reloadable<T> {
             latest_ts: None,
             pth,
             data: vec![],
+            stale: false,
+        }
+    }
+    /// Take freshly parsed contents, or say why they couldn't be and keep the old ones.
+    fn keep_if_parsed(&mut self, parsed: anyhow::Result<Vec<T>>) {
+        match crate::metrics::reloaded(&self.pth, parsed) {
+            Ok(data) => {
+                self.data = data;
+                self.stale = false;
+            }
+            Err(e) => {
+                tracing::error!(
+                    "not reloading {}, keeping what was there: {:#}",
+                    self.pth.display(),
+                    e
+                );
+                self.stale = true;
+            }
         }
     }
+
+    /// Rewriting the file from stale contents would throw away whatever edit broke it, so refuse
+    /// until it parses again.
+    fn check_writable(&self) -> anyhow::Result<()> {
+        if self.stale {
+            anyhow::bail!(
+                "{} doesn't parse, not changing it until it's fixed",
+                self.pth.display()
+            );
+        }
+        Ok(())
+    }
+
     fn needs_reload(&mut self) -> anyhow::Result<bool> {
         let st = std::fs::metadata(&self.pth)?;
         if Some(st.modified()?) > self.latest_ts {
@@ -41,6 +84,63 @@ impl<T> Reloadable<T> {
     }
 }
 
+/// A TOML file that authd reloads when it changes, and that some RPCs rewrite. A missing file is the
+/// same as an empty one.
+#[derive(Debug)]
+pub struct TomlFile<T> {
+    pth: PathBuf,
+    /// What `save` creates the file with.
+    mode: u32,
+    latest_ts: Option<SystemTime>,
+    pub data: T,
+}
+
+impl<T: Default + DeserializeOwned + Serialize> TomlFile<T> {
+    pub fn new<P: Into<PathBuf>>(pth: P) -> Self {
+        Self {
+            pth: pth.into(),
+            mode: 0o644,
+            latest_ts: None,
+            data: T::default(),
+        }
+    }
+
+    /// Like `new`, but `save` makes the file readable by its owner only, for TOTP secrets and
+    /// the like.
+    pub fn secret<P: Into<PathBuf>>(pth: P) -> Self {
+        Self {
+            mode: 0o600,
+            ..Self::new(pth)
+        }
+    }
+
+    pub fn refresh(&mut self) -> anyhow::Result<()> {
+        let modified = match std::fs::metadata(&self.pth) {
+            Ok(st) => st.modified()?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                self.data = T::default();
+                self.latest_ts = None;
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if Some(modified) > self.latest_ts {
+            let parsed = std::fs::read(&self.pth)
+                .map_err(anyhow::Error::from)
+                .and_then(|contents| Ok(toml::from_slice(&contents)?));
+            self.data = crate::metrics::reloaded(&self.pth, parsed)?;
+            self.latest_ts = Some(modified);
+        }
+        Ok(())
+    }
+
+    pub fn save(&mut self, data: T) -> anyhow::Result<()> {
+        replace_file(&self.pth, self.mode, &toml::to_string(&data)?)?;
+        self.data = data;
+        Ok(())
+    }
+}
+
 impl Files {
     pub fn new<P1, P2, P3>(passwd: P1, group: P2, shadow: P3) -> Self
     where
@@ -52,108 +152,665 @@ impl Files {
             passwd: Reloadable::new(passwd.into()),
             group: Reloadable::new(group.into()),
             shadow: Reloadable::new(shadow.into()),
+            netgroup: None,
         }
     }
 
+    /// Also serve netgroups from this file.
+    pub fn with_netgroup<P: Into<PathBuf>>(mut self, netgroup: P) -> Self {
+        self.netgroup = Some(Reloadable::new(netgroup.into()));
+        self
+    }
+
     pub fn get_all_groups(&self) -> anyhow::Result<Vec<Group>> {
-        let lines = BufReader::new(File::open(&self.group.pth)?).lines();
+        parse_lines(&self.group.pth, parse_group)
+    }
+
+    pub fn get_all_passwd(&self) -> anyhow::Result<Vec<Passwd>> {
+        parse_lines(&self.passwd.pth, parse_passwd)
+    }
+
+    pub fn get_all_shadow(&self) -> anyhow::Result<Vec<Shadow>> {
+        parse_lines(&self.shadow.pth, parse_shadow)
+    }
+
+    pub fn get_all_netgroups(&self) -> anyhow::Result<Vec<Netgroup>> {
+        let pth = match &self.netgroup {
+            Some(ng) => &ng.pth,
+            None => return Ok(vec![]),
+        };
+        let lines = BufReader::new(File::open(pth)?).lines();
 
-        let mut groups = vec![];
+        // a trailing backslash continues the entry on the next line
+        let mut entries = vec![];
+        let mut pending = String::new();
         for line in lines {
             let line = line?;
-            // requires 4 inputs
-            let mut split = line.split(':');
-
-            let name = split.next().unwrap().to_owned();
-            split.next(); // ignore password
-            let gid = split.next().map(|i| i.parse::<u32>().unwrap()).unwrap();
-            let members = split
-                .next()
-                .unwrap()
-                .split(',')
-                .map(ToOwned::to_owned)
-                .collect();
+            match line.strip_suffix('\\') {
+                Some(continued) => {
+                    pending.push_str(continued);
+                    pending.push(' ');
+                }
+                None => {
+                    pending.push_str(&line);
+                    entries.push(std::mem::take(&mut pending));
+                }
+            }
+        }
+        if !pending.is_empty() {
+            entries.push(pending);
+        }
 
-            groups.push(Group { name, gid, members })
+        let mut netgroups = vec![];
+        for entry in entries {
+            let entry = entry.split('#').next().unwrap().trim();
+            if entry.is_empty() {
+                continue;
+            }
+            netgroups.push(parse_netgroup(entry)?);
         }
 
-        Ok(groups)
+        Ok(netgroups)
     }
 
-    pub fn get_all_passwd(&self) -> anyhow::Result<Vec<Passwd>> {
-        // each passwd is seperated by lines
-        let lines = BufReader::new(File::open(&self.passwd.pth)?).lines();
+    /// All the triples in a netgroup, following nested netgroups. `None` if there's no such
+    /// netgroup.
+    ///
+    /// Each netgroup is only visited once, so cycles are harmless, and each triple only comes out
+    /// once.
+    pub fn expand_netgroup(&self, name: &str) -> Option<Vec<NetgroupTriple>> {
+        let data = &self.netgroup.as_ref()?.data;
+        let find = |name: &str| data.iter().find(|ng| ng.name == name);
 
-        let mut passwd = vec![];
-        for line in lines {
-            let line = line?;
-            let mut split = line.split(':');
-            // requires 7 inputs
-            let name = split.next().unwrap().to_owned();
-            split.next(); // ignore password
-            let id = split.next().map(|i| i.parse::<u32>().unwrap()).unwrap();
-            split.next(); // ignore gid
-            let gecos = split.next().unwrap().to_owned();
-            let dir = split.next().unwrap().to_owned();
-            let shell = split.next().unwrap().to_owned();
-
-            passwd.push(Passwd {
-                name,
-                id,
-                gecos,
-                dir,
-                shell,
-            });
+        let mut triples = vec![];
+        let mut seen_triples = HashSet::new();
+        let mut visited = HashSet::new();
+        let mut todo = vec![find(name)?];
+        while let Some(ng) = todo.pop() {
+            if !visited.insert(ng.name.as_str()) {
+                continue;
+            }
+            for member in &ng.members {
+                match member {
+                    NetgroupMember::Triple(t) => {
+                        if seen_triples.insert(t) {
+                            triples.push(t.clone());
+                        }
+                    }
+                    NetgroupMember::Netgroup(nested) => match find(nested) {
+                        Some(nested) => todo.push(nested),
+                        None => tracing::warn!("netgroup {} includes unknown {}", ng.name, nested),
+                    },
+                }
+            }
         }
+        Some(triples)
+    }
 
-        Ok(passwd)
+    /// Record that `name` changed their password on `day`, rewriting the shadow file. Users
+    /// without a shadow entry have no aging to keep track of, so are left alone.
+    pub fn set_last_change(&mut self, name: &str, day: i64) -> anyhow::Result<()> {
+        self.refresh()?;
+        self.shadow.check_writable()?;
+        match self.shadow.data.iter_mut().find(|x| x.name == name) {
+            Some(entry) => entry.last_change = day,
+            None => return Ok(()),
+        }
+        self.write_shadow()
     }
 
-    pub fn get_all_shadow(&self) -> anyhow::Result<Vec<Shadow>> {
-        // each passwd is seperated by lines
-        let lines = BufReader::new(File::open(&self.shadow.pth)?).lines();
+    /// Replace the password field of `name`'s shadow entry, rewriting the shadow file.
+    pub fn set_shadow_password(&mut self, name: &str, passwd: &str) -> anyhow::Result<()> {
+        self.refresh()?;
+        self.shadow.check_writable()?;
+        match self.shadow.data.iter_mut().find(|x| x.name == name) {
+            Some(entry) => entry.passwd = passwd.into(),
+            None => anyhow::bail!("{} has no shadow entry", name),
+        }
+        self.write_shadow()
+    }
 
-        let mut shadow = vec![];
-        for line in lines {
-            let line = line?;
-            let mut split = line.split(':');
-            // requires 9 inputs
-            let name = split.next().unwrap().to_owned();
-            let passwd = split.next().unwrap().to_owned();
-            let last_change = split.next().map(|i| i.parse::<i64>().unwrap()).unwrap();
-            let change_min_days = split.next().map(|i| i.parse::<i64>().unwrap()).unwrap();
-            let change_max_days = split.next().map(|i| i.parse::<i64>().unwrap()).unwrap();
-            let change_warn_days = split.next().map(|i| i.parse::<i64>().unwrap()).unwrap();
-            let change_inactive_days = split.next().map(|i| i.parse::<i64>().ok()).unwrap();
-            let expire_date = split.next().map(|i| i.parse::<i64>().ok()).unwrap();
-            let _unused = split.next().map(|i| i.parse::<i64>().ok()).unwrap();
-
-            shadow.push(Shadow {
-                name,
-                passwd,
-                last_change,
-                change_min_days,
-                change_max_days,
-                change_warn_days,
-                change_inactive_days,
-                expire_date,
-            })
+    /// Add a new account: its passwd and shadow entries, a group of its own with the same id, and
+    /// membership of `groups`. Fails without writing anything if the name or id is taken, or if
+    /// one of the files doesn't parse.
+    pub fn add_user(
+        &mut self,
+        passwd: Passwd,
+        shadow: Shadow,
+        groups: &[String],
+    ) -> anyhow::Result<()> {
+        self.refresh()?;
+        self.passwd.check_writable()?;
+        self.group.check_writable()?;
+        self.shadow.check_writable()?;
+        if self.passwd.data.iter().any(|x| x.name == passwd.name)
+            || self.group.data.iter().any(|x| x.name == passwd.name)
+        {
+            anyhow::bail!("{} already exists", passwd.name);
+        }
+        if self.passwd.data.iter().any(|x| x.id == passwd.id)
+            || self.group.data.iter().any(|x| x.gid == passwd.id)
+        {
+            anyhow::bail!("id {} is already taken", passwd.id);
+        }
+        for group in self
+            .group
+            .data
+            .iter_mut()
+            .filter(|x| groups.contains(&x.name))
+        {
+            // a group with no members reads back as one empty member
+            group.members.retain(|m| !m.is_empty());
+            group.members.push(passwd.name.clone());
         }
+        self.group.data.push(Group {
+            name: passwd.name.clone(),
+            gid: passwd.id,
+            members: vec![],
+        });
+        self.passwd.data.push(passwd);
+        self.shadow.data.push(shadow);
+
+        // shadow last, so a half-made account can't be logged into
+        self.write_passwd()?;
+        self.write_group()?;
+        self.write_shadow()
+    }
+
+    fn write_passwd(&self) -> anyhow::Result<()> {
+        let contents: String = self
+            .passwd
+            .data
+            .iter()
+            .map(|entry| format!("{}\n", entry))
+            .collect();
+        replace_file(&self.passwd.pth, 0o644, &contents)
+    }
 
-        Ok(shadow)
+    fn write_group(&self) -> anyhow::Result<()> {
+        let contents: String = self
+            .group
+            .data
+            .iter()
+            .map(|entry| format!("{}\n", entry))
+            .collect();
+        replace_file(&self.group.pth, 0o644, &contents)
     }
 
+    fn write_shadow(&self) -> anyhow::Result<()> {
+        // shadow's Display already ends the line
+        let contents: String = self.shadow.data.iter().map(ToString::to_string).collect();
+        replace_file(&self.shadow.pth, 0o600, &contents)
+    }
+
+    /// Read the files again if they changed. A file that doesn't parse is logged and counted,
+    /// and its last good contents are kept, so one bad edit doesn't take every lookup down with
+    /// it. It is tried again once it changes, and authd won't write to it until then.
     pub fn refresh(&mut self) -> anyhow::Result<()> {
         if self.passwd.needs_reload()? {
-            self.passwd.data = self.get_all_passwd()?;
+            let data = self.get_all_passwd();
+            self.passwd.keep_if_parsed(data);
         }
         if self.group.needs_reload()? {
-            self.group.data = self.get_all_groups()?;
+            let data = self.get_all_groups();
+            self.group.keep_if_parsed(data);
         }
         if self.shadow.needs_reload()? {
-            self.shadow.data = self.get_all_shadow()?;
+            let data = self.get_all_shadow();
+            self.shadow.keep_if_parsed(data);
+        }
+        if let Some(true) = self
+            .netgroup
+            .as_mut()
+            .map(|ng| ng.needs_reload())
+            .transpose()?
+        {
+            let data = self.get_all_netgroups();
+            self.netgroup.as_mut().unwrap().keep_if_parsed(data);
         }
 
         Ok(())
     }
 }
+
+/// Write `contents` next to `pth` and rename it over `pth`, so nobody ever reads half a file. It
+/// is synced before the rename, so a crash leaves either the old file or the whole new one.
+pub fn replace_file(pth: &Path, mode: u32, contents: &str) -> anyhow::Result<()> {
+    let tmp = pth.with_extension("new");
+    let mut f = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(&tmp)?;
+    // the mode only counts when the file is created, and one could be left over from a crash
+    f.set_permissions(std::fs::Permissions::from_mode(mode))?;
+    f.write_all(contents.as_bytes())?;
+    f.sync_all()?;
+    std::fs::rename(&tmp, pth)?;
+    if let Some(dir) = pth.parent().filter(|d| !d.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Parse every non-blank line of `pth` with `parse`, saying which line it was if one is wrong.
+fn parse_lines<T>(pth: &Path, parse: fn(&str) -> anyhow::Result<T>) -> anyhow::Result<Vec<T>> {
+    let mut entries = vec![];
+    for (n, line) in BufReader::new(File::open(pth)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        entries.push(parse(&line).with_context(|| format!("{} line {}", pth.display(), n + 1))?);
+    }
+    Ok(entries)
+}
+
+/// Split a line into exactly `N` `:`-separated fields.
+fn fields<const N: usize>(line: &str) -> anyhow::Result<[&str; N]> {
+    let fields: Vec<&str> = line.split(':').collect();
+    let count = fields.len();
+    fields
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("expected {} fields, found {}", N, count))
+}
+
+fn number<T: std::str::FromStr>(field: &str, what: &str) -> anyhow::Result<T> {
+    field
+        .parse()
+        .map_err(|_| anyhow::anyhow!("{} {:?} isn't a number", what, field))
+}
+
+/// `name:password:gid:member,member...`, man group(5).
+fn parse_group(line: &str) -> anyhow::Result<Group> {
+    let [name, _password, gid, members] = fields::<4>(line)?;
+    Ok(Group {
+        name: name.to_owned(),
+        gid: number(gid, "gid")?,
+        members: members
+            .split(',')
+            .filter(|m| !m.is_empty())
+            .map(ToOwned::to_owned)
+            .collect(),
+    })
+}
+
+/// `name:password:uid:gid:gecos:dir:shell`, man passwd(5). The gid is always the uid here.
+fn parse_passwd(line: &str) -> anyhow::Result<Passwd> {
+    let [name, _password, id, _gid, gecos, dir, shell] = fields::<7>(line)?;
+    Ok(Passwd {
+        name: name.to_owned(),
+        id: number(id, "uid")?,
+        gecos: gecos.to_owned(),
+        dir: dir.to_owned(),
+        shell: shell.to_owned(),
+    })
+}
+
+/// `name:password:last:min:max:warn:inactive:expire:reserved`, man shadow(5). Any of the numbers
+/// may be empty: an empty last change or maximum age is kept as -1, and an empty minimum age or
+/// warning period is 0.
+fn parse_shadow(line: &str) -> anyhow::Result<Shadow> {
+    let [name, passwd, last_change, min, max, warn, inactive, expire, _reserved] =
+        fields::<9>(line)?;
+    let optional = |field: &str, what| match field {
+        "" => Ok(None),
+        field => number(field, what).map(Some),
+    };
+    let or = |field: &str, what, empty| optional(field, what).map(|x| x.unwrap_or(empty));
+    Ok(Shadow {
+        name: name.to_owned(),
+        passwd: passwd.to_owned(),
+        last_change: or(last_change, "last change", -1)?,
+        change_min_days: or(min, "minimum age", 0)?,
+        change_max_days: or(max, "maximum age", -1)?,
+        change_warn_days: or(warn, "warning period", 0)?,
+        change_inactive_days: optional(inactive, "inactivity period")?,
+        expire_date: optional(expire, "expiry date")?,
+    })
+}
+
+/// Parse one (comment-free, continuation-joined) netgroup entry: `name member member...`, where
+/// each member is either `(host,user,domain)` or the name of another netgroup.
+fn parse_netgroup(entry: &str) -> anyhow::Result<Netgroup> {
+    let (name, mut rest) = entry.split_once(char::is_whitespace).unwrap_or((entry, ""));
+
+    let mut members = vec![];
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            break;
+        }
+        if let Some(triple) = rest.strip_prefix('(') {
+            let (inside, after) = triple
+                .split_once(')')
+                .ok_or_else(|| anyhow::anyhow!("netgroup {}: unclosed triple", name))?;
+            let mut fields = inside.split(',').map(|f| match f.trim() {
+                "" => None,
+                f => Some(f.to_owned()),
+            });
+            let (host, user, domain) = (fields.next(), fields.next(), fields.next());
+            if fields.next().is_some() || domain.is_none() {
+                anyhow::bail!(
+                    "netgroup {}: triple ({}) needs exactly 3 fields",
+                    name,
+                    inside
+                );
+            }
+            members.push(NetgroupMember::Triple(NetgroupTriple {
+                host: host.flatten(),
+                user: user.flatten(),
+                domain: domain.flatten(),
+            }));
+            rest = after;
+        } else {
+            let (nested, after) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+            members.push(NetgroupMember::Netgroup(nested.to_owned()));
+            rest = after;
+        }
+    }
+
+    Ok(Netgroup {
+        name: name.to_owned(),
+        members,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn triple(host: Option<&str>, user: Option<&str>, domain: Option<&str>) -> NetgroupTriple {
+        NetgroupTriple {
+            host: host.map(Into::into),
+            user: user.map(Into::into),
+            domain: domain.map(Into::into),
+        }
+    }
+
+    fn files_with_netgroups(netgroups: Vec<Netgroup>) -> Files {
+        let mut files = Files::new("passwd", "group", "shadow").with_netgroup("netgroup");
+        files.netgroup.as_mut().unwrap().data = netgroups;
+        files
+    }
+
+    #[test]
+    fn parses_passwd_group_and_shadow_lines() {
+        let passwd = parse_passwd("alice:x:2001:2001:Alice A:/home/alice:/bin/bash").unwrap();
+        assert_eq!(passwd.name, "alice");
+        assert_eq!(passwd.id, 2001);
+        assert_eq!(passwd.gecos, "Alice A");
+        assert_eq!(passwd.dir, "/home/alice");
+        assert_eq!(passwd.shell, "/bin/bash");
+
+        let group = parse_group("cosi:x:3000:alice,bob").unwrap();
+        assert_eq!(group.gid, 3000);
+        assert_eq!(group.members, vec!["alice", "bob"]);
+        assert!(parse_group("alice:x:2001:").unwrap().members.is_empty());
+
+        let shadow = parse_shadow("alice:$6$salt$hash:19000:1:90:7:14:20000:").unwrap();
+        assert_eq!(shadow.passwd, "$6$salt$hash");
+        assert_eq!(
+            (
+                shadow.last_change,
+                shadow.change_min_days,
+                shadow.change_max_days
+            ),
+            (19000, 1, 90)
+        );
+        assert_eq!(shadow.change_warn_days, 7);
+        assert_eq!(shadow.change_inactive_days, Some(14));
+        assert_eq!(shadow.expire_date, Some(20000));
+        let shadow = parse_shadow("bob:*:19000:0:99999:7:::").unwrap();
+        assert_eq!(
+            (shadow.change_inactive_days, shadow.expire_date),
+            (None, None)
+        );
+
+        // what authd writes reads back the same
+        assert_eq!(parse_passwd(&passwd.to_string()).unwrap().id, 2001);
+        assert_eq!(parse_group(&group.to_string()).unwrap().members.len(), 2);
+        let written = shadow.to_string();
+        assert_eq!(parse_shadow(written.trim_end()).unwrap().name, "bob");
+    }
+
+    #[test]
+    fn malformed_lines_are_errors() {
+        for line in [
+            "alice:x:2001:2001:Alice:/home/alice",
+            "alice:x:2001:2001:Alice:/home/alice:/bin/sh:extra",
+            "alice:x:notanumber:2001:Alice:/home/alice:/bin/sh",
+            "alice",
+        ] {
+            assert!(parse_passwd(line).is_err(), "{}", line);
+        }
+        for line in ["cosi:x:3000", "cosi:x::alice", "cosi:x:-1:alice"] {
+            assert!(parse_group(line).is_err(), "{}", line);
+        }
+        for line in [
+            "alice:*:19000:0:99999:7::",
+            "alice:*:19000:none:99999:7:::",
+            "alice:*:19000:0:99999:7:soon::",
+        ] {
+            assert!(parse_shadow(line).is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn empty_shadow_fields_mean_what_shadow_5_says() {
+        let shadow = parse_shadow("alice:*:::::::").unwrap();
+        // no aging at all, no minimum age or warning, and never has to change
+        assert_eq!(
+            (
+                shadow.last_change,
+                shadow.change_min_days,
+                shadow.change_max_days,
+                shadow.change_warn_days
+            ),
+            (-1, 0, -1, 0)
+        );
+        assert_eq!(
+            shadow.aging_status(crate::types::today()),
+            crate::types::AccountStatus::Ok
+        );
+
+        let shadow = parse_shadow("bob:*:19000:::::20000:").unwrap();
+        assert_eq!(shadow.last_change, 19000);
+        assert_eq!(shadow.change_max_days, -1);
+        assert_eq!(shadow.aging_status(19500), crate::types::AccountStatus::Ok);
+
+        // and they're written back empty
+        assert_eq!(shadow.to_string(), "bob:*:19000:0::0::20000:\n");
+    }
+
+    #[test]
+    fn a_bad_line_keeps_the_last_good_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let pth = |name: &str| dir.path().join(name);
+        std::fs::write(pth("passwd"), "alice:x:2001:2001::/home/alice:/bin/sh\n\n").unwrap();
+        std::fs::write(pth("group"), "alice:x:2001:\n").unwrap();
+        std::fs::write(pth("shadow"), "alice:*:19000:0:99999:7:::\n").unwrap();
+        let mut files = Files::new(pth("passwd"), pth("group"), pth("shadow"));
+        files.refresh().unwrap();
+        assert_eq!(files.passwd.data.len(), 1);
+
+        std::fs::write(
+            pth("passwd"),
+            "alice:x:2001:2001::/home/alice:/bin/sh\nbob:x:oops:2002::/home/bob:/bin/sh\n",
+        )
+        .unwrap();
+        let err = files.get_all_passwd().unwrap_err();
+        assert!(format!("{:#}", err).contains("line 2"), "{:#}", err);
+        // make sure the change is noticed even if the mtime didn't move
+        files.passwd.latest_ts = None;
+        files.refresh().unwrap();
+        assert_eq!(files.passwd.data.len(), 1);
+        assert_eq!(files.passwd.data[0].name, "alice");
+    }
+
+    #[test]
+    fn writes_wait_until_a_bad_edit_is_fixed() {
+        let dir = tempfile::tempdir().unwrap();
+        let pth = |name: &str| dir.path().join(name);
+        std::fs::write(pth("passwd"), "alice:x:2001:2001::/home/alice:/bin/sh\n").unwrap();
+        std::fs::write(pth("group"), "alice:x:2001:\n").unwrap();
+        std::fs::write(pth("shadow"), "alice:*:19000:0:99999:7:::\n").unwrap();
+        let mut files = Files::new(pth("passwd"), pth("group"), pth("shadow"));
+        files.refresh().unwrap();
+
+        // the admin adds bob by hand, and gets a field wrong
+        let edited = "alice:*:19000:0:99999:7:::\nbob:*:oops:0:99999:7:::\n";
+        std::fs::write(pth("shadow"), edited).unwrap();
+        files.shadow.latest_ts = None;
+        files.refresh().unwrap();
+        assert!(files.shadow.stale);
+
+        assert!(files.set_last_change("alice", 19500).is_err());
+        assert!(files.set_shadow_password("alice", "!").is_err());
+        let carol = Passwd {
+            name: "carol".into(),
+            id: 2003,
+            gecos: String::new(),
+            dir: "/home/carol".into(),
+            shell: "/bin/sh".into(),
+        };
+        let carol_shadow = Shadow {
+            name: "carol".into(),
+            ..files.shadow.data[0].clone()
+        };
+        assert!(files.add_user(carol, carol_shadow, &[]).is_err());
+        assert_eq!(std::fs::read_to_string(pth("shadow")).unwrap(), edited);
+        assert!(!std::fs::read_to_string(pth("passwd"))
+            .unwrap()
+            .contains("carol"));
+
+        // once it's fixed, writes go through and keep the edit
+        std::fs::write(pth("shadow"), edited.replace("oops", "19000")).unwrap();
+        files.shadow.latest_ts = None;
+        files.set_last_change("alice", 19500).unwrap();
+        let shadow = std::fs::read_to_string(pth("shadow")).unwrap();
+        assert!(shadow.starts_with("alice:*:19500:"), "{}", shadow);
+        assert!(shadow.contains("bob:*:19000:"), "{}", shadow);
+    }
+
+    #[test]
+    fn parses_triples_and_nested_netgroups() {
+        let ng = parse_netgroup("admins (lab1,tj,) ( , ember , cosi) staff").unwrap();
+        assert_eq!(ng.name, "admins");
+        let triples: Vec<_> = ng
+            .members
+            .iter()
+            .filter_map(|m| match m {
+                NetgroupMember::Triple(t) => Some(t.clone()),
+                NetgroupMember::Netgroup(_) => None,
+            })
+            .collect();
+        assert_eq!(
+            triples,
+            vec![
+                triple(Some("lab1"), Some("tj"), None),
+                triple(None, Some("ember"), Some("cosi")),
+            ]
+        );
+        assert!(matches!(&ng.members[2], NetgroupMember::Netgroup(n) if n == "staff"));
+    }
+
+    #[test]
+    fn rejects_malformed_triples() {
+        assert!(parse_netgroup("broken (a,b,c").is_err());
+        assert!(parse_netgroup("broken (a,b)").is_err());
+        assert!(parse_netgroup("broken (a,b,c,d)").is_err());
+    }
+
+    #[test]
+    fn joins_continuation_lines_and_drops_comments() {
+        let dir = tempfile::tempdir().unwrap();
+        let pth = dir.path().join("netgroup");
+        std::fs::write(
+            &pth,
+            concat!(
+                "# who may log in\n",
+                "trusted (lab1,tj,) \\\n",
+                "    (lab2,ember,) staff # the rest\n",
+                "\n",
+                "staff (,kim,)\n",
+            ),
+        )
+        .unwrap();
+        let files = Files::new("passwd", "group", "shadow").with_netgroup(&pth);
+        let netgroups = files.get_all_netgroups().unwrap();
+        assert_eq!(netgroups.len(), 2);
+        assert_eq!(netgroups[0].name, "trusted");
+        assert_eq!(netgroups[0].members.len(), 3);
+        assert_eq!(netgroups[1].name, "staff");
+    }
+
+    #[test]
+    fn saving_replaces_the_file_with_its_mode() {
+        #[derive(Default, serde::Deserialize, Serialize)]
+        struct Rules {
+            rules: Vec<String>,
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let pth = dir.path().join("rules.toml");
+        std::fs::write(&pth, "rules = ['old']\n").unwrap();
+        std::fs::set_permissions(&pth, std::fs::Permissions::from_mode(0o666)).unwrap();
+        // as if a crash had left one behind
+        std::fs::write(pth.with_extension("new"), "half a fi").unwrap();
+
+        let mut file = TomlFile::<Rules>::new(&pth);
+        file.refresh().unwrap();
+        assert_eq!(file.data.rules, vec!["old"]);
+        file.save(Rules {
+            rules: vec!["new".into()],
+        })
+        .unwrap();
+
+        let mode = std::fs::metadata(&pth).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode, 0o644);
+        assert_eq!(
+            std::fs::read_to_string(&pth).unwrap(),
+            "rules = [\"new\"]\n"
+        );
+        assert!(!pth.with_extension("new").exists());
+    }
+
+    #[test]
+    fn secrets_are_saved_for_the_owner_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let pth = dir.path().join("totp.toml");
+        std::fs::write(&pth, "").unwrap();
+        std::fs::set_permissions(&pth, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        let mut file = TomlFile::<std::collections::BTreeMap<String, String>>::secret(&pth);
+        file.refresh().unwrap();
+        file.save(std::collections::BTreeMap::from([(
+            "alice".into(),
+            "JBSWY3DP".into(),
+        )]))
+        .unwrap();
+
+        let mode = std::fs::metadata(&pth).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode, 0o600);
+    }
+
+    #[test]
+    fn expands_nested_netgroups_through_cycles() {
+        let files = files_with_netgroups(vec![
+            parse_netgroup("a (h1,u1,) b").unwrap(),
+            parse_netgroup("b (h2,u2,) a c (h1,u1,)").unwrap(),
+            parse_netgroup("c (h3,u3,) missing").unwrap(),
+        ]);
+        let triples = files.expand_netgroup("a").unwrap();
+        assert_eq!(
+            triples,
+            vec![
+                triple(Some("h1"), Some("u1"), None),
+                triple(Some("h2"), Some("u2"), None),
+                triple(Some("h3"), Some("u3"), None),
+            ]
+        );
+        assert_eq!(files.expand_netgroup("nope"), None);
+    }
+}
@@ -0,0 +1,67 @@
+//! Invite codes, which let new members make their own accounts without an admin sitting next to
+//! them.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Where UIDs for enrolled accounts start unless `enroll_uid_min` says otherwise.
+pub const DEFAULT_UID_MIN: u32 = 10000;
+/// Longest username useradd(8) takes.
+const MAX_USERNAME_LEN: usize = 32;
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Invites {
+    /// Outstanding invites, by the hex SHA-256 of the code, like reset tokens.
+    #[serde(default)]
+    pub invites: BTreeMap<String, Invite>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Invite {
+    /// The admin who made it.
+    pub issued_by: String,
+    /// Seconds since 1970 after which the code is no good.
+    pub expires: u64,
+    /// How many more accounts the code can make.
+    pub uses_left: u32,
+    /// Groups accounts made with the code are put in.
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+impl Invites {
+    /// Drop invites that expired before `now` or are used up, returning whether there were any.
+    pub fn prune(&mut self, now: u64) -> bool {
+        let before = self.invites.len();
+        self.invites
+            .retain(|_, i| i.expires >= now && i.uses_left > 0);
+        self.invites.len() != before
+    }
+
+    /// The usable invite matching `code`, if there is one.
+    pub fn find(&self, code: &str, now: u64) -> Option<&Invite> {
+        self.invites
+            .get(&crate::reset::hash_token(code))
+            .filter(|i| i.expires >= now && i.uses_left > 0)
+    }
+}
+
+/// Check `name` is something useradd would take by default: a lowercase letter or `_`, then
+/// lowercase letters, digits, `_` and `-`.
+pub fn check_username(name: &str) -> Result<(), String> {
+    let mut chars = name.chars();
+    let first_ok = matches!(chars.next(), Some('a'..='z' | '_'));
+    if !first_ok || !chars.all(|c| matches!(c, 'a'..='z' | '0'..='9' | '_' | '-')) {
+        return Err(format!(
+            "{:?} has to start with a lowercase letter or _, then only have lowercase letters, digits, _ and -",
+            name
+        ));
+    }
+    if name.len() > MAX_USERNAME_LEN {
+        return Err(format!(
+            "{:?} is longer than {} characters",
+            name, MAX_USERNAME_LEN
+        ));
+    }
+    Ok(())
+}
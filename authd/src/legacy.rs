@@ -0,0 +1,122 @@
+//! Checking passwords against the crypt(3) hashes accounts had before authd, so they can move to
+//! OPAQUE the first time they log in.
+//!
+//! This goes through the system's libcrypt rather than a Rust implementation, since it is what
+//! made (and until now checked) the hashes: it knows bcrypt, SHA-512, yescrypt and the rest.
+//!
+//! The password still has to reach authd once to be checked. The client first registers an
+//! OPAQUE credential and logs in with it, and sends the password sealed under that login's
+//! session key, so it never crosses the wire as-is even inside TLS.
+
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit, Nonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::{
+    ffi::{CStr, CString},
+    os::raw::c_char,
+    sync::Mutex,
+};
+use zeroize::Zeroizing;
+
+const SEAL_INFO: &[u8] = b"cosiauthd legacy migration password";
+
+#[link(name = "crypt")]
+extern "C" {
+    fn crypt(phrase: *const c_char, setting: *const c_char) -> *mut c_char;
+}
+
+/// `crypt` hands back a static buffer.
+static CRYPT: Mutex<()> = Mutex::new(());
+
+/// Whether a shadow password field is a hash we can check, as opposed to `*`, `!`-locked, empty or
+/// some other way of saying there is no password.
+pub fn is_crypt_hash(hash: &str) -> bool {
+    hash.starts_with('$')
+}
+
+/// Whether `password` hashes to `hash`.
+pub fn verify(password: &[u8], hash: &str) -> bool {
+    if !is_crypt_hash(hash) {
+        return false;
+    }
+    let (phrase, setting) = match (CString::new(password), CString::new(hash)) {
+        (Ok(phrase), Ok(setting)) => (phrase, setting),
+        _ => return false,
+    };
+    let _guard = CRYPT.lock().unwrap_or_else(|e| e.into_inner());
+    let out = unsafe { crypt(phrase.as_ptr(), setting.as_ptr()) };
+    if out.is_null() {
+        return false;
+    }
+    // libxcrypt fails with a hash starting with `*` rather than NULL
+    let out = unsafe { CStr::from_ptr(out) }.to_bytes();
+    out.len() == hash.len()
+        && out
+            .iter()
+            .zip(hash.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// The key a migrating password is sealed under, from the session key of the login made with the
+/// new credential.
+fn sealing_key(session_key: &[u8]) -> ChaCha20Poly1305 {
+    let mut key = Zeroizing::new([0; 32]);
+    Hkdf::<Sha256>::new(None, session_key)
+        .expand(SEAL_INFO, &mut *key)
+        .expect("32 bytes is a valid hkdf output length");
+    ChaCha20Poly1305::new((&*key).into())
+}
+
+/// Seal `password` for `open_password` on the other end of the login that made `session_key`.
+pub fn seal_password(session_key: &[u8], password: &[u8]) -> Vec<u8> {
+    // every login has its own session key, and this is the only thing sealed with it, so a
+    // fixed nonce is never reused
+    sealing_key(session_key)
+        .encrypt(&Nonce::default(), password)
+        .expect("sealing a password")
+}
+
+/// Undo `seal_password`, or `None` if it wasn't sealed under `session_key` or was tampered with.
+pub fn open_password(session_key: &[u8], sealed: &[u8]) -> Option<Zeroizing<Vec<u8>>> {
+    sealing_key(session_key)
+        .decrypt(&Nonce::default(), sealed)
+        .ok()
+        .map(Zeroizing::new)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sealed_passwords_open_with_the_same_session_key() {
+        let key = [7; 64];
+        let sealed = seal_password(&key, b"hunter2");
+        assert!(!sealed.windows(7).any(|w| w == b"hunter2"));
+        assert_eq!(
+            open_password(&key, &sealed).as_deref().map(Vec::as_slice),
+            Some(&b"hunter2"[..])
+        );
+    }
+
+    #[test]
+    fn sealed_passwords_refuse_other_keys_and_tampering() {
+        let sealed = seal_password(&[7; 64], b"hunter2");
+        assert!(open_password(&[8; 64], &sealed).is_none());
+
+        let mut tampered = sealed.clone();
+        tampered[0] ^= 1;
+        assert!(open_password(&[7; 64], &tampered).is_none());
+        assert!(open_password(&[7; 64], &sealed[..sealed.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn only_real_hashes_are_checked() {
+        assert!(is_crypt_hash("$6$salt$hash"));
+        for not_a_hash in ["", "*", "!", "!$6$salt$hash", "x"] {
+            assert!(!is_crypt_hash(not_a_hash));
+            assert!(!verify(b"", not_a_hash));
+        }
+    }
+}
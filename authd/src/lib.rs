@@ -6,11 +6,23 @@ use stubborn_io::{ReconnectOptions, StubbornTcpStream};
 use tarpc::serde_transport::Transport;
 use tokio::net::ToSocketAddrs;
 
+pub mod access;
+pub mod audit;
 pub mod files;
+pub mod invite;
+pub mod legacy;
+pub mod metrics;
+pub mod nss;
+pub mod policy;
+pub mod reset;
 pub mod rpc;
+pub mod ssh;
+pub mod sudo;
+pub mod tls;
+pub mod totp;
 pub mod types;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum SocketName {
     Dns(String, u16),
     Addr(SocketAddr),
@@ -71,33 +83,107 @@ pub struct AuthdConfig {
     pub passwd_file: String,
     pub shadow_file: String,
     pub group_file: String,
+    /// Netgroups are only served if this is set.
+    pub netgroup_file: Option<String>,
+    /// Where the rules about who may log in where are kept, see [`access`].
+    pub access_file: Option<String>,
+    /// Sudo rules to serve, see [`sudo`].
+    pub sudo_file: Option<String>,
+    /// Where users' SSH public keys are kept, see [`ssh`].
+    pub ssh_keys_file: Option<String>,
+    /// OpenSSH private key (unencrypted) to sign SSH certificates with. No certificates without it.
+    pub ssh_ca_key: Option<String>,
+    /// How many hours SSH user certificates are valid for.
+    pub ssh_user_cert_hours: Option<u64>,
+    /// How many days SSH host certificates are valid for.
+    pub ssh_host_cert_days: Option<u64>,
+    /// Where TOTP secrets are kept. Nobody can enroll without it.
+    pub totp_file: Option<String>,
+    /// Who has to give a TOTP code when logging in.
+    #[serde(default)]
+    pub totp_required: totp::TotpPolicy,
+    /// Where outstanding password reset tokens are kept, hashed. No resets without it.
+    pub reset_file: Option<String>,
+    /// How many hours password reset tokens are valid for.
+    pub reset_token_hours: Option<u64>,
+    /// Where outstanding invite codes are kept, hashed. Nobody can enroll without it.
+    pub invites_file: Option<String>,
+    /// Lowest UID (and GID) handed out to enrolled accounts.
+    pub enroll_uid_min: Option<u32>,
+    /// Directory enrolled accounts get their home directory in, `/home` by default.
+    pub enroll_home_base: Option<String>,
+    /// Login shell of enrolled accounts, `/bin/bash` by default.
+    pub enroll_shell: Option<String>,
+    /// Let accounts that only have a crypt(3) hash in shadow log in with it once, over TLS, and
+    /// register an OPAQUE credential with the same password. Off unless set.
+    #[serde(default)]
+    pub legacy_login: bool,
+    /// Where to append the audit log of logins and changes, see [`audit`]. Nothing is kept
+    /// without it.
+    pub audit_file: Option<String>,
+    /// How many hours `auth login` sessions last.
+    pub session_hours: Option<u64>,
     pub opaque_cookies: String,
     pub cert: String,
     pub key: String,
+    /// Where to serve Prometheus metrics over plain HTTP, at `/metrics`. Not served without it.
+    pub metrics_addr: Option<SocketAddr>,
+    /// Where to serve the HTTPS JSON API, with the same certificate as `bind_addrs`. Not served
+    /// without it.
+    pub http_addr: Option<SocketAddr>,
+    /// Recommended TTL (seconds) for clients caching lookups that found something.
+    pub cache_positive_ttl: Option<u64>,
+    /// Recommended TTL (seconds) for clients caching lookups that found nothing.
+    pub cache_negative_ttl: Option<u64>,
+    /// Who may read passwd, group and shadow.
+    #[serde(default)]
+    pub read_policy: policy::ReadPolicy,
 }
 
 impl AuthdConfig {
+    /// Read, parse and expand the config at `path`.
+    pub fn load(path: &std::path::Path) -> anyhow::Result<Self> {
+        let mut cfg: AuthdConfig = toml::from_slice(&std::fs::read(path)?)?;
+        cfg.try_expand()?;
+        if cfg.bind_addrs.is_empty() {
+            anyhow::bail!("bind_addrs is empty");
+        }
+        Ok(cfg)
+    }
+
     /// Shell-expand any paths in the config.
     pub fn expand(&mut self) {
-        self.opaque_server_setup = shellexpand::full(&self.opaque_server_setup)
-            .expect("expanding opaque_server_setup")
-            .into();
-        self.passwd_file = shellexpand::full(&self.passwd_file)
-            .expect("expanding passwd_file")
-            .into();
-        self.shadow_file = shellexpand::full(&self.shadow_file)
-            .expect("expanding shadow_file")
-            .into();
-        self.group_file = shellexpand::full(&self.group_file)
-            .expect("expanding group_file")
-            .into();
-        self.opaque_cookies = shellexpand::full(&self.opaque_cookies)
-            .expect("expanding opaque_cookies")
-            .into();
-        self.cert = shellexpand::full(&self.cert)
-            .expect("expanding cert")
-            .into();
-        self.key = shellexpand::full(&self.key).expect("expanding key").into();
+        self.try_expand().expect("expanding config")
+    }
+
+    /// Like [`expand`](Self::expand), but an error naming the path that didn't expand instead of a
+    /// panic.
+    pub fn try_expand(&mut self) -> anyhow::Result<()> {
+        fn full(name: &str, path: &str) -> anyhow::Result<String> {
+            Ok(shellexpand::full(path)
+                .map_err(|e| anyhow::anyhow!("expanding {}: {}", name, e))?
+                .into())
+        }
+        fn opt(name: &str, path: &Option<String>) -> anyhow::Result<Option<String>> {
+            path.as_deref().map(|path| full(name, path)).transpose()
+        }
+        self.opaque_server_setup = full("opaque_server_setup", &self.opaque_server_setup)?;
+        self.passwd_file = full("passwd_file", &self.passwd_file)?;
+        self.shadow_file = full("shadow_file", &self.shadow_file)?;
+        self.group_file = full("group_file", &self.group_file)?;
+        self.netgroup_file = opt("netgroup_file", &self.netgroup_file)?;
+        self.access_file = opt("access_file", &self.access_file)?;
+        self.sudo_file = opt("sudo_file", &self.sudo_file)?;
+        self.ssh_keys_file = opt("ssh_keys_file", &self.ssh_keys_file)?;
+        self.ssh_ca_key = opt("ssh_ca_key", &self.ssh_ca_key)?;
+        self.totp_file = opt("totp_file", &self.totp_file)?;
+        self.reset_file = opt("reset_file", &self.reset_file)?;
+        self.invites_file = opt("invites_file", &self.invites_file)?;
+        self.audit_file = opt("audit_file", &self.audit_file)?;
+        self.opaque_cookies = full("opaque_cookies", &self.opaque_cookies)?;
+        self.cert = full("cert", &self.cert)?;
+        self.key = full("key", &self.key)?;
+        Ok(())
     }
 }
 
@@ -105,7 +191,14 @@ impl AuthdConfig {
 ///
 /// If neither exist, return `$XDG_CONFIG_DIR/auth`. Unless `$XDG_CONFIG_DIR` is bogus, in which
 /// case `Err`.
+///
+/// Setting `$AUTH_CONFIG_DIR` skips the search entirely, which is mostly useful for tests. It is
+/// ignored in setuid and setgid programs, since the NSS and PAM modules end up in `su`, `sudo` and
+/// `passwd`, and whoever runs those mustn't get to pick the authd they trust.
 pub fn find_config_dir() -> anyhow::Result<PathBuf> {
+    if let Some(dir) = config_dir_override(is_privileged()) {
+        return Ok(dir.into());
+    }
     if let Ok(true) = std::path::Path::new("/etc/auth").try_exists() {
         return Ok("/etc/auth".into());
     }
@@ -119,32 +212,286 @@ pub fn find_config_dir() -> anyhow::Result<PathBuf> {
     Ok(config_dir)
 }
 
-/// Connect to authd over TLS, already knowing + trusting its certificate (if we don't get MITM).
+/// Whether this process has more privilege than whoever started it, so its environment can't be
+/// trusted. The same test as glibc's `secure_getenv`, plus the ids in case the kernel didn't say.
+fn is_privileged() -> bool {
+    unsafe {
+        libc::getauxval(libc::AT_SECURE) != 0
+            || libc::geteuid() != libc::getuid()
+            || libc::getegid() != libc::getgid()
+    }
+}
+
+/// `$AUTH_CONFIG_DIR`, if it is set and the environment can be trusted.
+fn config_dir_override(privileged: bool) -> Option<std::ffi::OsString> {
+    if privileged {
+        return None;
+    }
+    std::env::var_os("AUTH_CONFIG_DIR")
+}
+
+/// Why [`client_login`] didn't work out.
+#[derive(Debug)]
+pub enum LoginError {
+    /// Couldn't talk to authd at all.
+    Unavailable(tarpc::client::RpcError),
+    /// authd said no.
+    Rejected(rpc::RpcError),
+    /// The password didn't open the envelope authd sent back.
+    BadPassword,
+    /// The password was right, but the session only counts once a TOTP code is verified with
+    /// `verify_totp`.
+    TotpRequired,
+    /// The password was right, but this account has to enroll in TOTP before the session counts.
+    TotpEnrollmentRequired,
+    /// OPAQUE itself failed, which shouldn't happen.
+    Protocol(String),
+}
+
+impl std::fmt::Display for LoginError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoginError::Unavailable(e) => write!(f, "could not reach authd: {}", e),
+            LoginError::Rejected(e) => write!(f, "authd rejected login: {}", e),
+            LoginError::BadPassword => write!(f, "login failure: bad password?"),
+            LoginError::TotpRequired => write!(f, "a TOTP code is required"),
+            LoginError::TotpEnrollmentRequired => write!(f, "TOTP enrollment is required"),
+            LoginError::Protocol(e) => write!(f, "OPAQUE failure: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for LoginError {}
+
+impl From<tarpc::client::RpcError> for LoginError {
+    fn from(e: tarpc::client::RpcError) -> Self {
+        LoginError::Unavailable(e)
+    }
+}
+
+impl From<rpc::RpcError> for LoginError {
+    fn from(e: rpc::RpcError) -> Self {
+        LoginError::Rejected(e)
+    }
+}
+
+/// Log in as `username` over an existing connection, so that later RPCs on it are authenticated.
+///
+/// Accounts that need a second factor get [`LoginError::TotpRequired`] or
+/// [`LoginError::TotpEnrollmentRequired`], and the connection stays half logged in until they
+/// finish it.
+///
+/// Accounts that still only have a crypt(3) hash can't log in until they are moved to OPAQUE
+/// with [`client_migrate_legacy`], which callers only try when asked to.
+pub async fn client_login(
+    client: &rpc::AuthdClient,
+    username: &str,
+    password: &[u8],
+) -> Result<(), LoginError> {
+    let mut rng = rand::rngs::OsRng;
+    let login = opaque_ke::ClientLogin::<rpc::DefaultCipherSuite>::start(&mut rng, password)
+        .map_err(|e| LoginError::Protocol(format!("{:?}", e)))?;
+    let resp = client
+        .start_login(
+            tarpc::context::current(),
+            username.to_owned(),
+            login.message,
+        )
+        .await??;
+    let finished = login
+        .state
+        .finish(
+            password,
+            resp,
+            opaque_ke::ClientLoginFinishParameters::default(),
+        )
+        .map_err(|_| LoginError::BadPassword)?;
+    match client
+        .finish_login(tarpc::context::current(), finished.message)
+        .await??
+    {
+        rpc::LoginStatus::LoggedIn => Ok(()),
+        rpc::LoginStatus::NeedTotp => Err(LoginError::TotpRequired),
+        rpc::LoginStatus::NeedTotpEnrollment => Err(LoginError::TotpEnrollmentRequired),
+    }
+}
+
+/// Move `username` from a crypt(3) hash to OPAQUE: register a credential for `password`, log in
+/// with it, and send `password` sealed under that login's session key for authd to check against
+/// the hash. An account with no hash to check fails just like a wrong password, so only call this
+/// when `legacy_login_available` said yes or the user asked to migrate: it is the one time the
+/// password leaves the client. Log in afterwards as usual.
+pub async fn client_migrate_legacy(
+    client: &rpc::AuthdClient,
+    username: &str,
+    password: &[u8],
+) -> Result<(), LoginError> {
+    let mut rng = rand::rngs::OsRng;
+    let reg = opaque_ke::ClientRegistration::<rpc::DefaultCipherSuite>::start(&mut rng, password)
+        .map_err(|e| LoginError::Protocol(format!("{:?}", e)))?;
+    let resp = client
+        .start_legacy_migration(tarpc::context::current(), username.into(), reg.message)
+        .await??;
+    let registered = reg
+        .state
+        .finish(
+            &mut rng,
+            password,
+            resp,
+            opaque_ke::ClientRegistrationFinishParameters::default(),
+        )
+        .map_err(|e| LoginError::Protocol(format!("{:?}", e)))?;
+
+    let login = opaque_ke::ClientLogin::<rpc::DefaultCipherSuite>::start(&mut rng, password)
+        .map_err(|e| LoginError::Protocol(format!("{:?}", e)))?;
+    let resp = client
+        .continue_legacy_migration(tarpc::context::current(), registered.message, login.message)
+        .await??;
+    let finished = login
+        .state
+        .finish(
+            password,
+            resp,
+            opaque_ke::ClientLoginFinishParameters::default(),
+        )
+        .map_err(|e| LoginError::Protocol(format!("{:?}", e)))?;
+    let sealed = legacy::seal_password(&finished.session_key, password);
+    match client
+        .finish_legacy_migration(tarpc::context::current(), finished.message, sealed)
+        .await?
+    {
+        Ok(()) => Ok(()),
+        Err(rpc::RpcError::AuthenticationFailure) => Err(LoginError::BadPassword),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Replace the credential of whoever is logged in on `client` with one for `new_password`.
+pub async fn client_change_password(
+    client: &rpc::AuthdClient,
+    new_password: &[u8],
+) -> Result<(), LoginError> {
+    let mut rng = rand::rngs::OsRng;
+    let reg =
+        opaque_ke::ClientRegistration::<rpc::DefaultCipherSuite>::start(&mut rng, new_password)
+            .map_err(|e| LoginError::Protocol(format!("{:?}", e)))?;
+    let resp = client
+        .start_self_registration(tarpc::context::current(), reg.message)
+        .await??;
+    let finished = reg
+        .state
+        .finish(
+            &mut rng,
+            new_password,
+            resp,
+            opaque_ke::ClientRegistrationFinishParameters::default(),
+        )
+        .map_err(|e| LoginError::Protocol(format!("{:?}", e)))?;
+    client
+        .finish_self_registration(tarpc::context::current(), finished.message)
+        .await??;
+    Ok(())
+}
+
+/// Replace the credential of the account `token` was issued for with one for `new_password`,
+/// without logging in. Returns the account's username.
+pub async fn client_redeem_reset(
+    client: &rpc::AuthdClient,
+    token: &str,
+    new_password: &[u8],
+) -> Result<String, LoginError> {
+    let mut rng = rand::rngs::OsRng;
+    let reg =
+        opaque_ke::ClientRegistration::<rpc::DefaultCipherSuite>::start(&mut rng, new_password)
+            .map_err(|e| LoginError::Protocol(format!("{:?}", e)))?;
+    let (username, resp) = client
+        .start_reset_registration(tarpc::context::current(), token.into(), reg.message)
+        .await??;
+    let finished = reg
+        .state
+        .finish(
+            &mut rng,
+            new_password,
+            resp,
+            opaque_ke::ClientRegistrationFinishParameters::default(),
+        )
+        .map_err(|e| LoginError::Protocol(format!("{:?}", e)))?;
+    client
+        .finish_reset_registration(tarpc::context::current(), finished.message)
+        .await??;
+    Ok(username)
+}
+
+/// Make a new account called `username` with an invite code, registering `password` for it.
+/// Returns the account authd made.
+pub async fn client_enroll(
+    client: &rpc::AuthdClient,
+    code: &str,
+    username: &str,
+    password: &[u8],
+) -> Result<types::Passwd, LoginError> {
+    let mut rng = rand::rngs::OsRng;
+    let reg = opaque_ke::ClientRegistration::<rpc::DefaultCipherSuite>::start(&mut rng, password)
+        .map_err(|e| LoginError::Protocol(format!("{:?}", e)))?;
+    let resp = client
+        .start_enrollment(
+            tarpc::context::current(),
+            code.into(),
+            username.into(),
+            reg.message,
+        )
+        .await??;
+    let finished = reg
+        .state
+        .finish(
+            &mut rng,
+            password,
+            resp,
+            opaque_ke::ClientRegistrationFinishParameters::default(),
+        )
+        .map_err(|e| LoginError::Protocol(format!("{:?}", e)))?;
+    Ok(client
+        .finish_enrollment(tarpc::context::current(), finished.message)
+        .await??)
+}
+
+/// Connect to authd over TLS, trusting it however `trust` says to.
 ///
-/// The server_name is used for SNI. Setting it to localhost is fine for testing.
+/// The server_name is used for SNI, and checked against the certificate when trusting a CA. See
+/// [`tls::ServerTrust::server_name_for`].
 pub async fn client_connect<A: ToSocketAddrs + Unpin + Clone + Send + Sync + 'static>(
     addr: A,
-    cert: &rustls::Certificate,
+    trust: &tls::ServerTrust,
     server_name: &str,
 ) -> anyhow::Result<rpc::AuthdClient> {
+    let config = trust.client_config()?;
+
     let reconnect_opts = ReconnectOptions::new()
         .with_exit_if_first_connect_fails(false)
         .with_retries_generator(|| std::iter::repeat(Duration::from_secs(1)));
     let tcp_stream = StubbornTcpStream::connect_with_options(addr, reconnect_opts).await?;
 
-    let mut roots = rustls::RootCertStore::empty();
-    roots.add(cert).unwrap();
-
-    let config = rustls::ClientConfig::builder()
-        .with_safe_defaults()
-        .with_root_certificates(roots)
-        .with_no_client_auth();
-
     let connector = TlsConnector::from(Arc::new(config));
-    let servername = rustls::ServerName::try_from(server_name).unwrap();
+    let servername = rustls::ServerName::try_from(server_name)?;
     let transport = Transport::from((
         connector.connect(servername, tcp_stream).await?,
         tarpc::tokio_serde::formats::Json::default(),
     ));
     Ok(rpc::AuthdClient::new(tarpc::client::Config::default(), transport).spawn())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_dir_override_is_ignored_when_privileged() {
+        std::env::set_var("AUTH_CONFIG_DIR", "/tmp/someone-elses-config");
+        assert_eq!(
+            config_dir_override(false),
+            Some("/tmp/someone-elses-config".into())
+        );
+        assert_eq!(config_dir_override(true), None);
+        std::env::remove_var("AUTH_CONFIG_DIR");
+    }
+}
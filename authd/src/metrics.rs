@@ -0,0 +1,114 @@
+//! Prometheus metrics: counted all the time, served in the text format on `metrics_addr` if it is
+//! set.
+
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+use std::path::Path;
+
+lazy_static! {
+    static ref RPC_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "authd_rpc_requests_total",
+        "RPCs handled, by method.",
+        &["method"]
+    )
+    .unwrap();
+    static ref RPC_SECONDS: HistogramVec = register_histogram_vec!(
+        "authd_rpc_duration_seconds",
+        "How long RPCs took to handle, by method.",
+        &["method"]
+    )
+    .unwrap();
+    static ref LOGIN_STARTS: IntCounter = register_int_counter!(
+        "authd_login_starts_total",
+        "OPAQUE logins started. Wrong passwords are only noticed by the client, so these minus successful finish_logins are roughly the failures."
+    )
+    .unwrap();
+    static ref LOGINS: IntCounterVec = register_int_counter_vec!(
+        "authd_logins_total",
+        "Logins authd saw finish, by how (finish_login, verify_totp, resume_session, finish_legacy_migration) and outcome.",
+        &["method", "outcome"]
+    )
+    .unwrap();
+    static ref CONNECTIONS: IntGauge = register_int_gauge!(
+        "authd_connections",
+        "Open connections."
+    )
+    .unwrap();
+    static ref SESSION_TOKENS: IntGauge = register_int_gauge!(
+        "authd_session_tokens",
+        "Unexpired session tokens from create_session_token."
+    )
+    .unwrap();
+    static ref TLS_HANDSHAKE_ERRORS: IntCounter = register_int_counter!(
+        "authd_tls_handshake_errors_total",
+        "Connections dropped because the TLS handshake failed."
+    )
+    .unwrap();
+    static ref FILE_RELOADS: IntCounterVec = register_int_counter_vec!(
+        "authd_file_reloads_total",
+        "Times a file was read again because it changed, by path.",
+        &["file"]
+    )
+    .unwrap();
+    static ref FILE_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "authd_file_parse_errors_total",
+        "Times a changed file could not be read or parsed, by path.",
+        &["file"]
+    )
+    .unwrap();
+    static ref USERS: IntGauge = register_int_gauge!("authd_users", "Entries in passwd.").unwrap();
+    static ref GROUPS: IntGauge = register_int_gauge!("authd_groups", "Entries in group.").unwrap();
+}
+
+/// Count one `method` RPC that took `seconds`.
+pub fn rpc(method: &str, seconds: f64) {
+    RPC_REQUESTS.with_label_values(&[method]).inc();
+    RPC_SECONDS.with_label_values(&[method]).observe(seconds);
+}
+
+/// Count the login-related ones of the events that go to the audit log.
+pub fn event(action: &str, succeeded: bool) {
+    match action {
+        "start_login" => LOGIN_STARTS.inc(),
+        "finish_login" | "verify_totp" | "resume_session" | "finish_legacy_migration" => {
+            let outcome = if succeeded { "success" } else { "failure" };
+            LOGINS.with_label_values(&[action, outcome]).inc()
+        }
+        _ => {}
+    }
+}
+
+pub fn connection_opened() {
+    CONNECTIONS.inc();
+}
+
+pub fn connection_closed() {
+    CONNECTIONS.dec();
+}
+
+pub fn tls_handshake_failed() {
+    TLS_HANDSHAKE_ERRORS.inc();
+}
+
+/// Count a reload of `pth`, and whether it worked, passing the result on.
+pub fn reloaded<T>(pth: &Path, result: anyhow::Result<T>) -> anyhow::Result<T> {
+    let file = pth.to_string_lossy();
+    match &result {
+        Ok(_) => FILE_RELOADS.with_label_values(&[&file]).inc(),
+        Err(_) => FILE_ERRORS.with_label_values(&[&file]).inc(),
+    }
+    result
+}
+
+/// Everything in the text format, after setting the gauges that are only worked out on demand.
+pub fn render(users: usize, groups: usize, session_tokens: usize) -> String {
+    USERS.set(users as i64);
+    GROUPS.set(groups as i64);
+    SESSION_TOKENS.set(session_tokens as i64);
+    TextEncoder::new()
+        .encode_to_string(&prometheus::gather())
+        .expect("encoding metrics")
+}
@@ -0,0 +1,39 @@
+//! The NSS module's config, `nss_cosiauthd.toml`. It lives here so `auth doctor` reads it exactly
+//! the way the module does.
+
+use std::path::PathBuf;
+
+#[derive(Debug, serde::Deserialize)]
+pub struct NssConfig {
+    pub host: crate::SocketName,
+    /// `cert`, `certs`, `spki_pins`, `ca_bundle` and `server_name`.
+    #[serde(flatten)]
+    pub trust: crate::tls::ServerTrust,
+    /// Seconds to remember lookups that found something. Overrides what authd advertises.
+    pub positive_ttl: Option<u64>,
+    /// Seconds to remember lookups that found nothing. Overrides what authd advertises.
+    pub negative_ttl: Option<u64>,
+    /// Name this host logs in to authd as, see `auth enroll-host`.
+    pub host_principal: Option<String>,
+    /// File holding the host's password. Only root should be able to read it, so that only root
+    /// processes get to see real shadow entries.
+    pub host_secret: Option<String>,
+}
+
+impl NssConfig {
+    /// Where the module looks for its config.
+    pub fn path() -> anyhow::Result<PathBuf> {
+        Ok(crate::find_config_dir()?.join("nss_cosiauthd.toml"))
+    }
+
+    /// Read and shell-expand the config.
+    pub fn load() -> anyhow::Result<Self> {
+        let mut cfg: NssConfig = toml::from_slice(&std::fs::read(Self::path()?)?)?;
+        cfg.trust.expand();
+        cfg.host_secret = cfg
+            .host_secret
+            .map(|s| shellexpand::full(&s).map(Into::into))
+            .transpose()?;
+        Ok(cfg)
+    }
+}
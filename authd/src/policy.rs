@@ -0,0 +1,73 @@
+//! Who is allowed to read which parts of the directory.
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Group whose members are administrators.
+pub const ADMINS_GROUP: &str = "auth-admins";
+/// Group whose members are enrolled hosts, i.e. lab machines that have their own OPAQUE login.
+pub const HOSTS_GROUP: &str = "auth-hosts";
+
+/// How much we know about who is on the other end of a session, least trusted first.
+///
+/// Hosts rank above users because they need shadow data (for `unix_chkpwd` and friends) that
+/// plain users have no business seeing.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize, JsonSchema,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum Principal {
+    Anonymous,
+    User,
+    Host,
+    Admin,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Database {
+    Passwd,
+    Group,
+    Shadow,
+    Netgroup,
+    Sudo,
+}
+
+/// The least trusted principal that may read each database.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ReadPolicy {
+    pub passwd: Principal,
+    pub group: Principal,
+    pub shadow: Principal,
+    pub netgroup: Principal,
+    /// Everyone's sudo rules. Users may always see their own.
+    pub sudo: Principal,
+}
+
+impl Default for ReadPolicy {
+    fn default() -> Self {
+        Self {
+            passwd: Principal::Anonymous,
+            group: Principal::Anonymous,
+            shadow: Principal::Host,
+            netgroup: Principal::Anonymous,
+            sudo: Principal::Host,
+        }
+    }
+}
+
+impl ReadPolicy {
+    pub fn required(&self, db: Database) -> Principal {
+        match db {
+            Database::Passwd => self.passwd,
+            Database::Group => self.group,
+            Database::Shadow => self.shadow,
+            Database::Netgroup => self.netgroup,
+            Database::Sudo => self.sudo,
+        }
+    }
+
+    pub fn allows(&self, who: Principal, db: Database) -> bool {
+        who >= self.required(db)
+    }
+}
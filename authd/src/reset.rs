@@ -0,0 +1,56 @@
+//! One-time password reset tokens. An admin issues one for an account and hands it to its owner,
+//! who redeems it by registering a new credential, so the admin never learns the password.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+/// How long tokens last unless `reset_token_hours` says otherwise.
+pub const DEFAULT_TOKEN_HOURS: u64 = 24;
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ResetTokens {
+    /// Outstanding tokens, by the hex SHA-256 of the token. The tokens themselves aren't kept.
+    #[serde(default)]
+    pub tokens: BTreeMap<String, ResetToken>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ResetToken {
+    /// The account whose credential the token replaces.
+    pub user: String,
+    /// The admin who issued it.
+    pub issued_by: String,
+    /// Seconds since 1970 after which the token is no good.
+    pub expires: u64,
+}
+
+impl ResetTokens {
+    /// Drop tokens that expired before `now`, returning whether there were any.
+    pub fn prune(&mut self, now: u64) -> bool {
+        let before = self.tokens.len();
+        self.tokens.retain(|_, t| t.expires >= now);
+        self.tokens.len() != before
+    }
+
+    /// The unexpired token matching `token`, if there is one.
+    pub fn find(&self, token: &str, now: u64) -> Option<&ResetToken> {
+        self.tokens
+            .get(&hash_token(token))
+            .filter(|t| t.expires >= now)
+    }
+}
+
+/// A fresh random token, URL-safe base64 so it survives being pasted around.
+pub fn generate_token() -> String {
+    let token: [u8; 24] = rand::random();
+    base64::encode_config(token, base64::URL_SAFE_NO_PAD)
+}
+
+/// What is kept of a token on disk. Tokens are random enough that a plain hash is fine.
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.trim().as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
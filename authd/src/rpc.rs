@@ -1,8 +1,16 @@
 //! RPC server exposing all of the functionality over JSON over TLS.
 
 use crate::{
-    files::Files,
-    types::{Group, Passwd, Shadow},
+    access::AccessRules,
+    audit::{AuditEntry, AuditLog, Outcome},
+    files::{Files, TomlFile},
+    invite::{Invite, Invites},
+    policy::{Database, Principal, ADMINS_GROUP, HOSTS_GROUP},
+    reset::{ResetToken, ResetTokens},
+    ssh::{SshKey, SshKeys},
+    sudo::{SudoRule, SudoRules},
+    totp::{TotpEnrollment, TotpSecrets},
+    types::{AccountStatus, CacheTtls, Group, Netgroup, NetgroupTriple, Passwd, Shadow},
 };
 use opaque_ke::{
     CipherSuite, CredentialFinalization, CredentialRequest, CredentialResponse,
@@ -10,10 +18,13 @@ use opaque_ke::{
     ServerLoginStartParameters, ServerRegistration, ServerSetup,
 };
 use rand::rngs::OsRng;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::{path::PathBuf, sync::Arc};
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
 use zeroize::Zeroizing;
 
+mod gateway;
+
 pub struct DefaultCipherSuite;
 impl CipherSuite for DefaultCipherSuite {
     type OprfCs = opaque_ke::Ristretto255;
@@ -26,26 +37,200 @@ impl CipherSuite for DefaultCipherSuite {
 pub enum RpcError {
     NotAuthorized,
     AuthenticationFailure,
+    /// authd hasn't been set up to do that, e.g. there is no `access_file`.
+    NotConfigured,
+    /// The request itself doesn't make sense, e.g. a malformed SSH key.
+    Invalid(String),
+    /// The password was changed more recently than shadow's minimum age allows.
+    PasswordChangeTooSoon,
+}
+
+impl std::fmt::Display for RpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RpcError::NotAuthorized => write!(f, "not authorized"),
+            RpcError::AuthenticationFailure => write!(f, "authentication failure"),
+            RpcError::NotConfigured => write!(f, "not configured on this authd"),
+            RpcError::Invalid(why) => write!(f, "invalid request: {}", why),
+            RpcError::PasswordChangeTooSoon => write!(f, "password was changed too recently"),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+/// How far `finish_login` got.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoginStatus {
+    LoggedIn,
+    /// The password was right, now `verify_totp`.
+    NeedTotp,
+    /// The password was right, but the account has to enroll in TOTP before it can log in.
+    NeedTotpEnrollment,
+}
+
+/// Who a session is logged in as, for `auth whoami`.
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+pub struct WhoAmI {
+    pub username: Option<String>,
+    pub principal: Principal,
+    pub groups: Vec<String>,
+}
+
+/// An account for `create_account` to make. Anything left out gets the same defaults as accounts
+/// made with invites.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, JsonSchema)]
+#[serde(default)]
+pub struct NewAccount {
+    pub name: String,
+    /// The next free id from `enroll_uid_min` if not given.
+    pub uid: Option<u32>,
+    pub gecos: String,
+    pub home: Option<String>,
+    pub shell: Option<String>,
+    /// Groups to add the account to, besides its own.
+    pub groups: Vec<String>,
+}
+
+/// A session token handed out by `create_session_token`. Only kept in memory, so restarting authd
+/// logs everyone out.
+#[derive(Debug, Clone)]
+struct SessionToken {
+    username: String,
+    /// Seconds since 1970.
+    expires: u64,
 }
 
+/// How long session tokens last unless `session_hours` says otherwise.
+pub const DEFAULT_SESSION_HOURS: u64 = 8;
+
+/// The longest anything authd hands out may last, be it a token, an invite or a certificate: ten
+/// years.
+pub const MAX_HOURS: u64 = 10 * 366 * 24;
+
 #[tarpc::service]
 pub trait Authd {
-    async fn get_all_groups() -> Vec<Group>;
-    async fn get_group_by_name(name: String) -> Option<Group>;
-    async fn get_group_by_gid(gid: u32) -> Option<Group>;
+    async fn get_all_groups() -> Result<Vec<Group>, RpcError>;
+    async fn get_group_by_name(name: String) -> Result<Option<Group>, RpcError>;
+    async fn get_group_by_gid(gid: u32) -> Result<Option<Group>, RpcError>;
+
+    async fn get_all_passwd() -> Result<Vec<Passwd>, RpcError>;
+    async fn get_passwd_by_name(name: String) -> Result<Option<Passwd>, RpcError>;
+    async fn get_passwd_by_uid(uid: u32) -> Result<Option<Passwd>, RpcError>;
+
+    async fn get_all_shadow() -> Result<Vec<Shadow>, RpcError>;
+    async fn get_shadow_by_name(name: String) -> Result<Option<Shadow>, RpcError>;
+
+    /// Whether `username` has an OPAQUE credential, i.e. can log in at all. Anyone who may read
+    /// shadow may ask.
+    async fn has_credential(username: String) -> Result<bool, RpcError>;
+
+    async fn get_all_netgroups() -> Result<Vec<Netgroup>, RpcError>;
+    /// Every triple in the netgroup, with nested netgroups already expanded.
+    async fn get_netgroup_by_name(name: String) -> Result<Option<Vec<NetgroupTriple>>, RpcError>;
+
+    /// How long clients are encouraged to cache the results of the lookups above.
+    async fn get_cache_ttls() -> CacheTtls;
+
+    /// Whether `username` may use their account on `host` right now, going by the aging fields in
+    /// shadow and who may log in where. Anyone who can read shadow may ask, and so may the user.
+    async fn account_status(username: String, host: String) -> Result<AccountStatus, RpcError>;
+
+    /// Whether `username` may log in on `host` at all, going by the access rules. Anyone who may
+    /// read groups may ask.
+    async fn check_access(username: String, host: String) -> Result<bool, RpcError>;
+    /// The access rules and host groups, for admins to edit.
+    async fn get_access_rules() -> Result<AccessRules, RpcError>;
+    /// Replace the access rules and host groups.
+    async fn set_access_rules(rules: AccessRules) -> Result<(), RpcError>;
 
-    async fn get_all_passwd() -> Vec<Passwd>;
-    async fn get_passwd_by_name(name: String) -> Option<Passwd>;
-    async fn get_passwd_by_uid(uid: u32) -> Option<Passwd>;
+    /// The sudo rules that apply on `host`, to `user` or to everyone if `None`.
+    async fn get_sudo_rules(user: Option<String>, host: String) -> Result<Vec<SudoRule>, RpcError>;
 
-    async fn get_all_shadow() -> Vec<Shadow>;
-    async fn get_shadow_by_name(name: String) -> Option<Shadow>;
+    /// Store an SSH public key for `user`, returning its fingerprint. Users may manage their own
+    /// keys, admins anyone's.
+    async fn add_ssh_key(user: String, key: SshKey) -> Result<String, RpcError>;
+    async fn list_ssh_keys(user: String) -> Result<Vec<SshKey>, RpcError>;
+    /// Remove the key with this fingerprint, returning whether there was one.
+    async fn remove_ssh_key(user: String, fingerprint: String) -> Result<bool, RpcError>;
+    /// The authorized_keys lines for `user` on `host`: nothing if they may not log in there,
+    /// otherwise the keys that haven't expired and are allowed on `host`. Anyone who may read
+    /// passwd may ask, since sshd asks as an unprivileged user.
+    async fn get_authorized_keys(user: String, host: String) -> Result<Vec<String>, RpcError>;
+    /// The certificate principals that may log in as `user` on `host`, for sshd's
+    /// AuthorizedPrincipalsCommand: just `user` if they may log in there, otherwise nothing.
+    /// Anyone who may read passwd may ask.
+    async fn get_authorized_principals(user: String, host: String)
+        -> Result<Vec<String>, RpcError>;
+
+    /// The SSH CA's public key, for `TrustedUserCAKeys` and `@cert-authority`.
+    async fn get_ssh_ca_public_key() -> Result<String, RpcError>;
+    /// Sign the logged-in user's public key into a short-lived certificate, with their username as
+    /// the only principal. Only a fresh login counts, not a session resumed from a token. The
+    /// certificate says nothing about hosts; sshd should ask `get_authorized_principals` whether
+    /// it's any use there.
+    async fn sign_ssh_user_key(public_key: String) -> Result<String, RpcError>;
+    /// Sign a host key for `host`. Hosts may get their own keys signed, admins any host's.
+    async fn sign_ssh_host_key(host: String, public_key: String) -> Result<String, RpcError>;
+
+    /// Whether `username` has no OPAQUE credential yet, but a crypt(3) hash in shadow that
+    /// `finish_legacy_migration` can check. Always false unless `legacy_login` is on. Anyone who
+    /// may read shadow may ask.
+    async fn legacy_login_available(username: String) -> Result<bool, RpcError>;
+    /// Start registering an OPAQUE credential for `username`, who only has a crypt(3) hash.
+    /// Nothing is kept unless `finish_legacy_migration` finds the password matches the hash.
+    /// This goes the same way for any username, and only finishing says whether it worked, so it
+    /// can't be used to find out who has a hash.
+    async fn start_legacy_migration(
+        username: String,
+        reg: RegistrationRequest<DefaultCipherSuite>,
+    ) -> Result<RegistrationResponse<DefaultCipherSuite>, RpcError>;
+    /// Finish the registration, without saving it yet, and start logging in with it, so there
+    /// is a session key to send the password under.
+    async fn continue_legacy_migration(
+        reg: RegistrationUpload<DefaultCipherSuite>,
+        req: CredentialRequest<DefaultCipherSuite>,
+    ) -> Result<CredentialResponse<DefaultCipherSuite>, RpcError>;
+    /// Finish that login and check the password, sealed under its session key with
+    /// `legacy::seal_password`, against the hash. If it matches, store the credential and
+    /// replace the hash in shadow with `!`, so the account can only use OPAQUE from now on. This
+    /// is the one time the server sees a password. Log in normally afterwards.
+    async fn finish_legacy_migration(
+        req: CredentialFinalization<DefaultCipherSuite>,
+        sealed_password: Vec<u8>,
+    ) -> Result<(), RpcError>;
 
     async fn start_login(
         username: String,
         req: CredentialRequest<DefaultCipherSuite>,
     ) -> Result<CredentialResponse<DefaultCipherSuite>, RpcError>;
-    async fn finish_login(req: CredentialFinalization<DefaultCipherSuite>);
+    async fn finish_login(
+        req: CredentialFinalization<DefaultCipherSuite>,
+    ) -> Result<LoginStatus, RpcError>;
+
+    /// Mint a token that logs later connections in as whoever is logged in on this one, returning
+    /// it and when it expires (seconds since 1970). A session resumed from a token only gets
+    /// tokens that expire no later than that one.
+    async fn create_session_token() -> Result<(String, u64), RpcError>;
+    /// Log in with a token from `create_session_token`, returning who it was for.
+    async fn resume_session(token: String) -> Result<String, RpcError>;
+    /// Make a session token useless, returning whether it was any use before. Whoever holds the
+    /// token may revoke it.
+    async fn revoke_session_token(token: String) -> Result<bool, RpcError>;
+    /// Who this session is logged in as, how much that is trusted and which groups they are in.
+    async fn whoami() -> WhoAmI;
+
+    /// Finish a login that needed a TOTP code. A wrong code ends the login, so every guess
+    /// takes the password again.
+    async fn verify_totp(code: String) -> Result<(), RpcError>;
+    /// Start enrolling in TOTP, returning the otpauth URI for an authenticator app. Either logged
+    /// in with a password, or partway through a login that needs enrollment.
+    async fn start_totp_enrollment() -> Result<String, RpcError>;
+    /// Finish enrolling with a code from the app. For a login that needed enrollment, this also
+    /// finishes the login.
+    async fn confirm_totp_enrollment(code: String) -> Result<(), RpcError>;
+    /// Forget a user's TOTP secret, e.g. after they lost their phone. Admins only.
+    async fn remove_totp(username: String) -> Result<bool, RpcError>;
 
     async fn register_new_user(
         username: String,
@@ -55,6 +240,50 @@ pub trait Authd {
     async fn finish_registration(
         reg: RegistrationUpload<DefaultCipherSuite>,
     ) -> Result<(), RpcError>;
+
+    /// Replace the logged-in user's own credential, e.g. from `passwd`. Finishing it updates
+    /// `last_change` in shadow. Takes a password login, not a resumed session.
+    async fn start_self_registration(
+        reg: RegistrationRequest<DefaultCipherSuite>,
+    ) -> Result<RegistrationResponse<DefaultCipherSuite>, RpcError>;
+    async fn finish_self_registration(
+        reg: RegistrationUpload<DefaultCipherSuite>,
+    ) -> Result<(), RpcError>;
+
+    /// Mint a single-use token that lets whoever holds it set `username`'s password. Admins only.
+    async fn issue_reset_token(username: String) -> Result<String, RpcError>;
+    /// Replace the credential of the account `token` was issued for, without logging in. Returns
+    /// the account's username along with the registration response.
+    async fn start_reset_registration(
+        token: String,
+        reg: RegistrationRequest<DefaultCipherSuite>,
+    ) -> Result<(String, RegistrationResponse<DefaultCipherSuite>), RpcError>;
+    /// Finish a reset, using up the token.
+    async fn finish_reset_registration(
+        reg: RegistrationUpload<DefaultCipherSuite>,
+    ) -> Result<(), RpcError>;
+
+    /// Make an account's passwd, shadow and group entries, without a credential: follow up with
+    /// `register_new_user` or `issue_reset_token`. Admins only.
+    async fn create_account(account: NewAccount) -> Result<Passwd, RpcError>;
+
+    /// Make an invite code good for `uses` accounts over the next `hours`, which are put in
+    /// `groups`. Admins only.
+    async fn create_invite(uses: u32, hours: u64, groups: Vec<String>) -> Result<String, RpcError>;
+    /// Start making an account called `username` with an invite code, without logging in.
+    async fn start_enrollment(
+        code: String,
+        username: String,
+        reg: RegistrationRequest<DefaultCipherSuite>,
+    ) -> Result<RegistrationResponse<DefaultCipherSuite>, RpcError>;
+    /// Finish making the account: allocate its UID, write its passwd, shadow and group entries,
+    /// and use up one use of the invite.
+    async fn finish_enrollment(
+        reg: RegistrationUpload<DefaultCipherSuite>,
+    ) -> Result<Passwd, RpcError>;
+
+    /// Audit log entries after `after` (0 for all of them), oldest first. Admins only.
+    async fn get_audit_log(after: u64) -> Result<Vec<AuditEntry>, RpcError>;
 }
 
 /// All of the shared state amongst all of the various open sessions.
@@ -64,6 +293,16 @@ struct SharedState {
     setup: ServerSetup<DefaultCipherSuite>,
     config: crate::AuthdConfig,
     files: Files,
+    access: Option<TomlFile<AccessRules>>,
+    sudo: Option<TomlFile<SudoRules>>,
+    totp: Option<TomlFile<TotpSecrets>>,
+    resets: Option<TomlFile<ResetTokens>>,
+    invites: Option<TomlFile<Invites>>,
+    /// Outstanding session tokens, by their hash.
+    sessions: HashMap<String, SessionToken>,
+    audit: Option<AuditLog>,
+    ssh_keys: Option<TomlFile<SshKeys>>,
+    ssh_ca: Option<ssh_key::PrivateKey>,
 }
 impl std::fmt::Debug for SharedState {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -75,6 +314,237 @@ impl SharedState {
         let path = PathBuf::from(&self.config.opaque_cookies).join(username);
         Ok(std::fs::read(path)?)
     }
+
+    /// The crypt(3) hash `username` can still log in with once, if legacy logins are on and they
+    /// haven't moved to OPAQUE yet.
+    fn legacy_hash(&mut self, username: &str) -> Option<String> {
+        if !self.config.legacy_login || self.find_password_file(username).is_ok() {
+            return None;
+        }
+        self.files.refresh().expect("refreshing fio");
+        self.files
+            .shadow
+            .data
+            .iter()
+            .find(|x| x.name == username)
+            .map(|x| x.passwd.clone())
+            .filter(|hash| crate::legacy::is_crypt_hash(hash))
+    }
+
+    /// The names of the groups `username` is in, including their own group.
+    fn groups_of(&self, username: &str) -> Vec<String> {
+        let uid = self
+            .files
+            .passwd
+            .data
+            .iter()
+            .find(|x| x.name == username)
+            .map(|x| x.id);
+        self.files
+            .group
+            .data
+            .iter()
+            .filter(|x| Some(x.gid) == uid || x.members.iter().any(|m| m == username))
+            .map(|x| x.name.clone())
+            .collect()
+    }
+
+    /// Whether `username` may log in on `host`, refreshing the access rules first.
+    fn check_access(&mut self, username: &str, host: &str) -> bool {
+        if !self.files.passwd.data.iter().any(|x| x.name == username) {
+            return false;
+        }
+        let groups = self.groups_of(username);
+        // hosts log in to authd, not to each other
+        if groups.iter().any(|g| g == HOSTS_GROUP) {
+            return false;
+        }
+        match &mut self.access {
+            Some(access) => {
+                access.refresh().expect("refreshing access rules");
+                access.data.allows(&groups, host)
+            }
+            None => true,
+        }
+    }
+
+    fn sudo_rules(&mut self, user: Option<&str>, host: &str) -> Vec<SudoRule> {
+        let groups = user.map(|u| self.groups_of(u));
+        let access = self.access_rules();
+        let sudo = match &mut self.sudo {
+            Some(sudo) => sudo,
+            None => return vec![],
+        };
+        sudo.refresh().expect("refreshing sudo rules");
+        sudo.data
+            .rules
+            .iter()
+            .filter(|rule| rule.applies_on(host, &access))
+            .filter(|rule| match (user, &groups) {
+                (Some(user), Some(groups)) => rule.applies_to(user, groups),
+                _ => true,
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// The access rules, or none at all if they aren't configured.
+    fn access_rules(&mut self) -> AccessRules {
+        match &mut self.access {
+            Some(access) => {
+                access.refresh().expect("refreshing access rules");
+                access.data.clone()
+            }
+            None => AccessRules::default(),
+        }
+    }
+
+    fn ssh_keys(&mut self) -> Result<&mut TomlFile<SshKeys>, RpcError> {
+        let ssh_keys = self.ssh_keys.as_mut().ok_or(RpcError::NotConfigured)?;
+        ssh_keys.refresh().expect("refreshing ssh keys");
+        Ok(ssh_keys)
+    }
+
+    fn resets(&mut self) -> Result<&mut TomlFile<ResetTokens>, RpcError> {
+        let resets = self.resets.as_mut().ok_or(RpcError::NotConfigured)?;
+        resets.refresh().expect("refreshing reset tokens");
+        Ok(resets)
+    }
+
+    fn invites(&mut self) -> Result<&mut TomlFile<Invites>, RpcError> {
+        let invites = self.invites.as_mut().ok_or(RpcError::NotConfigured)?;
+        invites.refresh().expect("refreshing invites");
+        Ok(invites)
+    }
+
+    /// Refuse names that aren't valid or are already someone's (or some group's).
+    fn check_new_username(&self, username: &str) -> Result<(), RpcError> {
+        crate::invite::check_username(username).map_err(RpcError::Invalid)?;
+        if self.files.passwd.data.iter().any(|x| x.name == username)
+            || self.files.group.data.iter().any(|x| x.name == username)
+        {
+            return Err(RpcError::Invalid(format!("{} is taken", username)));
+        }
+        Ok(())
+    }
+
+    /// The next free id at or above `enroll_uid_min`, for both the UID and the user's own group.
+    fn next_free_id(&self) -> u32 {
+        let min = self
+            .config
+            .enroll_uid_min
+            .unwrap_or(crate::invite::DEFAULT_UID_MIN);
+        let passwd = self.files.passwd.data.iter().map(|x| x.id);
+        let group = self.files.group.data.iter().map(|x| x.gid);
+        passwd
+            .chain(group)
+            .filter(|&id| id >= min)
+            .max()
+            .map(|id| id + 1)
+            .unwrap_or(min)
+    }
+
+    /// Write out `account`, allocating its id if it doesn't have one.
+    fn make_account(&mut self, account: NewAccount) -> Result<Passwd, RpcError> {
+        self.files.refresh().expect("refreshing fio");
+        self.check_new_username(&account.name)?;
+        for group in &account.groups {
+            if !self.files.group.data.iter().any(|x| &x.name == group) {
+                return Err(RpcError::Invalid(format!("no such group {}", group)));
+            }
+        }
+        let id = account.uid.unwrap_or_else(|| self.next_free_id());
+        let home = account.home.unwrap_or_else(|| {
+            let base = self.config.enroll_home_base.as_deref().unwrap_or("/home");
+            format!("{}/{}", base.trim_end_matches('/'), account.name)
+        });
+        let passwd = Passwd {
+            name: account.name.clone(),
+            id,
+            gecos: account.gecos,
+            dir: home,
+            shell: account
+                .shell
+                .or_else(|| self.config.enroll_shell.clone())
+                .unwrap_or_else(|| "/bin/bash".into()),
+        };
+        let shadow = Shadow {
+            name: account.name,
+            // the password lives in OPAQUE, nothing local can check it
+            passwd: "*".into(),
+            last_change: crate::types::today(),
+            change_min_days: 0,
+            change_max_days: 99999,
+            change_warn_days: 7,
+            change_inactive_days: None,
+            expire_date: None,
+        };
+        self.files
+            .add_user(passwd.clone(), shadow, &account.groups)
+            .map_err(|e| RpcError::Invalid(e.to_string()))?;
+        Ok(passwd)
+    }
+
+    fn totp_enrollment(&mut self, username: &str) -> Option<TotpEnrollment> {
+        let totp = self.totp.as_mut()?;
+        totp.refresh().expect("refreshing totp secrets");
+        totp.data.users.get(username).cloned()
+    }
+
+    /// Check a TOTP code for `username`, remembering it so it can't be used again.
+    fn check_totp(&mut self, username: &str, code: &str) -> bool {
+        let enrollment = match self.totp_enrollment(username) {
+            Some(enrollment) => enrollment,
+            None => return false,
+        };
+        let step =
+            match crate::totp::check(&enrollment.secret, code, enrollment.last_step, unix_time()) {
+                Some(step) => step,
+                None => return false,
+            };
+        // totp_enrollment just found it, so this is there too
+        let totp = self.totp.as_mut().unwrap();
+        let mut data = totp.data.clone();
+        if let Some(enrollment) = data.users.get_mut(username) {
+            enrollment.last_step = step;
+        }
+        totp.save(data).expect("writing totp secrets");
+        true
+    }
+
+    fn account_status(&mut self, username: &str, host: &str) -> AccountStatus {
+        if !self.files.passwd.data.iter().any(|x| x.name == username) {
+            return AccountStatus::UnknownUser;
+        }
+        if !self.check_access(username, host) {
+            tracing::debug!("{} may not log in on {}", username, host);
+            return AccountStatus::HostDenied;
+        }
+        match self.files.shadow.data.iter().find(|x| x.name == username) {
+            Some(shadow) => shadow.aging_status(crate::types::today()),
+            None => AccountStatus::Ok,
+        }
+    }
+}
+
+fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// `hours` in seconds, refusing anything longer than `MAX_HOURS`.
+fn hours_in_secs(hours: u64) -> Result<u64, RpcError> {
+    hours
+        .checked_mul(60 * 60)
+        .filter(|_| hours <= MAX_HOURS)
+        .ok_or_else(|| {
+            RpcError::Invalid(format!(
+                "{} hours is longer than the {} authd allows",
+                hours, MAX_HOURS
+            ))
+        })
 }
 
 #[derive(Debug)]
@@ -84,14 +554,153 @@ struct AuthdSession {
     /// Stores the interim state of the 3-step login protocol.
     login_progress: Option<ServerLogin<DefaultCipherSuite>>,
     /// Who are we talking to?
-    _peer_addr: std::net::SocketAddr,
+    peer_addr: std::net::SocketAddr,
     /// They have claimed to have this username
     purported_username: Option<String>,
     /// If this is Some, purported_username is authenticated.
     session_key: Option<Zeroizing<Vec<u8>>>,
+    /// When the session token this session was resumed with expires. None after an OPAQUE
+    /// login, which is what changing credentials takes.
+    resumed_until: Option<u64>,
+    /// The session key of a login that still needs a TOTP code (or enrollment), which becomes
+    /// session_key once that is done.
+    pending_session_key: Option<Zeroizing<Vec<u8>>>,
+    /// A TOTP secret handed out by start_totp_enrollment, not yet confirmed.
+    totp_enrolling: Option<String>,
+    /// The account an admin is partway through registering a credential for.
+    registering_username: Option<String>,
+    /// Hash of the reset token being redeemed for registering_username.
+    redeeming_token: Option<String>,
+    /// Hash of the invite code registering_username is being made with.
+    enrolling_invite: Option<String>,
+    /// A legacy migration of registering_username, partway through.
+    migrating: Option<LegacyMigration>,
+}
+
+/// How far a legacy migration has got: a credential is registered and logged in with before the
+/// password is checked, so it can be sent under that login's session key.
+#[derive(Debug)]
+struct LegacyMigration {
+    /// The shadow hash the password has to match. None if there isn't one, and the migration
+    /// goes through the motions only to fail at the end, like a wrong password would.
+    hash: Option<String>,
+    /// The credential registered by `continue_legacy_migration`, saved once the password checks
+    /// out.
+    credential: Option<ServerRegistration<DefaultCipherSuite>>,
+    /// The login with that credential, which `finish_legacy_migration` finishes.
+    login: Option<ServerLogin<DefaultCipherSuite>>,
 }
 
 impl AuthdSession {
+    fn new(state: Arc<Mutex<SharedState>>, peer_addr: std::net::SocketAddr) -> Self {
+        AuthdSession {
+            state,
+            peer_addr,
+            purported_username: None,
+            session_key: None,
+            resumed_until: None,
+            pending_session_key: None,
+            totp_enrolling: None,
+            login_progress: None,
+            registering_username: None,
+            redeeming_token: None,
+            enrolling_invite: None,
+            migrating: None,
+        }
+    }
+
+    /// Log in with a session token, returning who it was for, or `None` if it is unknown or
+    /// expired.
+    async fn resume(&mut self, token: String) -> Option<String> {
+        let session = self
+            .state
+            .lock()
+            .await
+            .sessions
+            .get(&crate::reset::hash_token(&token))
+            .filter(|s| s.expires >= unix_time())
+            .cloned()?;
+        self.login_progress = None;
+        self.pending_session_key = None;
+        self.totp_enrolling = None;
+        self.purported_username = Some(session.username.clone());
+        // there was no OPAQUE exchange on this connection, the token stands in for its key
+        self.session_key = Some(Zeroizing::new(token.into_bytes()));
+        self.resumed_until = Some(session.expires);
+        Some(session.username)
+    }
+
+    /// Work out how much to trust this session, going by who logged in and their groups.
+    async fn principal(&self) -> Principal {
+        let uname = match (&self.purported_username, &self.session_key) {
+            (Some(uname), Some(_)) => uname,
+            _ => return Principal::Anonymous,
+        };
+        let state = self.state.lock().await;
+        let in_group = |name: &str| {
+            state
+                .files
+                .group
+                .data
+                .iter()
+                .any(|x| x.name == name && x.members.contains(uname))
+        };
+        if in_group(ADMINS_GROUP) {
+            Principal::Admin
+        } else if in_group(HOSTS_GROUP) {
+            Principal::Host
+        } else {
+            Principal::User
+        }
+    }
+
+    /// Has this session logged in as `username`?
+    fn authenticated_as(&self, username: &str) -> bool {
+        self.session_key.is_some() && self.purported_username.as_deref() == Some(username)
+    }
+
+    /// Refuse unless this session is logged in as `username`, or as an admin.
+    async fn check_self_or_admin(&self, username: &str) -> Result<(), RpcError> {
+        if self.authenticated_as(username) || self.auth_admin().await {
+            Ok(())
+        } else {
+            Err(RpcError::NotAuthorized)
+        }
+    }
+
+    /// Refuse if the read policy doesn't let this session see `db`.
+    async fn check_read(&self, db: Database) -> Result<(), RpcError> {
+        let who = self.principal().await;
+        if self.state.lock().await.config.read_policy.allows(who, db) {
+            Ok(())
+        } else {
+            tracing::debug!("{:?} may not read {:?}", who, db);
+            Err(RpcError::NotAuthorized)
+        }
+    }
+
+    /// Append `action` to the audit log, if there is one, as whoever is now logged in on this
+    /// session. Logins are counted for the metrics here too.
+    async fn audit<T>(&self, action: &str, target: Option<String>, result: &Result<T, RpcError>) {
+        crate::metrics::event(action, result.is_ok());
+        let actor = match (&self.purported_username, &self.session_key) {
+            (Some(uname), Some(_)) => Some(uname.clone()),
+            _ => None,
+        };
+        if let Some(audit) = &mut self.state.lock().await.audit {
+            audit
+                .record(
+                    actor,
+                    self.peer_addr.to_string(),
+                    action,
+                    target,
+                    Outcome::of(result),
+                    unix_time(),
+                )
+                .expect("writing audit log");
+        }
+    }
+
     async fn auth_admin(&self) -> bool {
         if let Some(uname) = &self.purported_username {
             if self.session_key.is_some() {
@@ -103,10 +712,9 @@ impl AuthdSession {
                     .group
                     .data
                     .iter()
-                    .find(|x| x.name == "auth-admins")
+                    .find(|x| x.name == ADMINS_GROUP)
                 {
                     if admin.members.contains(uname) {
-                        tracing::info!("{} just did admin things", uname);
                         return true;
                     }
                 }
@@ -118,185 +726,1376 @@ impl AuthdSession {
 
 #[tarpc::server]
 impl Authd for Arc<Mutex<AuthdSession>> {
-    async fn get_all_groups(self, _ctx: tarpc::context::Context) -> Vec<Group> {
+    async fn get_all_groups(self, _ctx: tarpc::context::Context) -> Result<Vec<Group>, RpcError> {
         let slf = self.lock().await;
+        slf.check_read(Database::Group).await?;
         let mut slf = slf.state.lock().await;
         slf.files.refresh().expect("refreshing fio");
-        slf.files.group.data.clone()
+        Ok(slf.files.group.data.clone())
     }
 
-    async fn get_group_by_name(self, _ctx: tarpc::context::Context, name: String) -> Option<Group> {
+    async fn get_group_by_name(
+        self,
+        _ctx: tarpc::context::Context,
+        name: String,
+    ) -> Result<Option<Group>, RpcError> {
         let slf = self.lock().await;
+        slf.check_read(Database::Group).await?;
         let mut slf = slf.state.lock().await;
         slf.files.refresh().expect("refreshing fio");
-        slf.files
+        Ok(slf
+            .files
             .group
             .data
             .iter()
             .find(|x| x.name == name)
-            .cloned()
+            .cloned())
     }
-    async fn get_group_by_gid(self, _ctx: tarpc::context::Context, gid: u32) -> Option<Group> {
+    async fn get_group_by_gid(
+        self,
+        _ctx: tarpc::context::Context,
+        gid: u32,
+    ) -> Result<Option<Group>, RpcError> {
         let slf = self.lock().await;
+        slf.check_read(Database::Group).await?;
         let mut slf = slf.state.lock().await;
         slf.files.refresh().expect("refreshing fio");
-        slf.files.group.data.iter().find(|x| x.gid == gid).cloned()
+        Ok(slf.files.group.data.iter().find(|x| x.gid == gid).cloned())
     }
 
-    async fn get_all_passwd(self, _ctx: tarpc::context::Context) -> Vec<Passwd> {
+    async fn get_all_passwd(self, _ctx: tarpc::context::Context) -> Result<Vec<Passwd>, RpcError> {
         let slf = self.lock().await;
+        slf.check_read(Database::Passwd).await?;
         let mut slf = slf.state.lock().await;
         slf.files.refresh().expect("refreshing fio");
-        slf.files.passwd.data.clone()
+        Ok(slf.files.passwd.data.clone())
     }
 
     async fn get_passwd_by_name(
         self,
         _ctx: tarpc::context::Context,
         name: String,
-    ) -> Option<Passwd> {
+    ) -> Result<Option<Passwd>, RpcError> {
         let slf = self.lock().await;
+        slf.check_read(Database::Passwd).await?;
         let mut slf = slf.state.lock().await;
         slf.files.refresh().expect("refreshing fio");
-        slf.files
+        Ok(slf
+            .files
             .passwd
             .data
             .iter()
             .find(|x| x.name == name)
-            .cloned()
+            .cloned())
     }
 
-    async fn get_passwd_by_uid(self, _ctx: tarpc::context::Context, uid: u32) -> Option<Passwd> {
+    async fn get_passwd_by_uid(
+        self,
+        _ctx: tarpc::context::Context,
+        uid: u32,
+    ) -> Result<Option<Passwd>, RpcError> {
         let slf = self.lock().await;
+        slf.check_read(Database::Passwd).await?;
         let mut slf = slf.state.lock().await;
         slf.files.refresh().expect("refreshing fio");
-        slf.files.passwd.data.iter().find(|x| x.id == uid).cloned()
+        Ok(slf.files.passwd.data.iter().find(|x| x.id == uid).cloned())
     }
 
-    async fn get_all_shadow(self, _ctx: tarpc::context::Context) -> Vec<Shadow> {
+    async fn get_all_shadow(self, _ctx: tarpc::context::Context) -> Result<Vec<Shadow>, RpcError> {
         let slf = self.lock().await;
+        slf.check_read(Database::Shadow).await?;
         let mut slf = slf.state.lock().await;
         slf.files.refresh().expect("refreshing fio");
-        slf.files.shadow.data.clone()
+        Ok(slf.files.shadow.data.clone())
     }
 
     async fn get_shadow_by_name(
         self,
         _ctx: tarpc::context::Context,
         name: String,
-    ) -> Option<Shadow> {
+    ) -> Result<Option<Shadow>, RpcError> {
         let slf = self.lock().await;
+        slf.check_read(Database::Shadow).await?;
         let mut slf = slf.state.lock().await;
         slf.files.refresh().expect("refreshing fio");
-        slf.files
+        Ok(slf
+            .files
             .shadow
             .data
             .iter()
             .find(|x| x.name == name)
-            .cloned()
+            .cloned())
     }
 
-    async fn register_new_user(
+    async fn has_credential(
         self,
         _ctx: tarpc::context::Context,
         username: String,
-        _selected_uid: Option<u32>,
-        reg: RegistrationRequest<DefaultCipherSuite>,
-    ) -> Result<RegistrationResponse<DefaultCipherSuite>, RpcError> {
-        let mut slf = self.lock().await;
-        if !slf.auth_admin().await {
-            return Err(RpcError::NotAuthorized);
+    ) -> Result<bool, RpcError> {
+        let slf = self.lock().await;
+        slf.check_read(Database::Shadow).await?;
+        let mut slf = slf.state.lock().await;
+        slf.files.refresh().expect("refreshing fio");
+        // only look on disk for real users, the name becomes a path
+        if !slf.files.passwd.data.iter().any(|x| x.name == username) {
+            return Ok(false);
         }
-        /* TODO: do something with selected_id? run the autoallocate? */
-        let reg = ServerRegistration::<DefaultCipherSuite>::start(
-            &slf.state.lock().await.setup,
-            reg,
-            username.as_bytes(),
-        )
-        .unwrap();
-        slf.purported_username = Some(username);
-        Ok(reg.message)
+        Ok(slf.find_password_file(&username).is_ok())
     }
 
-    async fn finish_registration(
+    async fn get_all_netgroups(
         self,
         _ctx: tarpc::context::Context,
-        reg: RegistrationUpload<DefaultCipherSuite>,
-    ) -> Result<(), RpcError> {
+    ) -> Result<Vec<Netgroup>, RpcError> {
         let slf = self.lock().await;
-        if !slf.auth_admin().await {
-            return Err(RpcError::NotAuthorized);
-        }
+        slf.check_read(Database::Netgroup).await?;
+        let mut slf = slf.state.lock().await;
+        slf.files.refresh().expect("refreshing fio");
+        Ok(slf
+            .files
+            .netgroup
+            .as_ref()
+            .map(|ng| ng.data.clone())
+            .unwrap_or_default())
+    }
 
-        let password_file = ServerRegistration::<DefaultCipherSuite>::finish(reg);
-        let path = PathBuf::from(&slf.state.lock().await.config.opaque_cookies)
-            .join(slf.purported_username.as_ref().unwrap());
-        std::fs::write(path, password_file.serialize()).expect("writing out opaque cookie");
-        Ok(())
+    async fn get_netgroup_by_name(
+        self,
+        _ctx: tarpc::context::Context,
+        name: String,
+    ) -> Result<Option<Vec<NetgroupTriple>>, RpcError> {
+        let slf = self.lock().await;
+        slf.check_read(Database::Netgroup).await?;
+        let mut slf = slf.state.lock().await;
+        slf.files.refresh().expect("refreshing fio");
+        Ok(slf.files.expand_netgroup(&name))
     }
 
-    async fn start_login(
+    async fn get_cache_ttls(self, _ctx: tarpc::context::Context) -> CacheTtls {
+        let slf = self.lock().await;
+        let slf = slf.state.lock().await;
+        CacheTtls {
+            positive: slf.config.cache_positive_ttl,
+            negative: slf.config.cache_negative_ttl,
+        }
+    }
+
+    async fn account_status(
         self,
         _ctx: tarpc::context::Context,
         username: String,
-        req: CredentialRequest<DefaultCipherSuite>,
-    ) -> Result<CredentialResponse<DefaultCipherSuite>, RpcError> {
-        let mut slf = self.lock().await;
+        host: String,
+    ) -> Result<AccountStatus, RpcError> {
+        let slf = self.lock().await;
+        if !slf.authenticated_as(&username) {
+            slf.check_read(Database::Shadow).await?;
+        }
+        let mut slf = slf.state.lock().await;
+        slf.files.refresh().expect("refreshing fio");
+        Ok(slf.account_status(&username, &host))
+    }
 
-        let password_file = slf
-            .state
-            .lock()
-            .await
-            .find_password_file(&username)
-            .ok()
-            .and_then(|d| {
-                ServerRegistration::<DefaultCipherSuite>::deserialize(&d)
-                    .map_err(|e| eprintln!("error deserializing password file: {:?}", e))
-                    .ok()
-            });
+    async fn check_access(
+        self,
+        _ctx: tarpc::context::Context,
+        username: String,
+        host: String,
+    ) -> Result<bool, RpcError> {
+        let slf = self.lock().await;
+        slf.check_read(Database::Group).await?;
+        let mut slf = slf.state.lock().await;
+        slf.files.refresh().expect("refreshing fio");
+        Ok(slf.check_access(&username, &host))
+    }
 
-        let mut server_rng = OsRng;
-        let server_login_start_result = ServerLogin::start(
-            &mut server_rng,
-            &slf.state.lock().await.setup,
-            password_file,
-            req,
-            username.as_bytes(),
-            ServerLoginStartParameters::default(),
-        )
-        .unwrap();
+    async fn get_access_rules(
+        self,
+        _ctx: tarpc::context::Context,
+    ) -> Result<AccessRules, RpcError> {
+        let slf = self.lock().await;
+        if !slf.auth_admin().await {
+            return Err(RpcError::NotAuthorized);
+        }
+        let mut slf = slf.state.lock().await;
+        let access = slf.access.as_mut().ok_or(RpcError::NotConfigured)?;
+        access.refresh().expect("refreshing access rules");
+        Ok(access.data.clone())
+    }
 
-        slf.login_progress = Some(server_login_start_result.state);
-        slf.purported_username = Some(username);
-        Ok(server_login_start_result.message)
+    async fn set_access_rules(
+        self,
+        _ctx: tarpc::context::Context,
+        rules: AccessRules,
+    ) -> Result<(), RpcError> {
+        let slf = self.lock().await;
+        let result: Result<(), RpcError> = async {
+            if !slf.auth_admin().await {
+                return Err(RpcError::NotAuthorized);
+            }
+            let mut slf = slf.state.lock().await;
+            let access = slf.access.as_mut().ok_or(RpcError::NotConfigured)?;
+            access.save(rules).expect("writing access rules");
+            Ok(())
+        }
+        .await;
+        slf.audit("set_access_rules", None, &result).await;
+        result
     }
 
-    async fn finish_login(
+    async fn get_sudo_rules(
         self,
         _ctx: tarpc::context::Context,
-        req: CredentialFinalization<DefaultCipherSuite>,
-    ) {
-        let mut slf = self.lock().await;
+        user: Option<String>,
+        host: String,
+    ) -> Result<Vec<SudoRule>, RpcError> {
+        let slf = self.lock().await;
+        match &user {
+            Some(user) if slf.authenticated_as(user) => {}
+            _ => slf.check_read(Database::Sudo).await?,
+        }
+        let mut slf = slf.state.lock().await;
+        slf.files.refresh().expect("refreshing fio");
+        Ok(slf.sudo_rules(user.as_deref(), &host))
+    }
 
-        let server_login = slf.login_progress.take().expect("no login to finish");
-        let finish_result = server_login.finish(req).expect("incorrect password");
-        slf.session_key = Some(Zeroizing::new(finish_result.session_key.to_vec()));
+    async fn add_ssh_key(
+        self,
+        _ctx: tarpc::context::Context,
+        user: String,
+        key: SshKey,
+    ) -> Result<String, RpcError> {
+        let slf = self.lock().await;
+        let target = Some(user.clone());
+        let result: Result<String, RpcError> = async {
+            slf.check_self_or_admin(&user).await?;
+            let key = SshKey::parse(&key.key, Some(key.host), key.expires)
+                .map_err(|e| RpcError::Invalid(e.to_string()))?;
+            let fingerprint = key.fingerprint();
+            let mut slf = slf.state.lock().await;
+            let ssh_keys = slf.ssh_keys()?;
+            let mut data = ssh_keys.data.clone();
+            let keys = data.users.entry(user).or_default();
+            // adding a key again updates where and until when it may be used
+            keys.retain(|k| k.fingerprint() != fingerprint);
+            keys.push(key);
+            ssh_keys.save(data).expect("writing ssh keys");
+            Ok(fingerprint)
+        }
+        .await;
+        slf.audit("add_ssh_key", target, &result).await;
+        result
     }
-}
 
-use argh::FromArgs;
-use tarpc::{
-    server::{BaseChannel, Channel},
-    tokio_serde::formats::Json,
-};
-use tokio::{net::TcpListener, sync::Mutex, task::JoinSet};
-use tokio_rustls::rustls::{self, Certificate, PrivateKey};
-use tokio_rustls::TlsAcceptor;
-use tracing_subscriber::{
-    fmt::format::FmtSpan, prelude::__tracing_subscriber_SubscriberExt, util::SubscriberInitExt,
-};
+    async fn list_ssh_keys(
+        self,
+        _ctx: tarpc::context::Context,
+        user: String,
+    ) -> Result<Vec<SshKey>, RpcError> {
+        let slf = self.lock().await;
+        slf.check_self_or_admin(&user).await?;
+        let mut slf = slf.state.lock().await;
+        let ssh_keys = slf.ssh_keys()?;
+        Ok(ssh_keys.data.users.get(&user).cloned().unwrap_or_default())
+    }
 
-#[derive(FromArgs, PartialEq, Debug)]
+    async fn remove_ssh_key(
+        self,
+        _ctx: tarpc::context::Context,
+        user: String,
+        fingerprint: String,
+    ) -> Result<bool, RpcError> {
+        let slf = self.lock().await;
+        let target = Some(format!("{} {}", user, fingerprint));
+        let result: Result<bool, RpcError> = async {
+            slf.check_self_or_admin(&user).await?;
+            let mut slf = slf.state.lock().await;
+            let ssh_keys = slf.ssh_keys()?;
+            let mut data = ssh_keys.data.clone();
+            let keys = data.users.entry(user.clone()).or_default();
+            let before = keys.len();
+            keys.retain(|k| k.fingerprint() != fingerprint);
+            let removed = keys.len() != before;
+            if keys.is_empty() {
+                data.users.remove(&user);
+            }
+            if removed {
+                ssh_keys.save(data).expect("writing ssh keys");
+            }
+            Ok(removed)
+        }
+        .await;
+        slf.audit("remove_ssh_key", target, &result).await;
+        result
+    }
+
+    async fn get_authorized_keys(
+        self,
+        _ctx: tarpc::context::Context,
+        user: String,
+        host: String,
+    ) -> Result<Vec<String>, RpcError> {
+        let slf = self.lock().await;
+        slf.check_read(Database::Passwd).await?;
+        let mut slf = slf.state.lock().await;
+        slf.files.refresh().expect("refreshing fio");
+        if slf.ssh_keys.is_none() {
+            return Ok(vec![]);
+        }
+        match slf.account_status(&user, &host) {
+            AccountStatus::Ok
+            | AccountStatus::PasswordExpiresSoon(_)
+            | AccountStatus::PasswordExpired => {}
+            status => {
+                tracing::debug!("no keys for {} on {}: {:?}", user, host, status);
+                return Ok(vec![]);
+            }
+        }
+        let access = slf.access_rules();
+        let today = crate::types::today();
+        let ssh_keys = slf.ssh_keys()?;
+        Ok(ssh_keys
+            .data
+            .users
+            .get(&user)
+            .map(|keys| {
+                keys.iter()
+                    .filter(|k| k.valid_on(&host, today, &access))
+                    .map(|k| k.key.clone())
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn get_authorized_principals(
+        self,
+        _ctx: tarpc::context::Context,
+        user: String,
+        host: String,
+    ) -> Result<Vec<String>, RpcError> {
+        let slf = self.lock().await;
+        slf.check_read(Database::Passwd).await?;
+        let mut slf = slf.state.lock().await;
+        slf.files.refresh().expect("refreshing fio");
+        match slf.account_status(&user, &host) {
+            AccountStatus::Ok
+            | AccountStatus::PasswordExpiresSoon(_)
+            | AccountStatus::PasswordExpired => Ok(vec![user]),
+            status => {
+                tracing::debug!("no principals for {} on {}: {:?}", user, host, status);
+                Ok(vec![])
+            }
+        }
+    }
+
+    async fn get_ssh_ca_public_key(
+        self,
+        _ctx: tarpc::context::Context,
+    ) -> Result<String, RpcError> {
+        let slf = self.lock().await;
+        let slf = slf.state.lock().await;
+        let ca = slf.ssh_ca.as_ref().ok_or(RpcError::NotConfigured)?;
+        Ok(ca
+            .public_key()
+            .to_openssh()
+            .expect("encoding ssh ca public key"))
+    }
+
+    async fn sign_ssh_user_key(
+        self,
+        _ctx: tarpc::context::Context,
+        public_key: String,
+    ) -> Result<String, RpcError> {
+        let slf = self.lock().await;
+        let result: Result<String, RpcError> = async {
+            let username = match (&slf.purported_username, &slf.session_key) {
+                (Some(uname), Some(_)) => uname.clone(),
+                _ => return Err(RpcError::NotAuthorized),
+            };
+            // a stolen session token shouldn't be worth hours of ssh access on its own
+            if slf.resumed_until.is_some() {
+                return Err(RpcError::NotAuthorized);
+            }
+            let mut state = slf.state.lock().await;
+            state.files.refresh().expect("refreshing fio");
+            if let Some(shadow) = state.files.shadow.data.iter().find(|x| x.name == username) {
+                let status = shadow.aging_status(crate::types::today());
+                if matches!(
+                    status,
+                    AccountStatus::AccountExpired | AccountStatus::Locked
+                ) {
+                    return Err(RpcError::NotAuthorized);
+                }
+            }
+            let valid = hours_in_secs(
+                state
+                    .config
+                    .ssh_user_cert_hours
+                    .unwrap_or(crate::ssh::DEFAULT_USER_CERT_HOURS),
+            )?;
+            let ca = state.ssh_ca.as_ref().ok_or(RpcError::NotConfigured)?;
+            let cert = crate::ssh::sign_certificate(
+                ca,
+                &public_key,
+                ssh_key::certificate::CertType::User,
+                &username,
+                &[username.clone()],
+                std::time::Duration::from_secs(valid),
+            )
+            .map_err(|e| RpcError::Invalid(e.to_string()))?;
+            tracing::info!("signed an ssh certificate for {}", username);
+            Ok(cert)
+        }
+        .await;
+        slf.audit("sign_ssh_user_key", None, &result).await;
+        result
+    }
+
+    async fn sign_ssh_host_key(
+        self,
+        _ctx: tarpc::context::Context,
+        host: String,
+        public_key: String,
+    ) -> Result<String, RpcError> {
+        let slf = self.lock().await;
+        let target = Some(host.clone());
+        let result: Result<String, RpcError> = async {
+            let is_that_host =
+                slf.authenticated_as(&host) && slf.principal().await == Principal::Host;
+            if !is_that_host && !slf.auth_admin().await {
+                return Err(RpcError::NotAuthorized);
+            }
+            let state = slf.state.lock().await;
+            let days = state
+                .config
+                .ssh_host_cert_days
+                .unwrap_or(crate::ssh::DEFAULT_HOST_CERT_DAYS);
+            let valid = hours_in_secs(days.saturating_mul(24))?;
+            let ca = state.ssh_ca.as_ref().ok_or(RpcError::NotConfigured)?;
+            let cert = crate::ssh::sign_certificate(
+                ca,
+                &public_key,
+                ssh_key::certificate::CertType::Host,
+                &host,
+                &[host.clone()],
+                std::time::Duration::from_secs(valid),
+            )
+            .map_err(|e| RpcError::Invalid(e.to_string()))?;
+            tracing::info!("signed an ssh host certificate for {}", host);
+            Ok(cert)
+        }
+        .await;
+        slf.audit("sign_ssh_host_key", target, &result).await;
+        result
+    }
+
+    async fn register_new_user(
+        self,
+        _ctx: tarpc::context::Context,
+        username: String,
+        _selected_uid: Option<u32>,
+        reg: RegistrationRequest<DefaultCipherSuite>,
+    ) -> Result<RegistrationResponse<DefaultCipherSuite>, RpcError> {
+        let mut slf = self.lock().await;
+        if !slf.auth_admin().await {
+            return Err(RpcError::NotAuthorized);
+        }
+        /* TODO: do something with selected_id? run the autoallocate? */
+        let reg = ServerRegistration::<DefaultCipherSuite>::start(
+            &slf.state.lock().await.setup,
+            reg,
+            username.as_bytes(),
+        )
+        .unwrap();
+        slf.registering_username = Some(username);
+        Ok(reg.message)
+    }
+
+    async fn finish_registration(
+        self,
+        _ctx: tarpc::context::Context,
+        reg: RegistrationUpload<DefaultCipherSuite>,
+    ) -> Result<(), RpcError> {
+        let mut slf = self.lock().await;
+        let target = slf.registering_username.clone();
+        let result: Result<(), RpcError> = async {
+            if !slf.auth_admin().await {
+                return Err(RpcError::NotAuthorized);
+            }
+
+            let password_file = ServerRegistration::<DefaultCipherSuite>::finish(reg);
+            let username = slf
+                .registering_username
+                .take()
+                .ok_or(RpcError::NotAuthorized)?;
+            let path = PathBuf::from(&slf.state.lock().await.config.opaque_cookies).join(username);
+            std::fs::write(path, password_file.serialize()).expect("writing out opaque cookie");
+            Ok(())
+        }
+        .await;
+        slf.audit("finish_registration", target, &result).await;
+        result
+    }
+
+    async fn start_self_registration(
+        self,
+        _ctx: tarpc::context::Context,
+        reg: RegistrationRequest<DefaultCipherSuite>,
+    ) -> Result<RegistrationResponse<DefaultCipherSuite>, RpcError> {
+        let mut slf = self.lock().await;
+        let username = match (&slf.purported_username, &slf.session_key) {
+            // a session token is no substitute for the password being replaced
+            (Some(uname), Some(_)) if slf.resumed_until.is_none() => uname.clone(),
+            _ => return Err(RpcError::NotAuthorized),
+        };
+        let state = slf.state.lock().await;
+        // honour the minimum password age, like pam_unix does for local users
+        if let Some(shadow) = state.files.shadow.data.iter().find(|x| x.name == username) {
+            if shadow.last_change != 0
+                && crate::types::today() < shadow.last_change + shadow.change_min_days
+            {
+                return Err(RpcError::PasswordChangeTooSoon);
+            }
+        }
+        let reg =
+            ServerRegistration::<DefaultCipherSuite>::start(&state.setup, reg, username.as_bytes())
+                .unwrap();
+        drop(state);
+        slf.registering_username = Some(username);
+        Ok(reg.message)
+    }
+
+    async fn finish_self_registration(
+        self,
+        _ctx: tarpc::context::Context,
+        reg: RegistrationUpload<DefaultCipherSuite>,
+    ) -> Result<(), RpcError> {
+        let mut slf = self.lock().await;
+        let target = slf.registering_username.clone();
+        let result: Result<(), RpcError> = async {
+            let username = slf
+                .registering_username
+                .take()
+                .ok_or(RpcError::NotAuthorized)?;
+            if !slf.authenticated_as(&username) {
+                return Err(RpcError::NotAuthorized);
+            }
+
+            let password_file = ServerRegistration::<DefaultCipherSuite>::finish(reg);
+            let mut state = slf.state.lock().await;
+            // shadow first, so if it can't be written the password stays as it was
+            state
+                .files
+                .set_last_change(&username, crate::types::today())
+                .map_err(|e| RpcError::Invalid(e.to_string()))?;
+            let path = PathBuf::from(&state.config.opaque_cookies).join(&username);
+            std::fs::write(path, password_file.serialize()).expect("writing out opaque cookie");
+            // sessions from the old password shouldn't outlive it
+            state.sessions.retain(|_, s| s.username != username);
+            tracing::info!("{} changed their password", username);
+            Ok(())
+        }
+        .await;
+        slf.audit("finish_self_registration", target, &result).await;
+        result
+    }
+
+    async fn issue_reset_token(
+        self,
+        _ctx: tarpc::context::Context,
+        username: String,
+    ) -> Result<String, RpcError> {
+        let slf = self.lock().await;
+        let target = Some(username.clone());
+        let result: Result<String, RpcError> = async {
+            if !slf.auth_admin().await {
+                return Err(RpcError::NotAuthorized);
+            }
+            let issued_by = slf.purported_username.clone().unwrap_or_default();
+            let mut state = slf.state.lock().await;
+            state.files.refresh().expect("refreshing fio");
+            if !state.files.passwd.data.iter().any(|x| x.name == username) {
+                return Err(RpcError::Invalid(format!("no such user {}", username)));
+            }
+            let hours = state
+                .config
+                .reset_token_hours
+                .unwrap_or(crate::reset::DEFAULT_TOKEN_HOURS);
+            let valid = hours_in_secs(hours)?;
+            let token = crate::reset::generate_token();
+            let now = unix_time();
+            let resets = state.resets()?;
+            let mut data = resets.data.clone();
+            data.prune(now);
+            data.tokens.insert(
+                crate::reset::hash_token(&token),
+                ResetToken {
+                    user: username.clone(),
+                    issued_by: issued_by.clone(),
+                    expires: now.saturating_add(valid),
+                },
+            );
+            resets.save(data).expect("writing reset tokens");
+            tracing::info!(
+                "{} issued a password reset token for {}, valid for {} hours",
+                issued_by,
+                username,
+                hours
+            );
+            Ok(token)
+        }
+        .await;
+        slf.audit("issue_reset_token", target, &result).await;
+        result
+    }
+
+    async fn start_reset_registration(
+        self,
+        _ctx: tarpc::context::Context,
+        token: String,
+        reg: RegistrationRequest<DefaultCipherSuite>,
+    ) -> Result<(String, RegistrationResponse<DefaultCipherSuite>), RpcError> {
+        let mut slf = self.lock().await;
+        let result: Result<(String, RegistrationResponse<DefaultCipherSuite>), RpcError> = async {
+            let mut state = slf.state.lock().await;
+            let username = match state.resets()?.data.find(&token, unix_time()) {
+                Some(reset) => reset.user.clone(),
+                None => {
+                    tracing::info!(
+                        "unknown or expired password reset token from {}",
+                        slf.peer_addr
+                    );
+                    return Err(RpcError::AuthenticationFailure);
+                }
+            };
+            let reg = ServerRegistration::<DefaultCipherSuite>::start(
+                &state.setup,
+                reg,
+                username.as_bytes(),
+            )
+            .unwrap();
+            drop(state);
+            slf.registering_username = Some(username.clone());
+            slf.redeeming_token = Some(crate::reset::hash_token(&token));
+            Ok((username, reg.message))
+        }
+        .await;
+        slf.audit("start_reset_registration", None, &result).await;
+        result
+    }
+
+    async fn finish_reset_registration(
+        self,
+        _ctx: tarpc::context::Context,
+        reg: RegistrationUpload<DefaultCipherSuite>,
+    ) -> Result<(), RpcError> {
+        let mut slf = self.lock().await;
+        let target = slf.registering_username.clone();
+        let result: Result<(), RpcError> = async {
+            let (username, token_hash) =
+                match (slf.registering_username.take(), slf.redeeming_token.take()) {
+                    (Some(username), Some(token_hash)) => (username, token_hash),
+                    _ => return Err(RpcError::NotAuthorized),
+                };
+
+            let mut state = slf.state.lock().await;
+            // the state stays locked until the token is used up, so it can't be redeemed twice
+            // at once
+            let resets = state.resets()?;
+            let mut data = resets.data.clone();
+            match data.tokens.remove(&token_hash) {
+                Some(reset) if reset.user == username && reset.expires >= unix_time() => {}
+                _ => return Err(RpcError::AuthenticationFailure),
+            }
+            // before using the token up, so it can be tried again if shadow can't be written
+            state
+                .files
+                .set_last_change(&username, crate::types::today())
+                .map_err(|e| RpcError::Invalid(e.to_string()))?;
+            let resets = state.resets()?;
+            data.prune(unix_time());
+            resets.save(data).expect("writing reset tokens");
+
+            let password_file = ServerRegistration::<DefaultCipherSuite>::finish(reg);
+            let path = PathBuf::from(&state.config.opaque_cookies).join(&username);
+            std::fs::write(path, password_file.serialize()).expect("writing out opaque cookie");
+            state.sessions.retain(|_, s| s.username != username);
+            tracing::info!(
+                "password for {} reset with a token, from {}",
+                username,
+                slf.peer_addr
+            );
+            Ok(())
+        }
+        .await;
+        slf.audit("finish_reset_registration", target, &result)
+            .await;
+        result
+    }
+
+    async fn create_account(
+        self,
+        _ctx: tarpc::context::Context,
+        account: NewAccount,
+    ) -> Result<Passwd, RpcError> {
+        let slf = self.lock().await;
+        let target = Some(account.name.clone());
+        let result: Result<Passwd, RpcError> = async {
+            if !slf.auth_admin().await {
+                return Err(RpcError::NotAuthorized);
+            }
+            let passwd = slf.state.lock().await.make_account(account)?;
+            tracing::info!(
+                "{} created account {} with uid {}",
+                slf.purported_username.as_deref().unwrap_or_default(),
+                passwd.name,
+                passwd.id
+            );
+            Ok(passwd)
+        }
+        .await;
+        slf.audit("create_account", target, &result).await;
+        result
+    }
+
+    async fn create_invite(
+        self,
+        _ctx: tarpc::context::Context,
+        uses: u32,
+        hours: u64,
+        groups: Vec<String>,
+    ) -> Result<String, RpcError> {
+        let slf = self.lock().await;
+        let result: Result<String, RpcError> = async {
+            if !slf.auth_admin().await {
+                return Err(RpcError::NotAuthorized);
+            }
+            let issued_by = slf.purported_username.clone().unwrap_or_default();
+            let mut state = slf.state.lock().await;
+            state.files.refresh().expect("refreshing fio");
+            for group in &groups {
+                // being an admin or a host takes more than showing up with a code
+                if group == ADMINS_GROUP || group == HOSTS_GROUP {
+                    return Err(RpcError::Invalid(format!("invites can't grant {}", group)));
+                }
+                if !state.files.group.data.iter().any(|x| &x.name == group) {
+                    return Err(RpcError::Invalid(format!("no such group {}", group)));
+                }
+            }
+            if uses == 0 {
+                return Err(RpcError::Invalid("an invite needs at least one use".into()));
+            }
+            let valid = hours_in_secs(hours)?;
+            let code = crate::reset::generate_token();
+            let now = unix_time();
+            let invites = state.invites()?;
+            let mut data = invites.data.clone();
+            data.prune(now);
+            data.invites.insert(
+                crate::reset::hash_token(&code),
+                Invite {
+                    issued_by: issued_by.clone(),
+                    expires: now.saturating_add(valid),
+                    uses_left: uses,
+                    groups: groups.clone(),
+                },
+            );
+            invites.save(data).expect("writing invites");
+            tracing::info!(
+                "{} made an invite for {} accounts over {} hours, into {:?}",
+                issued_by,
+                uses,
+                hours,
+                groups
+            );
+            Ok(code)
+        }
+        .await;
+        slf.audit("create_invite", None, &result).await;
+        result
+    }
+
+    async fn start_enrollment(
+        self,
+        _ctx: tarpc::context::Context,
+        code: String,
+        username: String,
+        reg: RegistrationRequest<DefaultCipherSuite>,
+    ) -> Result<RegistrationResponse<DefaultCipherSuite>, RpcError> {
+        let mut slf = self.lock().await;
+        let target = Some(username.clone());
+        let result: Result<RegistrationResponse<DefaultCipherSuite>, RpcError> = async {
+            let mut state = slf.state.lock().await;
+            if state.invites()?.data.find(&code, unix_time()).is_none() {
+                tracing::info!("unknown or used up invite code from {}", slf.peer_addr);
+                return Err(RpcError::AuthenticationFailure);
+            }
+            state.files.refresh().expect("refreshing fio");
+            state.check_new_username(&username)?;
+            let reg = ServerRegistration::<DefaultCipherSuite>::start(
+                &state.setup,
+                reg,
+                username.as_bytes(),
+            )
+            .unwrap();
+            drop(state);
+            slf.registering_username = Some(username);
+            slf.enrolling_invite = Some(crate::reset::hash_token(&code));
+            Ok(reg.message)
+        }
+        .await;
+        slf.audit("start_enrollment", target, &result).await;
+        result
+    }
+
+    async fn finish_enrollment(
+        self,
+        _ctx: tarpc::context::Context,
+        reg: RegistrationUpload<DefaultCipherSuite>,
+    ) -> Result<Passwd, RpcError> {
+        let mut slf = self.lock().await;
+        let target = slf.registering_username.clone();
+        let result: Result<Passwd, RpcError> = async {
+            let (username, code_hash) =
+                match (slf.registering_username.take(), slf.enrolling_invite.take()) {
+                    (Some(username), Some(code_hash)) => (username, code_hash),
+                    _ => return Err(RpcError::NotAuthorized),
+                };
+
+            let mut state = slf.state.lock().await;
+            let now = unix_time();
+            let invite = match state.invites()?.data.invites.get(&code_hash) {
+                Some(invite) if invite.expires >= now && invite.uses_left > 0 => invite.clone(),
+                _ => return Err(RpcError::AuthenticationFailure),
+            };
+            // someone else may have taken the name since start_enrollment, make_account checks again
+            let passwd = state.make_account(NewAccount {
+                name: username.clone(),
+                groups: invite.groups.clone(),
+                ..Default::default()
+            })?;
+
+            let password_file = ServerRegistration::<DefaultCipherSuite>::finish(reg);
+            let path = PathBuf::from(&state.config.opaque_cookies).join(&username);
+            std::fs::write(path, password_file.serialize()).expect("writing out opaque cookie");
+
+            let invites = state.invites()?;
+            let mut data = invites.data.clone();
+            if let Some(invite) = data.invites.get_mut(&code_hash) {
+                invite.uses_left -= 1;
+            }
+            data.prune(now);
+            invites.save(data).expect("writing invites");
+            tracing::info!(
+                "{} enrolled as uid {} with an invite from {}, from {}",
+                username,
+                passwd.id,
+                invite.issued_by,
+                slf.peer_addr
+            );
+            Ok(passwd)
+        }
+        .await;
+        slf.audit("finish_enrollment", target, &result).await;
+        result
+    }
+
+    async fn legacy_login_available(
+        self,
+        _ctx: tarpc::context::Context,
+        username: String,
+    ) -> Result<bool, RpcError> {
+        let slf = self.lock().await;
+        slf.check_read(Database::Shadow).await?;
+        let available = slf.state.lock().await.legacy_hash(&username).is_some();
+        Ok(available)
+    }
+
+    async fn start_legacy_migration(
+        self,
+        _ctx: tarpc::context::Context,
+        username: String,
+        reg: RegistrationRequest<DefaultCipherSuite>,
+    ) -> Result<RegistrationResponse<DefaultCipherSuite>, RpcError> {
+        let mut slf = self.lock().await;
+        let target = Some(username.clone());
+        let result: Result<RegistrationResponse<DefaultCipherSuite>, RpcError> = async {
+            let mut state = slf.state.lock().await;
+            if !state.config.legacy_login {
+                return Err(RpcError::NotConfigured);
+            }
+            let hash = state.legacy_hash(&username);
+            let reg = ServerRegistration::<DefaultCipherSuite>::start(
+                &state.setup,
+                reg,
+                username.as_bytes(),
+            )
+            .unwrap();
+            drop(state);
+            slf.registering_username = Some(username);
+            slf.migrating = Some(LegacyMigration {
+                hash,
+                credential: None,
+                login: None,
+            });
+            Ok(reg.message)
+        }
+        .await;
+        slf.audit("start_legacy_migration", target, &result).await;
+        result
+    }
+
+    async fn continue_legacy_migration(
+        self,
+        _ctx: tarpc::context::Context,
+        reg: RegistrationUpload<DefaultCipherSuite>,
+        req: CredentialRequest<DefaultCipherSuite>,
+    ) -> Result<CredentialResponse<DefaultCipherSuite>, RpcError> {
+        let mut slf = self.lock().await;
+        let username = match (&slf.registering_username, &slf.migrating) {
+            // only once per migration
+            (Some(username), Some(migrating)) if migrating.credential.is_none() => username.clone(),
+            _ => return Err(RpcError::NotAuthorized),
+        };
+        let credential = ServerRegistration::<DefaultCipherSuite>::finish(reg);
+        let start = ServerLogin::start(
+            &mut OsRng,
+            &slf.state.lock().await.setup,
+            Some(credential.clone()),
+            req,
+            username.as_bytes(),
+            ServerLoginStartParameters::default(),
+        )
+        .unwrap();
+        // checked above
+        let migrating = slf.migrating.as_mut().unwrap();
+        migrating.credential = Some(credential);
+        migrating.login = Some(start.state);
+        Ok(start.message)
+    }
+
+    async fn finish_legacy_migration(
+        self,
+        _ctx: tarpc::context::Context,
+        req: CredentialFinalization<DefaultCipherSuite>,
+        sealed_password: Vec<u8>,
+    ) -> Result<(), RpcError> {
+        let mut slf = self.lock().await;
+        let target = slf.registering_username.clone();
+        let result: Result<(), RpcError> = async {
+            let (username, migrating) =
+                match (slf.registering_username.take(), slf.migrating.take()) {
+                    (Some(username), Some(migrating)) => (username, migrating),
+                    _ => return Err(RpcError::NotAuthorized),
+                };
+            let (credential, login) = match (migrating.credential, migrating.login) {
+                (Some(credential), Some(login)) => (credential, login),
+                _ => return Err(RpcError::NotAuthorized),
+            };
+            let session_key = login
+                .finish(req)
+                .map_err(|_| RpcError::AuthenticationFailure)?
+                .session_key;
+            let password = crate::legacy::open_password(&session_key, &sealed_password)
+                .ok_or(RpcError::AuthenticationFailure)?;
+
+            let mut state = slf.state.lock().await;
+            // the hash may have changed, or another connection may have migrated them, since we started
+            let hash = match (state.legacy_hash(&username), migrating.hash) {
+                (Some(now), Some(then)) if now == then => then,
+                _ => return Err(RpcError::AuthenticationFailure),
+            };
+            if !crate::legacy::verify(&password, &hash) {
+                tracing::info!(
+                    "failed legacy login for {}, from {}",
+                    username,
+                    slf.peer_addr
+                );
+                return Err(RpcError::AuthenticationFailure);
+            }
+
+            // a `!` on its own says the password lives elsewhere, see `Shadow::is_locked`
+            state
+                .files
+                .set_shadow_password(&username, "!")
+                .map_err(|e| RpcError::Invalid(e.to_string()))?;
+            let path = PathBuf::from(&state.config.opaque_cookies).join(&username);
+            std::fs::write(path, credential.serialize()).expect("writing out opaque cookie");
+            tracing::info!(
+                "{} moved from a crypt hash to OPAQUE, from {}",
+                username,
+                slf.peer_addr
+            );
+            Ok(())
+        }
+        .await;
+        slf.audit("finish_legacy_migration", target, &result).await;
+        result
+    }
+
+    async fn start_login(
+        self,
+        _ctx: tarpc::context::Context,
+        username: String,
+        req: CredentialRequest<DefaultCipherSuite>,
+    ) -> Result<CredentialResponse<DefaultCipherSuite>, RpcError> {
+        let mut slf = self.lock().await;
+        let target = Some(username.clone());
+        let result: Result<CredentialResponse<DefaultCipherSuite>, RpcError> = async {
+            let password_file = slf
+                .state
+                .lock()
+                .await
+                .find_password_file(&username)
+                .ok()
+                .and_then(|d| {
+                    ServerRegistration::<DefaultCipherSuite>::deserialize(&d)
+                        .map_err(|e| eprintln!("error deserializing password file: {:?}", e))
+                        .ok()
+                });
+
+            let mut server_rng = OsRng;
+            let server_login_start_result = ServerLogin::start(
+                &mut server_rng,
+                &slf.state.lock().await.setup,
+                password_file,
+                req,
+                username.as_bytes(),
+                ServerLoginStartParameters::default(),
+            )
+            .unwrap();
+
+            slf.login_progress = Some(server_login_start_result.state);
+            // whoever was logged in before isn't anymore
+            slf.session_key = None;
+            slf.resumed_until = None;
+            slf.pending_session_key = None;
+            slf.totp_enrolling = None;
+            slf.purported_username = Some(username);
+            Ok(server_login_start_result.message)
+        }
+        .await;
+        slf.audit("start_login", target, &result).await;
+        result
+    }
+
+    async fn finish_login(
+        self,
+        _ctx: tarpc::context::Context,
+        req: CredentialFinalization<DefaultCipherSuite>,
+    ) -> Result<LoginStatus, RpcError> {
+        let mut slf = self.lock().await;
+        let target = slf.purported_username.clone();
+        let result: Result<LoginStatus, RpcError> = async {
+            let server_login = slf
+                .login_progress
+                .take()
+                .ok_or(RpcError::AuthenticationFailure)?;
+            let finish_result = server_login.finish(req).map_err(|_| {
+                tracing::info!("failed login for {:?}", slf.purported_username);
+                RpcError::AuthenticationFailure
+            })?;
+            let session_key = Zeroizing::new(finish_result.session_key.to_vec());
+
+            let username = slf.purported_username.clone().unwrap_or_default();
+            let status = {
+                let mut state = slf.state.lock().await;
+                state.files.refresh().expect("refreshing fio");
+                let groups = state.groups_of(&username);
+                if !state.config.totp_required.requires(&username, &groups) {
+                    LoginStatus::LoggedIn
+                } else if state.totp_enrollment(&username).is_some() {
+                    LoginStatus::NeedTotp
+                } else {
+                    LoginStatus::NeedTotpEnrollment
+                }
+            };
+            if status == LoginStatus::LoggedIn {
+                slf.session_key = Some(session_key);
+            } else {
+                slf.pending_session_key = Some(session_key);
+            }
+            Ok(status)
+        }
+        .await;
+        slf.audit("finish_login", target, &result).await;
+        result
+    }
+
+    async fn create_session_token(
+        self,
+        _ctx: tarpc::context::Context,
+    ) -> Result<(String, u64), RpcError> {
+        let slf = self.lock().await;
+        let result: Result<(String, u64), RpcError> = async {
+            let username = match (&slf.purported_username, &slf.session_key) {
+                (Some(uname), Some(_)) => uname.clone(),
+                _ => return Err(RpcError::NotAuthorized),
+            };
+            let mut state = slf.state.lock().await;
+            let valid = hours_in_secs(state.config.session_hours.unwrap_or(DEFAULT_SESSION_HOURS))?;
+            let now = unix_time();
+            // a token only ever leads to tokens that expire with it, or it would never run out
+            let expires = match slf.resumed_until {
+                Some(until) => until.min(now.saturating_add(valid)),
+                None => now.saturating_add(valid),
+            };
+            let token = crate::reset::generate_token();
+            state.sessions.retain(|_, s| s.expires >= now);
+            state.sessions.insert(
+                crate::reset::hash_token(&token),
+                SessionToken {
+                    username: username.clone(),
+                    expires,
+                },
+            );
+            tracing::info!(
+                "{} got a session token for {} minutes",
+                username,
+                expires.saturating_sub(now) / 60
+            );
+            Ok((token, expires))
+        }
+        .await;
+        slf.audit("create_session_token", None, &result).await;
+        result
+    }
+
+    async fn resume_session(
+        self,
+        _ctx: tarpc::context::Context,
+        token: String,
+    ) -> Result<String, RpcError> {
+        let mut slf = self.lock().await;
+        let result = match slf.resume(token).await {
+            Some(username) => Ok(username),
+            None => {
+                tracing::info!("unknown or expired session token from {}", slf.peer_addr);
+                Err(RpcError::AuthenticationFailure)
+            }
+        };
+        slf.audit("resume_session", None, &result).await;
+        result
+    }
+
+    async fn revoke_session_token(
+        self,
+        _ctx: tarpc::context::Context,
+        token: String,
+    ) -> Result<bool, RpcError> {
+        let slf = self.lock().await;
+        let result: Result<bool, RpcError> = async {
+            let revoked = slf
+                .state
+                .lock()
+                .await
+                .sessions
+                .remove(&crate::reset::hash_token(&token));
+            if let Some(session) = &revoked {
+                tracing::info!("session token for {} revoked", session.username);
+            }
+            Ok(revoked.is_some())
+        }
+        .await;
+        slf.audit("revoke_session_token", None, &result).await;
+        result
+    }
+
+    async fn whoami(self, _ctx: tarpc::context::Context) -> WhoAmI {
+        let slf = self.lock().await;
+        let principal = slf.principal().await;
+        let username = match principal {
+            Principal::Anonymous => None,
+            _ => slf.purported_username.clone(),
+        };
+        let groups = match &username {
+            Some(username) => {
+                let mut state = slf.state.lock().await;
+                state.files.refresh().expect("refreshing fio");
+                state.groups_of(username)
+            }
+            None => vec![],
+        };
+        WhoAmI {
+            username,
+            principal,
+            groups,
+        }
+    }
+
+    async fn verify_totp(
+        self,
+        _ctx: tarpc::context::Context,
+        code: String,
+    ) -> Result<(), RpcError> {
+        let mut slf = self.lock().await;
+        let target = slf.purported_username.clone();
+        let result: Result<(), RpcError> = async {
+            if slf.pending_session_key.is_none() {
+                return Err(RpcError::NotAuthorized);
+            }
+            let username = slf.purported_username.clone().unwrap_or_default();
+            let verified = slf.state.lock().await.check_totp(&username, &code);
+            if !verified {
+                tracing::info!("wrong TOTP code for {}", username);
+                // one guess per password: start the login over before trying another code
+                slf.pending_session_key = None;
+                slf.purported_username = None;
+                slf.login_progress = None;
+                slf.totp_enrolling = None;
+                return Err(RpcError::AuthenticationFailure);
+            }
+            slf.session_key = slf.pending_session_key.take();
+            Ok(())
+        }
+        .await;
+        slf.audit("verify_totp", target, &result).await;
+        result
+    }
+
+    async fn start_totp_enrollment(
+        self,
+        _ctx: tarpc::context::Context,
+    ) -> Result<String, RpcError> {
+        let mut slf = self.lock().await;
+        let username = match &slf.purported_username {
+            // nor for the second factor
+            Some(uname)
+                if slf.resumed_until.is_none()
+                    && (slf.session_key.is_some() || slf.pending_session_key.is_some()) =>
+            {
+                uname.clone()
+            }
+            _ => return Err(RpcError::NotAuthorized),
+        };
+        let secret = crate::totp::generate_secret();
+        let uri = {
+            let mut state = slf.state.lock().await;
+            if state.totp.is_none() {
+                return Err(RpcError::NotConfigured);
+            }
+            // halfway through logging in, only those who have never enrolled may, or replacing
+            // the secret would skip the code
+            if slf.session_key.is_none() && state.totp_enrollment(&username).is_some() {
+                return Err(RpcError::NotAuthorized);
+            }
+            crate::totp::otpauth_uri(&state.config.authoritative_name, &username, &secret)
+        };
+        slf.totp_enrolling = Some(secret);
+        Ok(uri)
+    }
+
+    async fn confirm_totp_enrollment(
+        self,
+        _ctx: tarpc::context::Context,
+        code: String,
+    ) -> Result<(), RpcError> {
+        let mut slf = self.lock().await;
+        let target = slf.purported_username.clone();
+        let result: Result<(), RpcError> = async {
+            let username = slf
+                .purported_username
+                .clone()
+                .ok_or(RpcError::NotAuthorized)?;
+            let secret = slf.totp_enrolling.clone().ok_or(RpcError::NotAuthorized)?;
+            let now = unix_time();
+            let step = crate::totp::check(&secret, &code, 0, now)
+                .ok_or(RpcError::AuthenticationFailure)?;
+            {
+                let mut state = slf.state.lock().await;
+                let totp = state.totp.as_mut().ok_or(RpcError::NotConfigured)?;
+                totp.refresh().expect("refreshing totp secrets");
+                let mut data = totp.data.clone();
+                data.users.insert(
+                    username.clone(),
+                    TotpEnrollment {
+                        secret,
+                        last_step: step,
+                    },
+                );
+                totp.save(data).expect("writing totp secrets");
+            }
+            slf.totp_enrolling = None;
+            tracing::info!("{} enrolled in TOTP", username);
+            // a login that was waiting on enrollment is now complete
+            if slf.pending_session_key.is_some() {
+                slf.session_key = slf.pending_session_key.take();
+            }
+            Ok(())
+        }
+        .await;
+        slf.audit("confirm_totp_enrollment", target, &result).await;
+        result
+    }
+
+    async fn remove_totp(
+        self,
+        _ctx: tarpc::context::Context,
+        username: String,
+    ) -> Result<bool, RpcError> {
+        let slf = self.lock().await;
+        let target = Some(username.clone());
+        let result: Result<bool, RpcError> = async {
+            if !slf.auth_admin().await {
+                return Err(RpcError::NotAuthorized);
+            }
+            let mut state = slf.state.lock().await;
+            let totp = state.totp.as_mut().ok_or(RpcError::NotConfigured)?;
+            totp.refresh().expect("refreshing totp secrets");
+            let mut data = totp.data.clone();
+            let removed = data.users.remove(&username).is_some();
+            if removed {
+                totp.save(data).expect("writing totp secrets");
+                tracing::info!("removed TOTP for {}", username);
+            }
+            Ok(removed)
+        }
+        .await;
+        slf.audit("remove_totp", target, &result).await;
+        result
+    }
+
+    async fn get_audit_log(
+        self,
+        _ctx: tarpc::context::Context,
+        after: u64,
+    ) -> Result<Vec<AuditEntry>, RpcError> {
+        let slf = self.lock().await;
+        if !slf.auth_admin().await {
+            return Err(RpcError::NotAuthorized);
+        }
+        let path = slf
+            .state
+            .lock()
+            .await
+            .config
+            .audit_file
+            .clone()
+            .ok_or(RpcError::NotConfigured)?;
+        let entries = crate::audit::read(std::path::Path::new(&path)).expect("reading audit log");
+        Ok(entries.into_iter().filter(|e| e.seq > after).collect())
+    }
+}
+
+use actix_web::dev::{Server, ServerHandle};
+use anyhow::Context;
+use argh::FromArgs;
+use tarpc::{
+    server::{BaseChannel, Channel},
+    tokio_serde::formats::Json,
+};
+use tokio::{
+    net::TcpListener,
+    signal::unix::{signal, SignalKind},
+    sync::Mutex,
+    task::{AbortHandle, JoinSet},
+};
+use tokio_rustls::rustls::{self, Certificate, PrivateKey};
+use tokio_rustls::TlsAcceptor;
+use tracing_subscriber::{
+    fmt::format::FmtSpan, prelude::__tracing_subscriber_SubscriberExt, util::SubscriberInitExt,
+};
+
+#[derive(FromArgs, PartialEq, Debug)]
 /// authentication daemon
 struct AuthdArgs {
     #[argh(option)]
@@ -321,73 +2120,1039 @@ pub async fn main() -> anyhow::Result<()> {
         .config_file
         .unwrap_or_else(|| cfgdir.join("authd.toml"));
 
-    let mut config_file: crate::AuthdConfig = toml::from_slice(&std::fs::read(&config_path)?)?;
-    config_file.expand();
-    let server_addrs = config_file.bind_addrs.clone();
+    let config_file = crate::AuthdConfig::load(&config_path)?;
+    serve_with_reload(config_file, config_path).await
+}
+
+/// Counts and times every RPC for the metrics, by method.
+#[derive(Clone)]
+struct Metered<S>(S);
+
+impl<Req, S> tarpc::server::Serve<Req> for Metered<S>
+where
+    S: tarpc::server::Serve<Req>,
+    S::Fut: Send + 'static,
+{
+    type Resp = S::Resp;
+    type Fut = futures_util::future::BoxFuture<'static, S::Resp>;
+
+    fn method(&self, request: &Req) -> Option<&'static str> {
+        self.0.method(request)
+    }
+
+    fn serve(self, ctx: tarpc::context::Context, req: Req) -> Self::Fut {
+        let method = self.0.method(&req).unwrap_or("unknown");
+        let fut = self.0.serve(ctx, req);
+        Box::pin(async move {
+            let start = std::time::Instant::now();
+            let resp = fut.await;
+            crate::metrics::rpc(method, start.elapsed().as_secs_f64());
+            resp
+        })
+    }
+}
+
+/// Bind `/metrics` to `addr`. It is served as long as the returned server is polled.
+fn metrics_server(
+    addr: std::net::SocketAddr,
+    state: Arc<Mutex<SharedState>>,
+) -> anyhow::Result<Server> {
+    use actix_web::{web, App, HttpResponse, HttpServer};
+
+    async fn metrics(state: web::Data<Arc<Mutex<SharedState>>>) -> HttpResponse {
+        let mut state = state.lock().await;
+        // a file that doesn't parse right now is counted in the metrics, not a reason to fail
+        let _ = state.files.refresh();
+        let now = unix_time();
+        state.sessions.retain(|_, s| s.expires >= now);
+        let body = crate::metrics::render(
+            state.files.passwd.data.len(),
+            state.files.group.data.len(),
+            state.sessions.len(),
+        );
+        HttpResponse::Ok()
+            .content_type("text/plain; version=0.0.4")
+            .body(body)
+    }
+
+    tracing::info!("serving metrics on {}", addr);
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(web::Data::new(state.clone()))
+            .route("/metrics", web::get().to(metrics))
+    })
+    .workers(1)
+    .bind(addr)?
+    .run();
+    Ok(server)
+}
+
+impl SharedState {
+    /// Load what `config` points at, failing if any of it is missing or broken. There are no
+    /// session tokens yet.
+    fn new(config: &crate::AuthdConfig) -> anyhow::Result<Self> {
+        let mut files = Files::new(&config.passwd_file, &config.group_file, &config.shadow_file);
+        if let Some(netgroup_file) = &config.netgroup_file {
+            files = files.with_netgroup(netgroup_file);
+        }
+        // these are only read on the first lookup, so at least make sure they are there
+        for pth in [&config.passwd_file, &config.group_file, &config.shadow_file]
+            .into_iter()
+            .chain(&config.netgroup_file)
+        {
+            std::fs::metadata(pth).with_context(|| format!("reading {}", pth))?;
+        }
+        let setup = std::fs::read(&config.opaque_server_setup)
+            .with_context(|| format!("reading {}", config.opaque_server_setup))?;
+        Ok(SharedState {
+            setup: ServerSetup::deserialize(&setup)
+                .map_err(|e| anyhow::anyhow!("deserializing opaque setup: {:?}", e))?,
+            config: config.clone(),
+            files,
+            access: config.access_file.as_ref().map(TomlFile::new),
+            sudo: config.sudo_file.as_ref().map(TomlFile::new),
+            totp: config.totp_file.as_ref().map(TomlFile::secret),
+            resets: config.reset_file.as_ref().map(TomlFile::secret),
+            invites: config.invites_file.as_ref().map(TomlFile::secret),
+            sessions: HashMap::new(),
+            audit: config.audit_file.as_ref().map(AuditLog::open).transpose()?,
+            ssh_keys: config.ssh_keys_file.as_ref().map(TomlFile::secret),
+            ssh_ca: config
+                .ssh_ca_key
+                .as_ref()
+                .map(|ssh_ca_key| {
+                    ssh_key::PrivateKey::read_openssh_file(std::path::Path::new(ssh_ca_key))
+                        .with_context(|| format!("reading ssh ca key {}", ssh_ca_key))
+                })
+                .transpose()?,
+        })
+    }
+}
+
+/// The certificate and key authd serves, as loaded.
+struct Tls {
+    cert: Vec<u8>,
+    key: Vec<u8>,
+    config: Arc<rustls::ServerConfig>,
+}
 
-    let tls_config = Arc::new(
-        rustls::ServerConfig::builder()
+impl Tls {
+    fn load(config: &crate::AuthdConfig) -> anyhow::Result<Self> {
+        let cert =
+            std::fs::read(&config.cert).with_context(|| format!("reading {}", config.cert))?;
+        let key = std::fs::read(&config.key).with_context(|| format!("reading {}", config.key))?;
+        let server_config = rustls::ServerConfig::builder()
             .with_safe_defaults()
             .with_no_client_auth()
-            .with_single_cert(
-                vec![Certificate(
-                    std::fs::read(&config_file.cert).expect("read cert"),
-                )],
-                PrivateKey(std::fs::read(&config_file.key).expect("read key")),
-            )?,
-    );
-
-    let state = Arc::new(Mutex::new(SharedState {
-        setup: opaque_ke::ServerSetup::deserialize(
-            &std::fs::read(&config_file.opaque_server_setup).expect("read opaque"),
-        )
-        .expect("deserializing opaque setup"),
-        config: config_file.clone(),
-        files: Files::new(
-            config_file.passwd_file,
-            config_file.group_file,
-            config_file.shadow_file,
-        ),
-    }));
+            .with_single_cert(vec![Certificate(cert.clone())], PrivateKey(key.clone()))?;
+        Ok(Tls {
+            cert,
+            key,
+            config: Arc::new(server_config),
+        })
+    }
+}
 
-    let mut set = JoinSet::new();
+/// Everything authd is serving, so that a reload can tell what to start, stop or swap.
+struct Running {
+    config: crate::AuthdConfig,
+    state: Arc<Mutex<SharedState>>,
+    tls: Tls,
+    /// What the tarpc listeners hand new connections to. Connections that are already up keep the
+    /// acceptor they came in with.
+    acceptor: Arc<std::sync::RwLock<TlsAcceptor>>,
+    listeners: HashMap<std::net::SocketAddr, AbortHandle>,
+    http: Option<ServerHandle>,
+    metrics: Option<ServerHandle>,
+    set: JoinSet<()>,
+}
 
-    for bindaddr in server_addrs {
-        let state = state.clone();
-        let tls_config = tls_config.clone();
-        let acceptor: TlsAcceptor = tls_config.into();
+impl Running {
+    async fn start(config: crate::AuthdConfig) -> anyhow::Result<Self> {
+        let tls = Tls::load(&config)?;
+        let mut running = Running {
+            state: Arc::new(Mutex::new(SharedState::new(&config)?)),
+            acceptor: Arc::new(std::sync::RwLock::new(tls.config.clone().into())),
+            tls,
+            listeners: HashMap::new(),
+            http: None,
+            metrics: None,
+            set: JoinSet::new(),
+            config,
+        };
+        if let Some(addr) = running.config.metrics_addr {
+            running.metrics = Some(running.spawn_http(
+                "metrics",
+                addr,
+                metrics_server(addr, running.state.clone())?,
+            ));
+        }
+        if let Some(addr) = running.config.http_addr {
+            let server = gateway::server(addr, running.tls.config.clone(), running.state.clone())?;
+            running.http = Some(running.spawn_http("the HTTP API", addr, server));
+        }
+        for bindaddr in running.config.bind_addrs.clone() {
+            let listener = TcpListener::bind(&bindaddr).await?;
+            running.listen(bindaddr, listener);
+        }
+        Ok(running)
+    }
 
-        let listener = TcpListener::bind(&bindaddr).await.expect("tcp bind");
+    fn spawn_http(
+        &mut self,
+        what: &'static str,
+        addr: std::net::SocketAddr,
+        server: Server,
+    ) -> ServerHandle {
+        let handle = server.handle();
+        self.set.spawn(async move {
+            if let Err(e) = server.await {
+                tracing::error!("serving {} on {}: {}", what, addr, e);
+            }
+        });
+        handle
+    }
 
-        set.spawn(async move {
+    /// Accept tarpc connections on `listener` until it is aborted.
+    fn listen(&mut self, bindaddr: std::net::SocketAddr, listener: TcpListener) {
+        let state = self.state.clone();
+        let acceptor = self.acceptor.clone();
+        let handle = self.set.spawn(async move {
             tracing::info!("listening on {}", bindaddr);
             loop {
-                let acceptor = acceptor.clone();
-                let state = state.clone();
                 let (stream, peer_addr) = listener.accept().await.expect("tcp accept");
+                let acceptor = acceptor.read().unwrap().clone();
+                let state = state.clone();
 
                 tokio::spawn(async move {
-                    let stream = acceptor.accept(stream).await.expect("accepting tls");
+                    let stream = match acceptor.accept(stream).await {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            tracing::info!("TLS handshake with {} failed: {}", peer_addr, e);
+                            crate::metrics::tls_handshake_failed();
+                            return;
+                        }
+                    };
                     let tport = tarpc::serde_transport::Transport::from((stream, Json::default()));
                     let channel = BaseChannel::with_defaults(tport);
 
-                    let session = Arc::new(Mutex::new(AuthdSession {
-                        state: state.clone(),
-                        _peer_addr: peer_addr,
-                        purported_username: None,
-                        session_key: None,
-                        login_progress: None,
-                    }));
+                    let session = Arc::new(Mutex::new(AuthdSession::new(state, peer_addr)));
                     tracing::info!("new connection: {:?}", session);
-                    channel.execute(session.serve()).await;
+                    crate::metrics::connection_opened();
+                    channel.execute(Metered(session.serve())).await;
+                    crate::metrics::connection_closed();
                 });
             }
         });
+        self.listeners.insert(bindaddr, handle);
     }
 
-    while !set.is_empty() {
-        set.join_next().await;
+    /// Switch to the config at `path`. Everything it needs is loaded and every new address bound
+    /// before anything changes, so a config that doesn't work leaves the old one running. Open
+    /// connections and session tokens carry on either way.
+    async fn reload(&mut self, path: &std::path::Path) -> anyhow::Result<()> {
+        let config = crate::AuthdConfig::load(path)?;
+        let tls = Tls::load(&config)?;
+        let mut state = SharedState::new(&config)?;
+        let mut bound = vec![];
+        for &bindaddr in &config.bind_addrs {
+            if !self.listeners.contains_key(&bindaddr) {
+                let listener = TcpListener::bind(&bindaddr)
+                    .await
+                    .with_context(|| format!("binding {}", bindaddr))?;
+                bound.push((bindaddr, listener));
+            }
+        }
+
+        let tls_changed = tls.cert != self.tls.cert || tls.key != self.tls.key;
+        *self.acceptor.write().unwrap() = tls.config.clone().into();
+        self.tls = tls;
+        {
+            let mut shared = self.state.lock().await;
+            state.sessions = std::mem::take(&mut shared.sessions);
+            // entries may have been written since `state` opened the same log, so keep the one
+            // that knows about them
+            if state.config.audit_file == shared.config.audit_file {
+                state.audit = shared.audit.take();
+            }
+            *shared = state;
+        }
+
+        self.listeners.retain(|bindaddr, handle| {
+            let keep = config.bind_addrs.contains(bindaddr);
+            if !keep {
+                tracing::info!("no longer listening on {}", bindaddr);
+                handle.abort();
+            }
+            keep
+        });
+        for (bindaddr, listener) in bound {
+            self.listen(bindaddr, listener);
+        }
+
+        // actix can't swap certificates, so the API is restarted on a new one, after letting go of
+        // the address
+        if config.http_addr != self.config.http_addr || tls_changed {
+            if let Some(handle) = self.http.take() {
+                handle.stop(true).await;
+            }
+            if let Some(addr) = config.http_addr {
+                match gateway::server(addr, self.tls.config.clone(), self.state.clone()) {
+                    Ok(server) => self.http = Some(self.spawn_http("the HTTP API", addr, server)),
+                    Err(e) => tracing::error!("serving the HTTP API on {}: {}", addr, e),
+                }
+            }
+        }
+        if config.metrics_addr != self.config.metrics_addr {
+            if let Some(handle) = self.metrics.take() {
+                handle.stop(true).await;
+            }
+            if let Some(addr) = config.metrics_addr {
+                match metrics_server(addr, self.state.clone()) {
+                    Ok(server) => self.metrics = Some(self.spawn_http("metrics", addr, server)),
+                    Err(e) => tracing::error!("serving metrics on {}: {}", addr, e),
+                }
+            }
+        }
+        self.config = config;
+        Ok(())
     }
+}
 
+/// Run authd with an already loaded and expanded config, until every listener has stopped.
+pub async fn serve(config_file: crate::AuthdConfig) -> anyhow::Result<()> {
+    let mut running = Running::start(config_file).await?;
+    while running.set.join_next().await.is_some() {}
     Ok(())
 }
+
+/// Like [`serve`], but reloading the config from `config_path` on SIGHUP.
+pub async fn serve_with_reload(
+    config_file: crate::AuthdConfig,
+    config_path: PathBuf,
+) -> anyhow::Result<()> {
+    let mut running = Running::start(config_file).await?;
+    let mut hangups = signal(SignalKind::hangup())?;
+    loop {
+        tokio::select! {
+            Some(()) = hangups.recv() => match running.reload(&config_path).await {
+                Ok(()) => tracing::info!("reloaded {}", config_path.display()),
+                Err(e) => tracing::error!(
+                    "not reloading {}, keeping the old config: {:#}",
+                    config_path.display(),
+                    e
+                ),
+            },
+            joined = running.set.join_next() => if joined.is_none() {
+                return Ok(());
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// State with one account, `alice`, who is enrolled in TOTP, all kept in `dir`.
+    pub(super) fn test_state(dir: &std::path::Path) -> Arc<Mutex<SharedState>> {
+        let example_state_dir =
+            std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("../example_configs/state-dir");
+        std::fs::copy(example_state_dir.join("opaque"), dir.join("opaque")).unwrap();
+        std::fs::write(
+            dir.join("passwd"),
+            "alice:x:2001:2001::/home/alice:/bin/sh\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("group"), "alice:x:2001:\n").unwrap();
+        std::fs::write(dir.join("shadow"), "alice:*:19000:0:99999:7:::\n").unwrap();
+        std::fs::write(
+            dir.join("totp.toml"),
+            "[users.alice]\nsecret = 'GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ'\n",
+        )
+        .unwrap();
+        let config: crate::AuthdConfig = toml::from_str(&format!(
+            "bind_addrs = []
+opaque_server_setup = '{dir}/opaque'
+opaque_cookies = '{dir}'
+authoritative_name = 'localhost'
+passwd_file = '{dir}/passwd'
+group_file = '{dir}/group'
+shadow_file = '{dir}/shadow'
+totp_file = '{dir}/totp.toml'
+cert = '{dir}/cert.der'
+key = '{dir}/key.der'
+",
+            dir = dir.display()
+        ))
+        .unwrap();
+        Arc::new(Mutex::new(SharedState::new(&config).unwrap()))
+    }
+
+    /// Append `lines` to one of the files `test_state` made in `dir`, and make sure `state` reads
+    /// them.
+    async fn append(
+        state: &Arc<Mutex<SharedState>>,
+        dir: &std::path::Path,
+        file: &str,
+        lines: &str,
+    ) {
+        use std::io::Write;
+        let mut f = std::fs::OpenOptions::new()
+            .append(true)
+            .open(dir.join(file))
+            .unwrap();
+        f.write_all(lines.as_bytes()).unwrap();
+        // in case the mtime didn't move
+        let mut state = state.lock().await;
+        state.files.passwd.latest_ts = None;
+        state.files.group.latest_ts = None;
+        state.files.shadow.latest_ts = None;
+        state.files.refresh().unwrap();
+    }
+
+    /// A connection to `state` that hasn't logged in.
+    fn anonymous(state: &Arc<Mutex<SharedState>>) -> Arc<Mutex<AuthdSession>> {
+        Arc::new(Mutex::new(AuthdSession::new(
+            state.clone(),
+            "127.0.0.1:1".parse().unwrap(),
+        )))
+    }
+
+    /// A connection to `state` logged in as `username` with a password.
+    fn logged_in(state: &Arc<Mutex<SharedState>>, username: &str) -> Arc<Mutex<AuthdSession>> {
+        let mut session = AuthdSession::new(state.clone(), "127.0.0.1:1".parse().unwrap());
+        session.purported_username = Some(username.into());
+        session.session_key = Some(Zeroizing::new(vec![1; 64]));
+        Arc::new(Mutex::new(session))
+    }
+
+    /// A session for `alice` with her password checked but no TOTP code yet.
+    fn session_waiting_for_totp(dir: &std::path::Path) -> Arc<Mutex<AuthdSession>> {
+        let mut session = AuthdSession::new(test_state(dir), "127.0.0.1:1".parse().unwrap());
+        session.purported_username = Some("alice".into());
+        session.pending_session_key = Some(Zeroizing::new(vec![1; 64]));
+        Arc::new(Mutex::new(session))
+    }
+
+    #[tokio::test]
+    async fn a_wrong_totp_code_ends_the_login() {
+        let dir = tempfile::tempdir().unwrap();
+        let session = session_waiting_for_totp(dir.path());
+
+        // seven digits, so it can't happen to be right
+        let wrong = session
+            .clone()
+            .verify_totp(tarpc::context::current(), "1000000".into())
+            .await;
+        assert!(matches!(wrong, Err(RpcError::AuthenticationFailure)));
+        {
+            let slf = session.lock().await;
+            assert!(slf.pending_session_key.is_none());
+            assert!(slf.purported_username.is_none());
+            assert!(slf.session_key.is_none());
+        }
+
+        // another guess needs the password first
+        let again = session
+            .clone()
+            .verify_totp(tarpc::context::current(), "000001".into())
+            .await;
+        assert!(matches!(again, Err(RpcError::NotAuthorized)));
+        let whoami = session.whoami(tarpc::context::current()).await;
+        assert_eq!(whoami.username, None);
+    }
+
+    /// `$6$` hash of `hunter2`.
+    const BOB_HASH: &str = "$6$saltsalt$8iYtNHxjWRl.NF6oNZ5tF.iKFlQREaXBLlSmZKP6dy9l5z3vsooWNW0/GZ6Nej73/TFug6pIPSqbJoCT6dfnj.";
+
+    /// `test_state` with legacy logins on, `bob` who only has `BOB_HASH`, and a host `lab1`.
+    async fn legacy_state(dir: &std::path::Path) -> Arc<Mutex<SharedState>> {
+        let state = test_state(dir);
+        state.lock().await.config.legacy_login = true;
+        append(
+            &state,
+            dir,
+            "passwd",
+            "bob:x:2002:2002::/home/bob:/bin/sh\n",
+        )
+        .await;
+        append(
+            &state,
+            dir,
+            "group",
+            "bob:x:2002:\nauth-hosts:x:3001:lab1\n",
+        )
+        .await;
+        append(
+            &state,
+            dir,
+            "shadow",
+            &format!("bob:{}:19000:0:99999:7:::\n", BOB_HASH),
+        )
+        .await;
+        state
+    }
+
+    /// The client's side of a legacy migration, as `client_migrate_legacy` does it.
+    async fn migrate(
+        session: &Arc<Mutex<AuthdSession>>,
+        username: &str,
+        password: &[u8],
+    ) -> Result<(), RpcError> {
+        let mut rng = OsRng;
+        let reg =
+            opaque_ke::ClientRegistration::<DefaultCipherSuite>::start(&mut rng, password).unwrap();
+        let resp = session
+            .clone()
+            .start_legacy_migration(tarpc::context::current(), username.into(), reg.message)
+            .await?;
+        let registered = reg
+            .state
+            .finish(
+                &mut rng,
+                password,
+                resp,
+                opaque_ke::ClientRegistrationFinishParameters::default(),
+            )
+            .unwrap();
+        let login =
+            opaque_ke::ClientLogin::<DefaultCipherSuite>::start(&mut rng, password).unwrap();
+        let resp = session
+            .clone()
+            .continue_legacy_migration(tarpc::context::current(), registered.message, login.message)
+            .await?;
+        let finished = login
+            .state
+            .finish(
+                password,
+                resp,
+                opaque_ke::ClientLoginFinishParameters::default(),
+            )
+            .unwrap();
+        let sealed = crate::legacy::seal_password(&finished.session_key, password);
+        session
+            .clone()
+            .finish_legacy_migration(tarpc::context::current(), finished.message, sealed)
+            .await
+    }
+
+    /// The client's side of an OPAQUE login, as `client_login` does it.
+    async fn login(
+        session: &Arc<Mutex<AuthdSession>>,
+        username: &str,
+        password: &[u8],
+    ) -> Result<LoginStatus, RpcError> {
+        let mut rng = OsRng;
+        let login =
+            opaque_ke::ClientLogin::<DefaultCipherSuite>::start(&mut rng, password).unwrap();
+        let resp = session
+            .clone()
+            .start_login(tarpc::context::current(), username.into(), login.message)
+            .await?;
+        let finished = login
+            .state
+            .finish(
+                password,
+                resp,
+                opaque_ke::ClientLoginFinishParameters::default(),
+            )
+            .map_err(|_| RpcError::AuthenticationFailure)?;
+        session
+            .clone()
+            .finish_login(tarpc::context::current(), finished.message)
+            .await
+    }
+
+    #[tokio::test]
+    async fn migrating_replaces_the_hash_with_a_credential() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = legacy_state(dir.path()).await;
+
+        migrate(&anonymous(&state), "bob", b"hunter2")
+            .await
+            .unwrap();
+        assert!(dir.path().join("bob").exists());
+        let shadow = std::fs::read_to_string(dir.path().join("shadow")).unwrap();
+        assert!(shadow.contains("bob:!:19000:"), "{}", shadow);
+        assert!(!shadow.contains(BOB_HASH));
+
+        // and the password works with OPAQUE from now on
+        let status = login(&anonymous(&state), "bob", b"hunter2").await.unwrap();
+        assert_eq!(status, LoginStatus::LoggedIn);
+    }
+
+    #[tokio::test]
+    async fn migrating_with_the_wrong_password_changes_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = legacy_state(dir.path()).await;
+
+        let wrong = migrate(&anonymous(&state), "bob", b"hunter3").await;
+        assert!(matches!(wrong, Err(RpcError::AuthenticationFailure)));
+        assert!(!dir.path().join("bob").exists());
+        let shadow = std::fs::read_to_string(dir.path().join("shadow")).unwrap();
+        assert!(shadow.contains(BOB_HASH));
+        let lab1 = logged_in(&state, "lab1");
+        let available = lab1
+            .legacy_login_available(tarpc::context::current(), "bob".into())
+            .await;
+        assert!(matches!(available, Ok(true)));
+    }
+
+    #[tokio::test]
+    async fn migrating_twice_fails_like_a_wrong_password() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = legacy_state(dir.path()).await;
+        migrate(&anonymous(&state), "bob", b"hunter2")
+            .await
+            .unwrap();
+        let credential = std::fs::read(dir.path().join("bob")).unwrap();
+
+        let again = migrate(&anonymous(&state), "bob", b"hunter2").await;
+        assert!(matches!(again, Err(RpcError::AuthenticationFailure)));
+        assert_eq!(std::fs::read(dir.path().join("bob")).unwrap(), credential);
+        // and so does someone who never had a hash, or doesn't exist
+        for username in ["alice", "nobody"] {
+            let result = migrate(&anonymous(&state), username, b"hunter2").await;
+            assert!(matches!(result, Err(RpcError::AuthenticationFailure)));
+            assert!(!dir.path().join(username).exists());
+        }
+    }
+
+    #[tokio::test]
+    async fn only_hosts_may_ask_who_can_migrate() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = legacy_state(dir.path()).await;
+
+        let nobody = anonymous(&state)
+            .legacy_login_available(tarpc::context::current(), "bob".into())
+            .await;
+        assert!(matches!(nobody, Err(RpcError::NotAuthorized)));
+        let alice = logged_in(&state, "alice")
+            .legacy_login_available(tarpc::context::current(), "bob".into())
+            .await;
+        assert!(matches!(alice, Err(RpcError::NotAuthorized)));
+
+        let lab1 = logged_in(&state, "lab1");
+        for (username, available) in [("bob", true), ("alice", false), ("nobody", false)] {
+            let result = lab1
+                .clone()
+                .legacy_login_available(tarpc::context::current(), username.into())
+                .await;
+            assert_eq!(result.unwrap(), available, "{}", username);
+        }
+    }
+
+    #[tokio::test]
+    async fn too_many_hours_are_refused() {
+        assert_eq!(hours_in_secs(MAX_HOURS).unwrap(), MAX_HOURS * 60 * 60);
+        assert!(matches!(
+            hours_in_secs(MAX_HOURS + 1),
+            Err(RpcError::Invalid(_))
+        ));
+        assert!(matches!(hours_in_secs(u64::MAX), Err(RpcError::Invalid(_))));
+
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path());
+        state.lock().await.config.session_hours = Some(u64::MAX);
+        let token = logged_in(&state, "alice")
+            .create_session_token(tarpc::context::current())
+            .await;
+        assert!(matches!(token, Err(RpcError::Invalid(_))));
+    }
+
+    /// `test_state` with the example SSH CA.
+    async fn ssh_ca_state(dir: &std::path::Path) -> Arc<Mutex<SharedState>> {
+        let state = test_state(dir);
+        let ca = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../example_configs/state-dir/ssh_ca");
+        state.lock().await.ssh_ca = Some(ssh_key::PrivateKey::read_openssh_file(&ca).unwrap());
+        state
+    }
+
+    const ALICE_SSH_KEY: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIJe2xu9/nZU730GxQNPwSSCCKVf2nkRhuytc4+Pnwpaf alice";
+
+    #[tokio::test]
+    async fn user_certificates_are_for_the_username_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = ssh_ca_state(dir.path()).await;
+        append(&state, dir.path(), "group", "wheel:x:10:alice\n").await;
+
+        let cert = logged_in(&state, "alice")
+            .sign_ssh_user_key(tarpc::context::current(), ALICE_SSH_KEY.into())
+            .await
+            .unwrap();
+        let cert = ssh_key::Certificate::from_openssh(&cert).unwrap();
+        assert_eq!(cert.key_id(), "alice");
+        assert_eq!(cert.valid_principals(), ["alice".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn resumed_sessions_get_no_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = ssh_ca_state(dir.path()).await;
+
+        let resumed = logged_in(&state, "alice");
+        resumed.lock().await.resumed_until = Some(u64::MAX);
+        let cert = resumed
+            .sign_ssh_user_key(tarpc::context::current(), ALICE_SSH_KEY.into())
+            .await;
+        assert!(matches!(cert, Err(RpcError::NotAuthorized)));
+        let cert = anonymous(&state)
+            .sign_ssh_user_key(tarpc::context::current(), ALICE_SSH_KEY.into())
+            .await;
+        assert!(matches!(cert, Err(RpcError::NotAuthorized)));
+    }
+
+    /// Write a config for the files `test_state` made in `dir` to `dir/authd.toml`, reading passwd
+    /// from `passwd` and serving the example certificate and key whose names start with `tls`.
+    fn write_config(dir: &std::path::Path, passwd: &str, tls: &str) -> PathBuf {
+        let example_state_dir =
+            std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("../example_configs/state-dir");
+        let path = dir.join("authd.toml");
+        std::fs::write(
+            &path,
+            format!(
+                "bind_addrs = ['127.0.0.1:0']
+opaque_server_setup = '{dir}/opaque'
+opaque_cookies = '{dir}'
+authoritative_name = 'localhost'
+passwd_file = '{dir}/{passwd}'
+group_file = '{dir}/group'
+shadow_file = '{dir}/shadow'
+cert = '{example}/{tls}cert.der'
+key = '{example}/{tls}key.der'
+",
+                dir = dir.display(),
+                example = example_state_dir.display(),
+            ),
+        )
+        .unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn reloading_swaps_the_config_files_and_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        test_state(dir.path());
+        std::fs::write(
+            dir.path().join("passwd2"),
+            "bob:x:2002:2002::/home/bob:/bin/sh\n",
+        )
+        .unwrap();
+        let path = write_config(dir.path(), "passwd", "");
+        let mut running = Running::start(crate::AuthdConfig::load(&path).unwrap())
+            .await
+            .unwrap();
+        let (token, _) = logged_in(&running.state, "alice")
+            .create_session_token(tarpc::context::current())
+            .await
+            .unwrap();
+
+        write_config(dir.path(), "passwd2", "rotated-");
+        running.reload(&path).await.unwrap();
+        assert!(running.config.passwd_file.ends_with("passwd2"));
+        let rotated = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../example_configs/state-dir/rotated-cert.der");
+        assert_eq!(running.tls.cert, std::fs::read(rotated).unwrap());
+        {
+            let mut state = running.state.lock().await;
+            state.files.refresh().unwrap();
+            let names: Vec<_> = state.files.passwd.data.iter().map(|p| &p.name).collect();
+            assert_eq!(names, ["bob"]);
+        }
+        // sessions from before the reload carry on
+        let resumed = anonymous(&running.state)
+            .resume_session(tarpc::context::current(), token)
+            .await;
+        assert_eq!(resumed.unwrap(), "alice");
+    }
+
+    #[tokio::test]
+    async fn a_broken_config_leaves_the_old_one_running() {
+        let dir = tempfile::tempdir().unwrap();
+        test_state(dir.path());
+        let path = write_config(dir.path(), "passwd", "");
+        let mut running = Running::start(crate::AuthdConfig::load(&path).unwrap())
+            .await
+            .unwrap();
+        let cert = running.tls.cert.clone();
+
+        // a passwd file that isn't there
+        write_config(dir.path(), "passwd2", "rotated-");
+        assert!(running.reload(&path).await.is_err());
+        std::fs::write(&path, "bind_addrs = [").unwrap();
+        assert!(running.reload(&path).await.is_err());
+
+        assert!(running.config.passwd_file.ends_with("/passwd"));
+        assert_eq!(running.tls.cert, cert);
+        assert_eq!(running.listeners.len(), 1);
+        let mut state = running.state.lock().await;
+        state.files.refresh().unwrap();
+        let names: Vec<_> = state.files.passwd.data.iter().map(|p| &p.name).collect();
+        assert_eq!(names, ["alice"]);
+    }
+
+    #[tokio::test]
+    async fn session_tokens_log_in_until_they_expire() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path());
+
+        let (token, expires) = logged_in(&state, "alice")
+            .create_session_token(tarpc::context::current())
+            .await
+            .unwrap();
+        assert!(expires > unix_time());
+        let resumed = anonymous(&state);
+        let username = resumed
+            .clone()
+            .resume_session(tarpc::context::current(), token.clone())
+            .await;
+        assert_eq!(username.unwrap(), "alice");
+        let me = resumed.whoami(tarpc::context::current()).await;
+        assert_eq!(me.username.as_deref(), Some("alice"));
+
+        // the same token once its time is up
+        state
+            .lock()
+            .await
+            .sessions
+            .get_mut(&crate::reset::hash_token(&token))
+            .unwrap()
+            .expires = unix_time() - 1;
+        let expired = anonymous(&state)
+            .resume_session(tarpc::context::current(), token)
+            .await;
+        assert!(matches!(expired, Err(RpcError::AuthenticationFailure)));
+        let unknown = anonymous(&state)
+            .resume_session(tarpc::context::current(), crate::reset::generate_token())
+            .await;
+        assert!(matches!(unknown, Err(RpcError::AuthenticationFailure)));
+        let nobody = anonymous(&state)
+            .create_session_token(tarpc::context::current())
+            .await;
+        assert!(matches!(nobody, Err(RpcError::NotAuthorized)));
+    }
+
+    #[tokio::test]
+    async fn tokens_from_a_resumed_session_expire_with_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path());
+        let (token, _) = logged_in(&state, "alice")
+            .create_session_token(tarpc::context::current())
+            .await
+            .unwrap();
+        let until = unix_time() + 60;
+        state
+            .lock()
+            .await
+            .sessions
+            .get_mut(&crate::reset::hash_token(&token))
+            .unwrap()
+            .expires = until;
+
+        let resumed = anonymous(&state);
+        resumed
+            .clone()
+            .resume_session(tarpc::context::current(), token)
+            .await
+            .unwrap();
+        let (_, expires) = resumed
+            .create_session_token(tarpc::context::current())
+            .await
+            .unwrap();
+        assert_eq!(expires, until);
+    }
+
+    /// `test_state` with an admin, `root`, and reset tokens and invites kept in `dir`.
+    async fn admin_state(dir: &std::path::Path) -> Arc<Mutex<SharedState>> {
+        let state = test_state(dir);
+        append(&state, dir, "group", "auth-admins:x:3000:root\n").await;
+        let mut locked = state.lock().await;
+        locked.resets = Some(TomlFile::secret(dir.join("resets.toml")));
+        locked.invites = Some(TomlFile::secret(dir.join("invites.toml")));
+        drop(locked);
+        state
+    }
+
+    /// The client's side of redeeming a reset token, as `client_redeem_reset` does it.
+    async fn redeem_reset(
+        session: &Arc<Mutex<AuthdSession>>,
+        token: &str,
+        password: &[u8],
+    ) -> Result<String, RpcError> {
+        let mut rng = OsRng;
+        let reg =
+            opaque_ke::ClientRegistration::<DefaultCipherSuite>::start(&mut rng, password).unwrap();
+        let (username, resp) = session
+            .clone()
+            .start_reset_registration(tarpc::context::current(), token.into(), reg.message)
+            .await?;
+        let registered = reg
+            .state
+            .finish(
+                &mut rng,
+                password,
+                resp,
+                opaque_ke::ClientRegistrationFinishParameters::default(),
+            )
+            .unwrap();
+        session
+            .clone()
+            .finish_reset_registration(tarpc::context::current(), registered.message)
+            .await?;
+        Ok(username)
+    }
+
+    #[tokio::test]
+    async fn reset_tokens_set_a_new_password_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = admin_state(dir.path()).await;
+
+        let token = logged_in(&state, "root")
+            .issue_reset_token(tarpc::context::current(), "alice".into())
+            .await
+            .unwrap();
+        let username = redeem_reset(&anonymous(&state), &token, b"correct horse")
+            .await
+            .unwrap();
+        assert_eq!(username, "alice");
+        // alice is enrolled in TOTP, so the password is only the first half
+        let status = login(&anonymous(&state), "alice", b"correct horse").await;
+        assert_eq!(status.unwrap(), LoginStatus::NeedTotp);
+
+        let again = redeem_reset(&anonymous(&state), &token, b"battery staple").await;
+        assert!(matches!(again, Err(RpcError::AuthenticationFailure)));
+        let status = login(&anonymous(&state), "alice", b"correct horse").await;
+        assert_eq!(status.unwrap(), LoginStatus::NeedTotp);
+    }
+
+    #[tokio::test]
+    async fn only_admins_issue_reset_tokens_for_real_accounts() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = admin_state(dir.path()).await;
+
+        let alice = logged_in(&state, "alice")
+            .issue_reset_token(tarpc::context::current(), "alice".into())
+            .await;
+        assert!(matches!(alice, Err(RpcError::NotAuthorized)));
+        let nobody = logged_in(&state, "root")
+            .issue_reset_token(tarpc::context::current(), "nobody".into())
+            .await;
+        assert!(matches!(nobody, Err(RpcError::Invalid(_))));
+        let made_up = redeem_reset(
+            &anonymous(&state),
+            &crate::reset::generate_token(),
+            b"correct horse",
+        )
+        .await;
+        assert!(matches!(made_up, Err(RpcError::AuthenticationFailure)));
+    }
+
+    #[tokio::test]
+    async fn expired_reset_tokens_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = admin_state(dir.path()).await;
+        let token = logged_in(&state, "root")
+            .issue_reset_token(tarpc::context::current(), "alice".into())
+            .await
+            .unwrap();
+        {
+            let mut state = state.lock().await;
+            let resets = state.resets().unwrap();
+            let mut data = resets.data.clone();
+            for reset in data.tokens.values_mut() {
+                reset.expires = unix_time() - 1;
+            }
+            resets.save(data).unwrap();
+        }
+
+        let expired = redeem_reset(&anonymous(&state), &token, b"correct horse").await;
+        assert!(matches!(expired, Err(RpcError::AuthenticationFailure)));
+        assert!(!dir.path().join("alice").exists());
+    }
+
+    /// The client's side of enrolling with an invite, as `client_enroll` does it.
+    async fn enroll(
+        session: &Arc<Mutex<AuthdSession>>,
+        code: &str,
+        username: &str,
+        password: &[u8],
+    ) -> Result<Passwd, RpcError> {
+        let mut rng = OsRng;
+        let reg =
+            opaque_ke::ClientRegistration::<DefaultCipherSuite>::start(&mut rng, password).unwrap();
+        let resp = session
+            .clone()
+            .start_enrollment(
+                tarpc::context::current(),
+                code.into(),
+                username.into(),
+                reg.message,
+            )
+            .await?;
+        let registered = reg
+            .state
+            .finish(
+                &mut rng,
+                password,
+                resp,
+                opaque_ke::ClientRegistrationFinishParameters::default(),
+            )
+            .unwrap();
+        session
+            .clone()
+            .finish_enrollment(tarpc::context::current(), registered.message)
+            .await
+    }
+
+    #[tokio::test]
+    async fn invites_make_as_many_accounts_as_they_say() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = admin_state(dir.path()).await;
+        append(&state, dir.path(), "group", "lab:x:3100:\n").await;
+
+        let code = logged_in(&state, "root")
+            .create_invite(tarpc::context::current(), 1, 24, vec!["lab".into()])
+            .await
+            .unwrap();
+        let carol = enroll(&anonymous(&state), &code, "carol", b"correct horse")
+            .await
+            .unwrap();
+        assert_eq!(carol.name, "carol");
+        let group = std::fs::read_to_string(dir.path().join("group")).unwrap();
+        assert!(group.contains("lab:x:3100:carol"), "{}", group);
+        let status = login(&anonymous(&state), "carol", b"correct horse").await;
+        assert_eq!(status.unwrap(), LoginStatus::LoggedIn);
+
+        let used_up = enroll(&anonymous(&state), &code, "dave", b"battery staple").await;
+        assert!(matches!(used_up, Err(RpcError::AuthenticationFailure)));
+        assert!(!dir.path().join("dave").exists());
+    }
+
+    #[tokio::test]
+    async fn only_admins_make_invites_and_only_for_ordinary_groups() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = admin_state(dir.path()).await;
+
+        let alice = logged_in(&state, "alice")
+            .create_invite(tarpc::context::current(), 1, 24, vec![])
+            .await;
+        assert!(matches!(alice, Err(RpcError::NotAuthorized)));
+        let admins = logged_in(&state, "root")
+            .create_invite(tarpc::context::current(), 1, 24, vec!["auth-admins".into()])
+            .await;
+        assert!(matches!(admins, Err(RpcError::Invalid(_))));
+        let made_up = enroll(
+            &anonymous(&state),
+            &crate::reset::generate_token(),
+            "carol",
+            b"correct horse",
+        )
+        .await;
+        assert!(matches!(made_up, Err(RpcError::AuthenticationFailure)));
+        let passwd = std::fs::read_to_string(dir.path().join("passwd")).unwrap();
+        assert!(!passwd.contains("carol"));
+    }
+}
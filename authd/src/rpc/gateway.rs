@@ -0,0 +1,979 @@
+//! The HTTPS JSON API, for web tools that can't speak tarpc: the same lookups as [`Authd`], plus
+//! the admin calls the dashboards need, on `http_addr` with authd's own certificate.
+//!
+//! There is no state between requests besides logins in progress. Each request gets a fresh
+//! [`AuthdSession`], logged in from its `Authorization: Bearer` token if it has one, and goes
+//! through the same RPC handlers (and so the same read policy and audit log) as everything else.
+//! Tokens are the session tokens `auth login` uses, got by an OPAQUE login in two POSTs.
+
+use super::{
+    Authd, AuthdSession, DefaultCipherSuite, LoginStatus, NewAccount, RpcError, SharedState, WhoAmI,
+};
+use crate::{
+    access::AccessRules,
+    types::{CacheTtls, Group, Netgroup, NetgroupTriple, Passwd, Shadow},
+};
+use actix_web::{
+    dev::Server,
+    http::{header, StatusCode},
+    web::{self, Json},
+    App, HttpRequest, HttpResponse, HttpServer, ResponseError,
+};
+use opaque_ke::{CredentialFinalization, CredentialRequest};
+use schemars::{
+    gen::{SchemaGenerator, SchemaSettings},
+    schema::Schema,
+    visit::Visitor,
+    JsonSchema,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tarpc::context;
+use tokio::sync::Mutex;
+use tokio_rustls::rustls;
+
+type Session = Arc<Mutex<AuthdSession>>;
+
+/// How long a login may sit between `login/start` and `login/finish`.
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How many logins may be between `login/start` and `login/finish` at once. Past that, new ones
+/// get a 503 until some finish or time out.
+const MAX_LOGINS: usize = 1024;
+
+struct Gateway {
+    state: Arc<Mutex<SharedState>>,
+    /// Sessions partway through an OPAQUE login, by login id.
+    logins: std::sync::Mutex<HashMap<String, (Session, Instant)>>,
+}
+
+impl Gateway {
+    /// A session for one request, logged in if it came with a bearer token.
+    async fn session(&self, req: &HttpRequest) -> Result<Session, ApiError> {
+        let peer_addr = req.peer_addr().unwrap_or_else(|| ([0, 0, 0, 0], 0).into());
+        let mut session = AuthdSession::new(self.state.clone(), peer_addr);
+        if let Some(token) = bearer(req) {
+            if session.resume(token.into()).await.is_none() {
+                tracing::info!("unknown or expired bearer token from {}", peer_addr);
+                return Err(RpcError::AuthenticationFailure.into());
+            }
+        }
+        Ok(Arc::new(Mutex::new(session)))
+    }
+
+    /// The logins in progress, once expired ones are dropped, if there is room for another.
+    fn room_for_login(
+        &self,
+    ) -> Result<std::sync::MutexGuard<'_, HashMap<String, (Session, Instant)>>, ApiError> {
+        let mut logins = self.logins.lock().unwrap();
+        logins.retain(|_, (_, started)| started.elapsed() < LOGIN_TIMEOUT);
+        if logins.len() >= MAX_LOGINS {
+            tracing::warn!("{} logins in progress, turning another away", logins.len());
+            return Err(ApiError::Busy);
+        }
+        Ok(logins)
+    }
+}
+
+fn bearer(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+/// What a request gets back when it doesn't work, as `{"error": "..."}`.
+#[derive(Debug)]
+enum ApiError {
+    Rpc(RpcError),
+    NotFound,
+    /// Too many logins in progress.
+    Busy,
+}
+
+#[derive(Serialize, JsonSchema)]
+#[schemars(rename = "Error")]
+struct ErrorBody {
+    error: String,
+}
+
+impl From<RpcError> for ApiError {
+    fn from(e: RpcError) -> Self {
+        ApiError::Rpc(e)
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiError::Rpc(e) => e.fmt(f),
+            ApiError::NotFound => write!(f, "not found"),
+            ApiError::Busy => write!(f, "too many logins in progress, try again later"),
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::Rpc(RpcError::NotAuthorized) => StatusCode::FORBIDDEN,
+            ApiError::Rpc(RpcError::AuthenticationFailure) => StatusCode::UNAUTHORIZED,
+            ApiError::Rpc(RpcError::NotConfigured) => StatusCode::NOT_IMPLEMENTED,
+            ApiError::Rpc(RpcError::Invalid(_)) => StatusCode::BAD_REQUEST,
+            ApiError::Rpc(RpcError::PasswordChangeTooSoon) => StatusCode::BAD_REQUEST,
+            ApiError::NotFound => StatusCode::NOT_FOUND,
+            ApiError::Busy => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(ErrorBody {
+            error: self.to_string(),
+        })
+    }
+}
+
+type ApiResult<T> = Result<Json<T>, ApiError>;
+
+fn found<T>(entry: Option<T>) -> ApiResult<T> {
+    entry.map(Json).ok_or(ApiError::NotFound)
+}
+
+fn decode(what: &str, b64: &str) -> Result<Vec<u8>, RpcError> {
+    base64::decode(b64).map_err(|e| RpcError::Invalid(format!("{} is not base64: {}", what, e)))
+}
+
+async fn all_passwd(gw: web::Data<Gateway>, req: HttpRequest) -> ApiResult<Vec<Passwd>> {
+    let session = gw.session(&req).await?;
+    Ok(Json(session.get_all_passwd(context::current()).await?))
+}
+
+async fn passwd_by_name(
+    gw: web::Data<Gateway>,
+    req: HttpRequest,
+    name: web::Path<String>,
+) -> ApiResult<Passwd> {
+    let session = gw.session(&req).await?;
+    found(
+        session
+            .get_passwd_by_name(context::current(), name.into_inner())
+            .await?,
+    )
+}
+
+async fn passwd_by_uid(
+    gw: web::Data<Gateway>,
+    req: HttpRequest,
+    uid: web::Path<u32>,
+) -> ApiResult<Passwd> {
+    let session = gw.session(&req).await?;
+    found(
+        session
+            .get_passwd_by_uid(context::current(), uid.into_inner())
+            .await?,
+    )
+}
+
+async fn all_groups(gw: web::Data<Gateway>, req: HttpRequest) -> ApiResult<Vec<Group>> {
+    let session = gw.session(&req).await?;
+    Ok(Json(session.get_all_groups(context::current()).await?))
+}
+
+async fn group_by_name(
+    gw: web::Data<Gateway>,
+    req: HttpRequest,
+    name: web::Path<String>,
+) -> ApiResult<Group> {
+    let session = gw.session(&req).await?;
+    found(
+        session
+            .get_group_by_name(context::current(), name.into_inner())
+            .await?,
+    )
+}
+
+async fn group_by_gid(
+    gw: web::Data<Gateway>,
+    req: HttpRequest,
+    gid: web::Path<u32>,
+) -> ApiResult<Group> {
+    let session = gw.session(&req).await?;
+    found(
+        session
+            .get_group_by_gid(context::current(), gid.into_inner())
+            .await?,
+    )
+}
+
+async fn all_shadow(gw: web::Data<Gateway>, req: HttpRequest) -> ApiResult<Vec<Shadow>> {
+    let session = gw.session(&req).await?;
+    Ok(Json(session.get_all_shadow(context::current()).await?))
+}
+
+async fn shadow_by_name(
+    gw: web::Data<Gateway>,
+    req: HttpRequest,
+    name: web::Path<String>,
+) -> ApiResult<Shadow> {
+    let session = gw.session(&req).await?;
+    found(
+        session
+            .get_shadow_by_name(context::current(), name.into_inner())
+            .await?,
+    )
+}
+
+async fn all_netgroups(gw: web::Data<Gateway>, req: HttpRequest) -> ApiResult<Vec<Netgroup>> {
+    let session = gw.session(&req).await?;
+    Ok(Json(session.get_all_netgroups(context::current()).await?))
+}
+
+async fn netgroup_by_name(
+    gw: web::Data<Gateway>,
+    req: HttpRequest,
+    name: web::Path<String>,
+) -> ApiResult<Vec<NetgroupTriple>> {
+    let session = gw.session(&req).await?;
+    found(
+        session
+            .get_netgroup_by_name(context::current(), name.into_inner())
+            .await?,
+    )
+}
+
+async fn cache_ttls(gw: web::Data<Gateway>, req: HttpRequest) -> ApiResult<CacheTtls> {
+    let session = gw.session(&req).await?;
+    Ok(Json(session.get_cache_ttls(context::current()).await))
+}
+
+async fn whoami(gw: web::Data<Gateway>, req: HttpRequest) -> ApiResult<WhoAmI> {
+    let session = gw.session(&req).await?;
+    Ok(Json(session.whoami(context::current()).await))
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct StartLogin {
+    username: String,
+    /// Base64 of the serialized `CredentialRequest`.
+    request: String,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct LoginStarted {
+    login_id: String,
+    /// Base64 of the serialized `CredentialResponse`.
+    response: String,
+}
+
+async fn start_login(
+    gw: web::Data<Gateway>,
+    req: HttpRequest,
+    body: Json<StartLogin>,
+) -> ApiResult<LoginStarted> {
+    let StartLogin { username, request } = body.into_inner();
+    let request =
+        CredentialRequest::<DefaultCipherSuite>::deserialize(&decode("request", &request)?)
+            .map_err(|e| RpcError::Invalid(format!("bad credential request: {:?}", e)))?;
+    gw.room_for_login()?;
+    let session = gw.session(&req).await?;
+    let response = session
+        .clone()
+        .start_login(context::current(), username, request)
+        .await?;
+    let login_id = crate::reset::generate_token();
+    let mut logins = gw.room_for_login()?;
+    logins.insert(login_id.clone(), (session, Instant::now()));
+    Ok(Json(LoginStarted {
+        login_id,
+        response: base64::encode(response.serialize()),
+    }))
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct FinishLogin {
+    login_id: String,
+    /// Base64 of the serialized `CredentialFinalization`.
+    finalization: String,
+    /// Needed if the account has TOTP.
+    totp_code: Option<String>,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct LoggedIn {
+    /// Goes in `Authorization: Bearer` from now on.
+    token: String,
+    /// Seconds since 1970.
+    expires: u64,
+}
+
+async fn finish_login(gw: web::Data<Gateway>, body: Json<FinishLogin>) -> ApiResult<LoggedIn> {
+    let FinishLogin {
+        login_id,
+        finalization,
+        totp_code,
+    } = body.into_inner();
+    let session = match gw.logins.lock().unwrap().remove(&login_id) {
+        Some((session, started)) if started.elapsed() < LOGIN_TIMEOUT => session,
+        _ => return Err(RpcError::AuthenticationFailure.into()),
+    };
+    let finalization = CredentialFinalization::<DefaultCipherSuite>::deserialize(&decode(
+        "finalization",
+        &finalization,
+    )?)
+    .map_err(|e| RpcError::Invalid(format!("bad credential finalization: {:?}", e)))?;
+    match session
+        .clone()
+        .finish_login(context::current(), finalization)
+        .await?
+    {
+        LoginStatus::LoggedIn => {}
+        LoginStatus::NeedTotp => {
+            let code = totp_code
+                .ok_or_else(|| RpcError::Invalid("this account needs a totp_code".into()))?;
+            session
+                .clone()
+                .verify_totp(context::current(), code)
+                .await?;
+        }
+        LoginStatus::NeedTotpEnrollment => {
+            return Err(RpcError::Invalid(
+                "this account has to enroll in TOTP with `auth totp enroll` first".into(),
+            )
+            .into())
+        }
+    }
+    let (token, expires) = session.create_session_token(context::current()).await?;
+    Ok(Json(LoggedIn { token, expires }))
+}
+
+async fn logout(gw: web::Data<Gateway>, req: HttpRequest) -> Result<HttpResponse, ApiError> {
+    let token = bearer(&req)
+        .ok_or(RpcError::AuthenticationFailure)?
+        .to_string();
+    let session = gw.session(&req).await?;
+    session
+        .revoke_session_token(context::current(), token)
+        .await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+async fn create_account(
+    gw: web::Data<Gateway>,
+    req: HttpRequest,
+    body: Json<NewAccount>,
+) -> ApiResult<Passwd> {
+    let session = gw.session(&req).await?;
+    Ok(Json(
+        session
+            .create_account(context::current(), body.into_inner())
+            .await?,
+    ))
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct NewInvite {
+    uses: u32,
+    hours: u64,
+    #[serde(default)]
+    groups: Vec<String>,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct Code {
+    code: String,
+}
+
+async fn create_invite(
+    gw: web::Data<Gateway>,
+    req: HttpRequest,
+    body: Json<NewInvite>,
+) -> ApiResult<Code> {
+    let NewInvite {
+        uses,
+        hours,
+        groups,
+    } = body.into_inner();
+    let session = gw.session(&req).await?;
+    let code = session
+        .create_invite(context::current(), uses, hours, groups)
+        .await?;
+    Ok(Json(Code { code }))
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct NewResetToken {
+    username: String,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct Token {
+    token: String,
+}
+
+async fn issue_reset_token(
+    gw: web::Data<Gateway>,
+    req: HttpRequest,
+    body: Json<NewResetToken>,
+) -> ApiResult<Token> {
+    let session = gw.session(&req).await?;
+    let token = session
+        .issue_reset_token(context::current(), body.into_inner().username)
+        .await?;
+    Ok(Json(Token { token }))
+}
+
+async fn get_access_rules(gw: web::Data<Gateway>, req: HttpRequest) -> ApiResult<AccessRules> {
+    let session = gw.session(&req).await?;
+    Ok(Json(session.get_access_rules(context::current()).await?))
+}
+
+async fn set_access_rules(
+    gw: web::Data<Gateway>,
+    req: HttpRequest,
+    body: Json<AccessRules>,
+) -> Result<HttpResponse, ApiError> {
+    let session = gw.session(&req).await?;
+    session
+        .set_access_rules(context::current(), body.into_inner())
+        .await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Serialize, JsonSchema)]
+struct Removed {
+    /// False if the account had no TOTP secret.
+    removed: bool,
+}
+
+async fn remove_totp(
+    gw: web::Data<Gateway>,
+    req: HttpRequest,
+    username: web::Path<String>,
+) -> ApiResult<Removed> {
+    let session = gw.session(&req).await?;
+    let removed = session
+        .remove_totp(context::current(), username.into_inner())
+        .await?;
+    Ok(Json(Removed { removed }))
+}
+
+/// Writes the schema of `T` into the generator's definitions, giving back a reference to it.
+fn schema<T: JsonSchema>(gen: &mut SchemaGenerator) -> Schema {
+    gen.subschema_for::<T>()
+}
+
+type SchemaFn = fn(&mut SchemaGenerator) -> Schema;
+
+/// One route: what serves it, and what the OpenAPI description says about it.
+struct Endpoint {
+    method: &'static str,
+    path: &'static str,
+    summary: &'static str,
+    /// Needs a bearer token for an admin.
+    admin: bool,
+    request: Option<SchemaFn>,
+    response: Option<SchemaFn>,
+    /// Attaches the handler to a route for `method`.
+    handler: fn(web::Route) -> web::Route,
+}
+
+const fn endpoint(
+    method: &'static str,
+    path: &'static str,
+    summary: &'static str,
+    request: Option<SchemaFn>,
+    response: Option<SchemaFn>,
+    handler: fn(web::Route) -> web::Route,
+) -> Endpoint {
+    Endpoint {
+        method,
+        path,
+        summary,
+        admin: false,
+        request,
+        response,
+        handler,
+    }
+}
+
+const fn admin(
+    method: &'static str,
+    path: &'static str,
+    summary: &'static str,
+    request: Option<SchemaFn>,
+    response: Option<SchemaFn>,
+    handler: fn(web::Route) -> web::Route,
+) -> Endpoint {
+    Endpoint {
+        admin: true,
+        ..endpoint(method, path, summary, request, response, handler)
+    }
+}
+
+impl Endpoint {
+    fn route(&self) -> web::Route {
+        let route = match self.method {
+            "get" => web::get(),
+            "post" => web::post(),
+            "put" => web::put(),
+            "delete" => web::delete(),
+            other => unreachable!("no {} endpoints", other),
+        };
+        (self.handler)(route)
+    }
+}
+
+/// Everything under `/v1`, which [`routes`] serves and [`openapi`] describes.
+const ENDPOINTS: &[Endpoint] = &[
+    endpoint(
+        "get",
+        "/passwd",
+        "Every passwd entry.",
+        None,
+        Some(schema::<Vec<Passwd>>),
+        |r| r.to(all_passwd),
+    ),
+    endpoint(
+        "get",
+        "/passwd/{name}",
+        "A passwd entry by name.",
+        None,
+        Some(schema::<Passwd>),
+        |r| r.to(passwd_by_name),
+    ),
+    endpoint(
+        "get",
+        "/passwd/uid/{uid}",
+        "A passwd entry by uid.",
+        None,
+        Some(schema::<Passwd>),
+        |r| r.to(passwd_by_uid),
+    ),
+    endpoint(
+        "get",
+        "/group",
+        "Every group.",
+        None,
+        Some(schema::<Vec<Group>>),
+        |r| r.to(all_groups),
+    ),
+    endpoint(
+        "get",
+        "/group/{name}",
+        "A group by name.",
+        None,
+        Some(schema::<Group>),
+        |r| r.to(group_by_name),
+    ),
+    endpoint(
+        "get",
+        "/group/gid/{gid}",
+        "A group by gid.",
+        None,
+        Some(schema::<Group>),
+        |r| r.to(group_by_gid),
+    ),
+    endpoint(
+        "get",
+        "/shadow",
+        "Every shadow entry.",
+        None,
+        Some(schema::<Vec<Shadow>>),
+        |r| r.to(all_shadow),
+    ),
+    endpoint(
+        "get",
+        "/shadow/{name}",
+        "A shadow entry by name.",
+        None,
+        Some(schema::<Shadow>),
+        |r| r.to(shadow_by_name),
+    ),
+    endpoint(
+        "get",
+        "/netgroup",
+        "Every netgroup.",
+        None,
+        Some(schema::<Vec<Netgroup>>),
+        |r| r.to(all_netgroups),
+    ),
+    endpoint(
+        "get",
+        "/netgroup/{name}",
+        "The triples a netgroup expands to.",
+        None,
+        Some(schema::<Vec<NetgroupTriple>>),
+        |r| r.to(netgroup_by_name),
+    ),
+    endpoint(
+        "get",
+        "/cache-ttls",
+        "How long clients should cache lookups.",
+        None,
+        Some(schema::<CacheTtls>),
+        |r| r.to(cache_ttls),
+    ),
+    endpoint(
+        "get",
+        "/whoami",
+        "Who the bearer token is for.",
+        None,
+        Some(schema::<WhoAmI>),
+        |r| r.to(whoami),
+    ),
+    endpoint(
+        "post",
+        "/login/start",
+        "First half of an OPAQUE login.",
+        Some(schema::<StartLogin>),
+        Some(schema::<LoginStarted>),
+        |r| r.to(start_login),
+    ),
+    endpoint(
+        "post",
+        "/login/finish",
+        "Second half of an OPAQUE login, giving a bearer token.",
+        Some(schema::<FinishLogin>),
+        Some(schema::<LoggedIn>),
+        |r| r.to(finish_login),
+    ),
+    endpoint(
+        "post",
+        "/logout",
+        "Revoke the bearer token.",
+        None,
+        None,
+        |r| r.to(logout),
+    ),
+    admin(
+        "post",
+        "/accounts",
+        "Create an account with no credential yet.",
+        Some(schema::<NewAccount>),
+        Some(schema::<Passwd>),
+        |r| r.to(create_account),
+    ),
+    admin(
+        "post",
+        "/invites",
+        "Make an invite code.",
+        Some(schema::<NewInvite>),
+        Some(schema::<Code>),
+        |r| r.to(create_invite),
+    ),
+    admin(
+        "post",
+        "/reset-tokens",
+        "Issue a password reset token.",
+        Some(schema::<NewResetToken>),
+        Some(schema::<Token>),
+        |r| r.to(issue_reset_token),
+    ),
+    admin(
+        "get",
+        "/access-rules",
+        "Who may log in where.",
+        None,
+        Some(schema::<AccessRules>),
+        |r| r.to(get_access_rules),
+    ),
+    admin(
+        "put",
+        "/access-rules",
+        "Replace the access rules.",
+        Some(schema::<AccessRules>),
+        None,
+        |r| r.to(set_access_rules),
+    ),
+    admin(
+        "delete",
+        "/totp/{username}",
+        "Remove an account's TOTP secret.",
+        None,
+        Some(schema::<Removed>),
+        |r| r.to(remove_totp),
+    ),
+];
+
+/// Registers `/openapi.json` and every one of [`ENDPOINTS`].
+fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("/openapi.json", web::get().to(openapi_json));
+    for e in ENDPOINTS {
+        cfg.route(e.path, e.route());
+    }
+}
+
+/// One schema as it goes in the description, with the generator's OpenAPI fixups applied.
+fn schema_json(gen: &mut SchemaGenerator, schema: SchemaFn) -> Value {
+    let mut schema = schema(gen);
+    for visitor in gen.visitors_mut() {
+        visitor.visit_schema(&mut schema);
+    }
+    serde_json::to_value(schema).expect("schemas serialize")
+}
+
+/// The OpenAPI 3 description of [`ENDPOINTS`], with the schemas generated from the types the
+/// handlers take and give back.
+fn openapi() -> Value {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    let error_schema = schema_json(&mut gen, schema::<ErrorBody>);
+    let mut paths = serde_json::Map::new();
+    for e in ENDPOINTS {
+        let params: Vec<Value> = e
+            .path
+            .split('/')
+            .filter_map(|seg| seg.strip_prefix('{')?.strip_suffix('}'))
+            .map(|name| {
+                let ty = if name == "uid" || name == "gid" {
+                    "integer"
+                } else {
+                    "string"
+                };
+                json!({ "name": name, "in": "path", "required": true, "schema": { "type": ty } })
+            })
+            .collect();
+        let ok = match e.response {
+            Some(schema) => json!({
+                "200": {
+                    "description": "OK",
+                    "content": {
+                        "application/json": { "schema": schema_json(&mut gen, schema) },
+                    },
+                },
+            }),
+            None => json!({ "204": { "description": "Done" } }),
+        };
+        let mut responses = ok.as_object().cloned().unwrap_or_default();
+        let error = json!({
+            "description": "See the error",
+            "content": { "application/json": { "schema": error_schema } },
+        });
+        responses.insert("default".into(), error);
+        let mut op = json!({
+            "summary": e.summary,
+            "parameters": params,
+            "responses": responses,
+        });
+        if let Some(schema) = e.request {
+            op["requestBody"] = json!({
+                "required": true,
+                "content": { "application/json": { "schema": schema_json(&mut gen, schema) } },
+            });
+        }
+        if e.admin {
+            op["security"] = json!([{ "bearer": [] }]);
+            op["description"] = json!("Needs a bearer token for a member of the admins group.");
+        }
+        let path = paths
+            .entry(format!("/v1{}", e.path))
+            .or_insert_with(|| json!({}));
+        path[e.method] = op;
+    }
+    let mut schemas = gen.take_definitions();
+    for visitor in gen.visitors_mut() {
+        for schema in schemas.values_mut() {
+            visitor.visit_schema(schema);
+        }
+    }
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "authd",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": concat!(
+                "Directory lookups and admin calls. Requests without a bearer token are ",
+                "anonymous, and see what the read policy lets anyone see.",
+            ),
+        },
+        "paths": paths,
+        "components": {
+            "schemas": schemas,
+            "securitySchemes": {
+                "bearer": {
+                    "type": "http",
+                    "scheme": "bearer",
+                    "description": "A session token from POST /v1/login/finish.",
+                },
+            },
+        },
+        "security": [{}, { "bearer": [] }],
+    })
+}
+
+async fn openapi_json() -> HttpResponse {
+    HttpResponse::Ok().json(openapi())
+}
+
+/// Bind the API to `addr`, with TLS. It is served as long as the returned server is polled.
+pub(super) fn server(
+    addr: std::net::SocketAddr,
+    tls_config: Arc<rustls::ServerConfig>,
+    state: Arc<Mutex<SharedState>>,
+) -> anyhow::Result<Server> {
+    let gateway = web::Data::new(Gateway {
+        state,
+        logins: Default::default(),
+    });
+
+    tracing::info!("serving the HTTP API on {}", addr);
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(gateway.clone())
+            .service(web::scope("/v1").configure(routes))
+    })
+    .bind_rustls(addr, (*tls_config).clone())?
+    .run();
+    Ok(server)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{body::to_bytes, test as actix_test};
+
+    fn refs(value: &Value, found: &mut Vec<String>) {
+        match value {
+            Value::Object(map) => {
+                if let Some(Value::String(r)) = map.get("$ref") {
+                    found.push(r.clone());
+                }
+                map.values().for_each(|v| refs(v, found));
+            }
+            Value::Array(items) => items.iter().for_each(|v| refs(v, found)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn every_schema_reference_resolves() {
+        let api = openapi();
+        let mut found = vec![];
+        refs(&api, &mut found);
+        assert!(!found.is_empty());
+        for r in found {
+            let name = r.strip_prefix("#/components/schemas/").unwrap();
+            assert!(
+                api["components"]["schemas"].get(name).is_some(),
+                "{} is not defined",
+                r
+            );
+        }
+    }
+
+    #[test]
+    fn schemas_say_which_fields_are_required() {
+        let api = openapi();
+        let schemas = &api["components"]["schemas"];
+        let required = |name: &str| -> Vec<&str> {
+            let mut fields: Vec<&str> = schemas[name]["required"]
+                .as_array()
+                .unwrap_or_else(|| panic!("{} has no required list", name))
+                .iter()
+                .map(|f| f.as_str().unwrap())
+                .collect();
+            fields.sort_unstable();
+            fields
+        };
+        assert_eq!(required("Passwd"), ["dir", "gecos", "id", "name", "shell"]);
+        assert_eq!(required("StartLogin"), ["request", "username"]);
+        assert_eq!(required("FinishLogin"), ["finalization", "login_id"]);
+        assert_eq!(required("LoggedIn"), ["expires", "token"]);
+        assert_eq!(required("NewInvite"), ["hours", "uses"]);
+        assert_eq!(required("Error"), ["error"]);
+        assert_eq!(
+            schemas["Shadow"]["properties"]["expire_date"]["nullable"],
+            json!(true)
+        );
+    }
+
+    #[test]
+    fn endpoints_are_listed_once() {
+        let mut seen = std::collections::HashSet::new();
+        for e in ENDPOINTS {
+            assert!(seen.insert((e.method, e.path)), "{} {}", e.method, e.path);
+        }
+    }
+
+    #[actix_web::test]
+    async fn every_endpoint_is_routed() {
+        let dir = tempfile::tempdir().unwrap();
+        let gateway = web::Data::new(Gateway {
+            state: super::super::tests::test_state(dir.path()),
+            logins: Default::default(),
+        });
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(gateway)
+                .service(web::scope("/v1").configure(routes)),
+        )
+        .await;
+
+        // actix's own 404 has no body, where a handler's has an error in it
+        let unrouted = actix_test::call_service(
+            &app,
+            actix_test::TestRequest::get().uri("/v1/nope").to_request(),
+        )
+        .await;
+        assert_eq!(unrouted.status(), StatusCode::NOT_FOUND);
+        assert!(to_bytes(unrouted.into_body()).await.unwrap().is_empty());
+
+        for e in ENDPOINTS {
+            let uri = format!(
+                "/v1{}",
+                e.path
+                    .replace("{uid}", "0")
+                    .replace("{gid}", "0")
+                    .replace("{name}", "nobody")
+                    .replace("{username}", "nobody")
+            );
+            let req = actix_test::TestRequest::default()
+                .method(e.method.to_uppercase().parse().unwrap())
+                .uri(&uri)
+                .to_request();
+            let resp = actix_test::call_service(&app, req).await;
+            let status = resp.status();
+            let body = to_bytes(resp.into_body()).await.unwrap();
+            assert!(
+                !matches!(
+                    status,
+                    StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED
+                ) || !body.is_empty(),
+                "{} {} is not routed",
+                e.method,
+                e.path
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn logins_in_progress_are_bounded() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = super::super::tests::test_state(dir.path());
+        let gateway = Gateway {
+            state: state.clone(),
+            logins: Default::default(),
+        };
+        {
+            let mut logins = gateway.room_for_login().unwrap();
+            for i in 0..MAX_LOGINS {
+                let session = AuthdSession::new(state.clone(), "127.0.0.1:1".parse().unwrap());
+                logins.insert(
+                    i.to_string(),
+                    (Arc::new(Mutex::new(session)), Instant::now()),
+                );
+            }
+        }
+        assert!(matches!(gateway.room_for_login(), Err(ApiError::Busy)));
+
+        // expired ones make room again
+        let long_ago = Instant::now() - LOGIN_TIMEOUT;
+        gateway.logins.lock().unwrap().get_mut("0").unwrap().1 = long_ago;
+        assert_eq!(gateway.room_for_login().unwrap().len(), MAX_LOGINS - 1);
+    }
+}
@@ -0,0 +1,143 @@
+//! SSH public keys that users keep in authd instead of `~/.ssh/authorized_keys`.
+
+use crate::access::AccessRules;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+/// Key types sshd will take in authorized_keys.
+const KEY_TYPES: &[&str] = &[
+    "ssh-ed25519",
+    "ssh-rsa",
+    "ecdsa-sha2-nistp256",
+    "ecdsa-sha2-nistp384",
+    "ecdsa-sha2-nistp521",
+    "sk-ssh-ed25519@openssh.com",
+    "sk-ecdsa-sha2-nistp256@openssh.com",
+];
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct SshKeys {
+    /// Everyone's keys, by username.
+    #[serde(default)]
+    pub users: BTreeMap<String, Vec<SshKey>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SshKey {
+    /// The key as it would appear in authorized_keys: type, base64 and an optional comment.
+    pub key: String,
+    /// Hosts the key may be used on, in the same syntax as access rules.
+    #[serde(default = "any_host")]
+    pub host: String,
+    /// Last day the key may be used on, in days since Jan 1st 1970.
+    pub expires: Option<i64>,
+}
+
+fn any_host() -> String {
+    "*".into()
+}
+
+impl SshKey {
+    /// Check that `key` looks like a public key sshd would accept, and tidy up its whitespace.
+    pub fn parse(key: &str, host: Option<String>, expires: Option<i64>) -> anyhow::Result<Self> {
+        let mut fields = key.split_whitespace();
+        let (kind, blob) = match (fields.next(), fields.next()) {
+            (Some(kind), Some(blob)) => (kind, blob),
+            _ => anyhow::bail!("expected a key type and base64 key"),
+        };
+        if !KEY_TYPES.contains(&kind) {
+            anyhow::bail!("unsupported key type {}", kind);
+        }
+        // the blob starts with its own type, as a length-prefixed string
+        let decoded = base64::decode(blob)?;
+        let embedded = decoded
+            .get(..4)
+            .map(|len| u32::from_be_bytes(len.try_into().unwrap()) as usize)
+            .and_then(|len| decoded.get(4..4 + len));
+        if embedded != Some(kind.as_bytes()) {
+            anyhow::bail!("key data isn't a {} key", kind);
+        }
+        let comment = fields.collect::<Vec<_>>().join(" ");
+        let key = if comment.is_empty() {
+            format!("{} {}", kind, blob)
+        } else {
+            format!("{} {} {}", kind, blob, comment)
+        };
+        Ok(SshKey {
+            key,
+            host: host.unwrap_or_else(any_host),
+            expires,
+        })
+    }
+
+    /// `SHA256:` and the unpadded base64 SHA-256 of the key, as `ssh-keygen -l` prints it.
+    pub fn fingerprint(&self) -> String {
+        let blob = self
+            .key
+            .split_whitespace()
+            .nth(1)
+            .and_then(|b| base64::decode(b).ok())
+            .unwrap_or_default();
+        format!(
+            "SHA256:{}",
+            base64::encode_config(Sha256::digest(blob), base64::STANDARD_NO_PAD)
+        )
+    }
+
+    /// May the key be used on `host` on day `today`? Host groups come from `access`.
+    pub fn valid_on(&self, host: &str, today: i64, access: &AccessRules) -> bool {
+        self.expires.map(|e| today <= e).unwrap_or(true) && access.host_matches(&self.host, host)
+    }
+}
+
+/// How long user certificates last unless `ssh_user_cert_hours` says otherwise.
+pub const DEFAULT_USER_CERT_HOURS: u64 = 8;
+/// How long host certificates last unless `ssh_host_cert_days` says otherwise.
+pub const DEFAULT_HOST_CERT_DAYS: u64 = 30;
+
+/// Extensions OpenSSH puts on user certificates unless told otherwise.
+const USER_EXTENSIONS: &[&str] = &[
+    "permit-X11-forwarding",
+    "permit-agent-forwarding",
+    "permit-port-forwarding",
+    "permit-pty",
+    "permit-user-rc",
+];
+
+/// Let certificates be used a little before they were issued, for hosts whose clocks are behind.
+const CLOCK_SKEW: u64 = 5 * 60;
+
+/// Sign `public_key` (in authorized_keys format) into an OpenSSH certificate for `principals`,
+/// valid from now for `validity`.
+pub fn sign_certificate(
+    ca: &ssh_key::PrivateKey,
+    public_key: &str,
+    cert_type: ssh_key::certificate::CertType,
+    key_id: &str,
+    principals: &[String],
+    validity: std::time::Duration,
+) -> anyhow::Result<String> {
+    let public_key = ssh_key::PublicKey::from_openssh(public_key.trim())?;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    let mut builder = ssh_key::certificate::Builder::new_with_random_nonce(
+        &mut rand::rngs::OsRng,
+        public_key.key_data().clone(),
+        now - CLOCK_SKEW,
+        now + validity.as_secs(),
+    );
+    builder.serial(rand::random())?;
+    builder.key_id(key_id)?;
+    builder.cert_type(cert_type)?;
+    for principal in principals {
+        builder.valid_principal(principal)?;
+    }
+    if cert_type == ssh_key::certificate::CertType::User {
+        for extension in USER_EXTENSIONS {
+            builder.extension(*extension, "")?;
+        }
+    }
+    Ok(builder.sign(ca)?.to_openssh()?)
+}
@@ -0,0 +1,207 @@
+//! Sudo rules, kept next to the users and groups they refer to and rendered into sudoers(5) on each
+//! host by `auth render-sudoers`.
+
+use crate::access::AccessRules;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct SudoRules {
+    #[serde(default)]
+    pub rules: Vec<SudoRule>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SudoRule {
+    /// A username, or `%` and a group name.
+    pub who: String,
+    /// Hosts the rule applies on, in the same syntax as access rules: a host name, a pattern with
+    /// `*`, or `@` and the name of a host group from the access file.
+    #[serde(default = "any_host")]
+    pub host: String,
+    /// Users the commands may be run as.
+    #[serde(default = "root")]
+    pub run_as: Vec<String>,
+    /// Full paths of commands, with arguments if they should be restricted, or `ALL`.
+    pub commands: Vec<String>,
+    /// Don't ask for the user's password.
+    #[serde(default)]
+    pub nopasswd: bool,
+}
+
+fn any_host() -> String {
+    "*".into()
+}
+
+fn root() -> Vec<String> {
+    vec!["root".into()]
+}
+
+impl SudoRule {
+    /// Does this rule apply to someone called `user` in `groups`?
+    pub fn applies_to(&self, user: &str, groups: &[String]) -> bool {
+        match self.who.strip_prefix('%') {
+            Some(group) => groups.iter().any(|g| g == group),
+            None => self.who == user,
+        }
+    }
+
+    /// Does this rule apply on `host`? Host groups come from `access`.
+    pub fn applies_on(&self, host: &str, access: &AccessRules) -> bool {
+        access.host_matches(&self.host, host)
+    }
+
+    /// This rule as a sudoers(5) line for the host it was fetched for, so the host is always
+    /// `ALL`.
+    pub fn to_sudoers(&self) -> anyhow::Result<String> {
+        check_name(self.who.strip_prefix('%').unwrap_or(&self.who))?;
+        for user in &self.run_as {
+            check_name(user)?;
+        }
+        if self.commands.is_empty() {
+            anyhow::bail!("sudo rule for {} has no commands", self.who);
+        }
+        let commands = self
+            .commands
+            .iter()
+            .map(|c| escape_command(c))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(format!(
+            "{} ALL=({}) {}{}",
+            self.who,
+            self.run_as.join(", "),
+            if self.nopasswd { "NOPASSWD: " } else { "" },
+            commands.join(", ")
+        ))
+    }
+}
+
+/// Users and groups end up unquoted in sudoers, so only allow names that can't mean anything else.
+fn check_name(name: &str) -> anyhow::Result<()> {
+    let ok = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+    if ok || name == "ALL" {
+        Ok(())
+    } else {
+        anyhow::bail!("{:?} isn't a name sudoers can take as-is", name)
+    }
+}
+
+/// Escape the characters sudoers gives meaning to inside a command.
+fn escape_command(command: &str) -> anyhow::Result<String> {
+    if command == "ALL" {
+        return Ok(command.into());
+    }
+    if !command.starts_with('/') {
+        anyhow::bail!("sudo command {:?} needs a full path", command);
+    }
+    if command.contains('\n') {
+        anyhow::bail!("sudo command {:?} has a newline in it", command);
+    }
+    let mut escaped = String::with_capacity(command.len());
+    for c in command.chars() {
+        if matches!(c, '\\' | ',' | ':' | '=') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    Ok(escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(who: &str, commands: &[&str]) -> SudoRule {
+        SudoRule {
+            who: who.into(),
+            host: any_host(),
+            run_as: root(),
+            commands: commands.iter().map(|&c| c.into()).collect(),
+            nopasswd: false,
+        }
+    }
+
+    #[test]
+    fn renders_sudoers_lines() {
+        assert_eq!(
+            rule("tj", &["ALL"]).to_sudoers().unwrap(),
+            "tj ALL=(root) ALL"
+        );
+        let r = SudoRule {
+            run_as: vec!["root".into(), "mirror".into()],
+            nopasswd: true,
+            ..rule(
+                "%mirror-admins",
+                &[
+                    "/usr/bin/systemctl restart rsyncd",
+                    "/usr/local/bin/sync-mirror",
+                ],
+            )
+        };
+        assert_eq!(
+            r.to_sudoers().unwrap(),
+            "%mirror-admins ALL=(root, mirror) NOPASSWD: /usr/bin/systemctl restart rsyncd, \
+             /usr/local/bin/sync-mirror"
+        );
+    }
+
+    #[test]
+    fn escapes_what_sudoers_gives_meaning_to() {
+        assert_eq!(
+            rule("tj", &["/bin/echo a,b:c=d\\e"]).to_sudoers().unwrap(),
+            "tj ALL=(root) /bin/echo a\\,b\\:c\\=d\\\\e"
+        );
+    }
+
+    #[test]
+    fn refuses_what_it_cant_render_safely() {
+        assert!(rule("tj", &[]).to_sudoers().is_err());
+        assert!(rule("tj", &["systemctl"]).to_sudoers().is_err());
+        assert!(rule("tj", &["/bin/sh\nroot ALL=(ALL) ALL"])
+            .to_sudoers()
+            .is_err());
+        assert!(rule("tj ALL=(ALL) ALL #", &["ALL"]).to_sudoers().is_err());
+        assert!(rule("%", &["ALL"]).to_sudoers().is_err());
+        let r = SudoRule {
+            run_as: vec!["root)".into()],
+            ..rule("tj", &["ALL"])
+        };
+        assert!(r.to_sudoers().is_err());
+    }
+
+    /// What `auth render-sudoers` relies on: visudo accepts everything we render. Skipped where
+    /// sudo isn't installed.
+    #[test]
+    fn visudo_accepts_rendered_rules() {
+        let visudo = ["/usr/sbin/visudo", "/usr/bin/visudo", "/sbin/visudo"]
+            .into_iter()
+            .find(|p| std::path::Path::new(p).exists());
+        let visudo = match visudo {
+            Some(visudo) => visudo,
+            None => return eprintln!("no visudo, skipping"),
+        };
+        let rules = [
+            rule("tj", &["ALL"]),
+            SudoRule {
+                nopasswd: true,
+                run_as: vec!["root".into(), "nobody".into()],
+                ..rule("%wheel", &["/bin/echo a,b:c=d\\e", "/usr/bin/id"])
+            },
+        ];
+        let sudoers: String = rules
+            .iter()
+            .map(|r| format!("{}\n", r.to_sudoers().unwrap()))
+            .collect();
+        let dir = tempfile::tempdir().unwrap();
+        let pth = dir.path().join("cosiauthd");
+        std::fs::write(&pth, sudoers).unwrap();
+        let status = std::process::Command::new(visudo)
+            .arg("-cqf")
+            .arg(&pth)
+            .status()
+            .unwrap();
+        assert!(status.success(), "visudo rejected {}", pth.display());
+    }
+}
@@ -0,0 +1,156 @@
+//! How clients decide that they are really talking to authd.
+//!
+//! There are two modes. Pinning trusts any server presenting one of a list of certificates or
+//! public keys, whatever name they are for, which makes rotation a matter of pinning the new one
+//! before switching. CA mode verifies the server's chain against a bundle of CAs and checks that
+//! the certificate is for the expected server name.
+
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use sha2::{Digest, Sha256};
+use std::{sync::Arc, time::SystemTime};
+
+use crate::SocketName;
+
+/// Prefix for SPKI pins, the same format curl's `--pinnedpubkey` uses.
+const PIN_PREFIX: &str = "sha256//";
+
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(default)]
+pub struct ServerTrust {
+    /// Name to send as SNI and, in CA mode, to check the certificate against. Defaults to the
+    /// host part of the authd address, or `localhost` if that is a bare IP address.
+    pub server_name: Option<String>,
+    /// A single pinned DER certificate. Same as listing it in `certs`, kept for older configs.
+    pub cert: Option<String>,
+    /// Pinned DER certificates.
+    pub certs: Vec<String>,
+    /// Pinned public keys: `sha256//` and the base64 SHA-256 of the SubjectPublicKeyInfo. See
+    /// `auth spki-pin`.
+    pub spki_pins: Vec<String>,
+    /// PEM bundle of CAs to verify the server against. Can't be combined with pins.
+    pub ca_bundle: Option<String>,
+}
+
+impl ServerTrust {
+    /// Shell-expand any paths.
+    pub fn expand(&mut self) {
+        let expand = |p: &String| -> String {
+            shellexpand::full(p)
+                .expect("expanding certificate path")
+                .into()
+        };
+        self.cert = self.cert.as_ref().map(expand);
+        self.certs = self.certs.iter().map(expand).collect();
+        self.ca_bundle = self.ca_bundle.as_ref().map(expand);
+    }
+
+    /// The name to present to (and maybe check against) the server at `host`.
+    pub fn server_name_for(&self, host: &SocketName) -> String {
+        match (&self.server_name, host) {
+            (Some(name), _) => name.clone(),
+            (None, SocketName::Dns(name, _)) => name.clone(),
+            (None, SocketName::Addr(_)) => "localhost".into(),
+        }
+    }
+
+    fn pinned_certs(&self) -> impl Iterator<Item = &String> {
+        self.cert.iter().chain(self.certs.iter())
+    }
+
+    /// Build the rustls config, reading any certificates from disk.
+    pub fn client_config(&self) -> anyhow::Result<rustls::ClientConfig> {
+        let pinning = self.pinned_certs().next().is_some() || !self.spki_pins.is_empty();
+        let builder = rustls::ClientConfig::builder().with_safe_defaults();
+
+        let config = match (&self.ca_bundle, pinning) {
+            (Some(_), true) => {
+                anyhow::bail!("pick either a CA bundle or pinned certificates/keys, not both")
+            }
+            (None, false) => anyhow::bail!("no way to trust authd: configure a cert, pin or CA"),
+            (Some(bundle), false) => {
+                let pem = std::fs::read(bundle)?;
+                let ders = rustls_pemfile::certs(&mut pem.as_slice())?;
+                let mut roots = rustls::RootCertStore::empty();
+                let (added, _) = roots.add_parsable_certificates(&ders);
+                if added == 0 {
+                    anyhow::bail!("no usable certificates in CA bundle {}", bundle);
+                }
+                builder.with_root_certificates(roots).with_no_client_auth()
+            }
+            (None, true) => {
+                let certs = self
+                    .pinned_certs()
+                    .map(std::fs::read)
+                    .collect::<Result<Vec<_>, _>>()?;
+                let spki_sha256 = self
+                    .spki_pins
+                    .iter()
+                    .map(|pin| parse_pin(pin))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                builder
+                    .with_custom_certificate_verifier(Arc::new(PinnedVerifier {
+                        certs,
+                        spki_sha256,
+                    }))
+                    .with_no_client_auth()
+            }
+        };
+        Ok(config)
+    }
+}
+
+/// The pin for a DER certificate's public key, in the format `spki_pins` wants.
+pub fn spki_pin(cert_der: &[u8]) -> anyhow::Result<String> {
+    Ok(format!(
+        "{}{}",
+        PIN_PREFIX,
+        base64::encode(spki_sha256(cert_der)?)
+    ))
+}
+
+fn spki_sha256(cert_der: &[u8]) -> anyhow::Result<[u8; 32]> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert_der)
+        .map_err(|e| anyhow::anyhow!("parsing certificate: {}", e))?;
+    Ok(Sha256::digest(cert.tbs_certificate.subject_pki.raw).into())
+}
+
+fn parse_pin(pin: &str) -> anyhow::Result<[u8; 32]> {
+    let b64 = pin
+        .strip_prefix(PIN_PREFIX)
+        .ok_or_else(|| anyhow::anyhow!("pin {} should start with {}", pin, PIN_PREFIX))?;
+    base64::decode(b64)?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("pin {} is not a SHA-256 hash", pin))
+}
+
+/// Accepts exactly the pinned certificates and public keys.
+struct PinnedVerifier {
+    certs: Vec<Vec<u8>>,
+    spki_sha256: Vec<[u8; 32]>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if self.certs.iter().any(|c| *c == end_entity.0) {
+            return Ok(ServerCertVerified::assertion());
+        }
+        if !self.spki_sha256.is_empty() {
+            let spki = spki_sha256(&end_entity.0)
+                .map_err(|e| rustls::Error::InvalidCertificateData(e.to_string()))?;
+            if self.spki_sha256.contains(&spki) {
+                return Ok(ServerCertVerified::assertion());
+            }
+        }
+        Err(rustls::Error::InvalidCertificateData(
+            "server certificate matches none of the pins".into(),
+        ))
+    }
+}
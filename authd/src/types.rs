@@ -1,3 +1,4 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 pub trait ToNSS {
@@ -5,7 +6,18 @@ pub trait ToNSS {
     fn to_nss(&self) -> Self::Target;
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+/// How long clients should cache directory lookups, in seconds.
+///
+/// `None` means authd has no opinion and the client should use its own default.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, JsonSchema)]
+pub struct CacheTtls {
+    /// For lookups that found an entry.
+    pub positive: Option<u64>,
+    /// For lookups that came back empty.
+    pub negative: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
 pub struct Group {
     pub name: String,
     pub gid: u32,
@@ -35,7 +47,42 @@ impl From<Group> for libnss::group::Group {
     }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+impl std::fmt::Display for Group {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}:{}",
+            self.name,
+            "x",
+            self.gid,
+            self.members.join(",")
+        )
+    }
+}
+
+/// One `(host,user,domain)` entry of a netgroup. `None` is a wildcard.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize, JsonSchema)]
+pub struct NetgroupTriple {
+    pub host: Option<String>,
+    pub user: Option<String>,
+    pub domain: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub enum NetgroupMember {
+    Triple(NetgroupTriple),
+    /// Everything in another netgroup.
+    Netgroup(String),
+}
+
+/// man netgroup(5)
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct Netgroup {
+    pub name: String,
+    pub members: Vec<NetgroupMember>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
 pub struct Passwd {
     pub name: String,
     // forcing the uid to match the gid because we're pissed
@@ -87,13 +134,14 @@ impl std::fmt::Display for Passwd {
     }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
 pub struct Shadow {
     pub name: String,
     pub passwd: String,
-    /// days since Jan 1st 1970
+    /// days since Jan 1st 1970, or -1 if the field is empty, which turns password aging off
     pub last_change: i64,
     pub change_min_days: i64,
+    /// -1 if the field is empty, meaning the password never has to be changed
     pub change_max_days: i64,
     pub change_warn_days: i64,
     pub change_inactive_days: Option<i64>,
@@ -102,14 +150,22 @@ pub struct Shadow {
 
 impl std::fmt::Display for Shadow {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // negative is how an empty field is kept, so write it back empty
+        let unless_negative = |x: i64| {
+            if x < 0 {
+                String::new()
+            } else {
+                x.to_string()
+            }
+        };
         writeln!(
             f,
             "{}:{}:{}:{}:{}:{}:{}:{}:",
             self.name,
             self.passwd,
-            self.last_change,
+            unless_negative(self.last_change),
             self.change_min_days,
-            self.change_max_days,
+            unless_negative(self.change_max_days),
             self.change_warn_days,
             self.change_inactive_days
                 .map(|x| x.to_string())
@@ -121,6 +177,76 @@ impl std::fmt::Display for Shadow {
     }
 }
 
+/// Today, in the days since Jan 1st 1970 that shadow counts in.
+pub fn today() -> i64 {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    (now.as_secs() / (24 * 60 * 60)) as i64
+}
+
+impl Shadow {
+    /// Whether the password is locked, i.e. prefixed with `!` as `passwd -l` does. A `!` on its
+    /// own isn't a lock: it's what accounts that moved off crypt hashes are left with, since
+    /// their password is only in OPAQUE.
+    pub fn is_locked(&self) -> bool {
+        self.passwd.len() > 1 && self.passwd.starts_with('!')
+    }
+
+    /// Evaluate the aging fields on day `today` (days since Jan 1st 1970), the way shadow(5) says.
+    pub fn aging_status(&self, today: i64) -> AccountStatus {
+        if let Some(expire_date) = self.expire_date {
+            if today >= expire_date {
+                return AccountStatus::AccountExpired;
+            }
+        }
+        if self.is_locked() {
+            return AccountStatus::Locked;
+        }
+        // 0 means the password has to be changed at the next login, and empty that there's no aging
+        if self.last_change == 0 {
+            return AccountStatus::PasswordExpired;
+        }
+        if self.last_change < 0 {
+            return AccountStatus::Ok;
+        }
+        // an empty or negative maximum age means the password never has to be changed
+        if self.change_max_days < 0 {
+            return AccountStatus::Ok;
+        }
+        let password_expires = self.last_change + self.change_max_days;
+        if let Some(inactive) = self.change_inactive_days {
+            if inactive >= 0 && today > password_expires + inactive {
+                return AccountStatus::AccountExpired;
+            }
+        }
+        if today > password_expires {
+            AccountStatus::PasswordExpired
+        } else if self.change_warn_days > 0 && today > password_expires - self.change_warn_days {
+            AccountStatus::PasswordExpiresSoon(password_expires - today)
+        } else {
+            AccountStatus::Ok
+        }
+    }
+}
+
+/// Whether an account may be used right now, see `Authd::account_status`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum AccountStatus {
+    Ok,
+    /// Fine for now, but the password has to be changed within this many days.
+    PasswordExpiresSoon(i64),
+    /// The password has to be changed before the account can be used.
+    PasswordExpired,
+    /// The account has expired, or its password expired long enough ago that it went inactive.
+    AccountExpired,
+    /// The password has been locked.
+    Locked,
+    /// The account exists but may not log in on the host that asked.
+    HostDenied,
+    UnknownUser,
+}
+
 impl ToNSS for Shadow {
     type Target = libnss::shadow::Shadow;
     fn to_nss(&self) -> libnss::shadow::Shadow {
@@ -153,3 +279,137 @@ impl From<Shadow> for libnss::shadow::Shadow {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TODAY: i64 = 20_000;
+
+    /// Changed 10 days ago, may be changed right away, has to be changed every 90 days with a week
+    /// of warning.
+    fn shadow() -> Shadow {
+        Shadow {
+            name: "tj".into(),
+            passwd: "*".into(),
+            last_change: TODAY - 10,
+            change_min_days: 0,
+            change_max_days: 90,
+            change_warn_days: 7,
+            change_inactive_days: None,
+            expire_date: None,
+        }
+    }
+
+    #[test]
+    fn fresh_password_is_ok() {
+        assert_eq!(shadow().aging_status(TODAY), AccountStatus::Ok);
+    }
+
+    #[test]
+    fn no_maximum_age_never_expires() {
+        let s = Shadow {
+            change_max_days: -1,
+            last_change: 1,
+            ..shadow()
+        };
+        assert_eq!(s.aging_status(TODAY), AccountStatus::Ok);
+    }
+
+    #[test]
+    fn empty_last_change_turns_aging_off() {
+        let s = Shadow {
+            last_change: -1,
+            change_inactive_days: Some(0),
+            ..shadow()
+        };
+        assert_eq!(s.aging_status(TODAY), AccountStatus::Ok);
+        let s = Shadow {
+            expire_date: Some(TODAY),
+            ..s
+        };
+        assert_eq!(s.aging_status(TODAY), AccountStatus::AccountExpired);
+    }
+
+    #[test]
+    fn warns_before_the_password_expires() {
+        let s = Shadow {
+            last_change: TODAY - 85,
+            ..shadow()
+        };
+        assert_eq!(s.aging_status(TODAY), AccountStatus::PasswordExpiresSoon(5));
+    }
+
+    #[test]
+    fn password_expires_after_the_maximum_age() {
+        let s = Shadow {
+            last_change: TODAY - 91,
+            ..shadow()
+        };
+        assert_eq!(s.aging_status(TODAY), AccountStatus::PasswordExpired);
+    }
+
+    #[test]
+    fn zero_last_change_forces_a_change() {
+        let s = Shadow {
+            last_change: 0,
+            ..shadow()
+        };
+        assert_eq!(s.aging_status(TODAY), AccountStatus::PasswordExpired);
+    }
+
+    #[test]
+    fn goes_inactive_after_the_grace_period() {
+        let s = Shadow {
+            last_change: TODAY - 100,
+            change_inactive_days: Some(14),
+            ..shadow()
+        };
+        assert_eq!(s.aging_status(TODAY), AccountStatus::PasswordExpired);
+        let s = Shadow {
+            last_change: TODAY - 105,
+            ..s
+        };
+        assert_eq!(s.aging_status(TODAY), AccountStatus::AccountExpired);
+    }
+
+    #[test]
+    fn account_expires_on_its_expire_date() {
+        let s = Shadow {
+            expire_date: Some(TODAY),
+            ..shadow()
+        };
+        assert_eq!(s.aging_status(TODAY - 1), AccountStatus::Ok);
+        assert_eq!(s.aging_status(TODAY), AccountStatus::AccountExpired);
+    }
+
+    #[test]
+    fn locked_password() {
+        let s = Shadow {
+            passwd: "!$6$salt$hash".into(),
+            ..shadow()
+        };
+        assert_eq!(s.aging_status(TODAY), AccountStatus::Locked);
+        // an expired account says so even if it is also locked
+        let s = Shadow {
+            expire_date: Some(TODAY - 1),
+            ..s
+        };
+        assert_eq!(s.aging_status(TODAY), AccountStatus::AccountExpired);
+    }
+
+    #[test]
+    fn a_bare_bang_is_not_a_lock() {
+        let s = Shadow {
+            passwd: "!".into(),
+            ..shadow()
+        };
+        assert!(!s.is_locked());
+        assert_eq!(s.aging_status(TODAY), AccountStatus::Ok);
+        let s = Shadow {
+            passwd: "!*".into(),
+            ..shadow()
+        };
+        assert!(s.is_locked());
+    }
+}
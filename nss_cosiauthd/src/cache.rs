@@ -0,0 +1,143 @@
+//! In-process TTL cache for lookups, so `ls -l` doesn't make an RPC per file.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+/// Positive TTL used if neither the config nor authd say otherwise.
+pub const DEFAULT_POSITIVE_TTL: u64 = 60;
+/// Negative TTL used if neither the config nor authd say otherwise.
+pub const DEFAULT_NEGATIVE_TTL: u64 = 10;
+/// How many lookups a cache remembers at most, so walking a big tree of files owned by lots of
+/// different users doesn't grow it forever.
+pub const MAX_ENTRIES: usize = 4096;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Ttls {
+    pub positive: Duration,
+    pub negative: Duration,
+}
+
+/// Remembers both hits (`Some`) and misses (`None`) until they expire.
+pub struct TtlCache<K, V> {
+    entries: HashMap<K, (Instant, Option<V>)>,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> TtlCache<K, V> {
+    pub fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+        }
+    }
+
+    /// The outer `Option` is whether we had anything cached at all, the inner one whether authd
+    /// found an entry.
+    pub fn get(&mut self, key: &K) -> Option<Option<V>> {
+        match self.entries.get(key) {
+            Some((expiry, value)) if *expiry > Instant::now() => Some(value.clone()),
+            Some(_) => {
+                self.entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&mut self, key: K, value: Option<V>, ttls: Ttls) {
+        let ttl = if value.is_some() {
+            ttls.positive
+        } else {
+            ttls.negative
+        };
+        // a zero TTL turns caching off
+        if ttl.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        if self.entries.len() >= MAX_ENTRIES && !self.entries.contains_key(&key) {
+            self.entries.retain(|_, (expiry, _)| *expiry > now);
+        }
+        if self.entries.len() >= MAX_ENTRIES && !self.entries.contains_key(&key) {
+            // still full of live entries, so make room by dropping whichever expires first
+            let soonest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (expiry, _))| *expiry)
+                .map(|(k, _)| k.clone());
+            if let Some(soonest) = soonest {
+                self.entries.remove(&soonest);
+            }
+        }
+        self.entries.insert(key, (now + ttl, value));
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ttls(positive: u64, negative: u64) -> Ttls {
+        Ttls {
+            positive: Duration::from_millis(positive),
+            negative: Duration::from_millis(negative),
+        }
+    }
+
+    #[test]
+    fn remembers_hits_for_the_positive_ttl() {
+        let mut cache = TtlCache::new();
+        cache.insert("tj", Some(1000), ttls(50, 60_000));
+        assert_eq!(cache.get(&"tj"), Some(Some(1000)));
+        std::thread::sleep(Duration::from_millis(80));
+        assert_eq!(cache.get(&"tj"), None);
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[test]
+    fn remembers_misses_for_the_negative_ttl() {
+        let mut cache: TtlCache<&str, u32> = TtlCache::new();
+        cache.insert("nobody", None, ttls(60_000, 50));
+        assert_eq!(cache.get(&"nobody"), Some(None));
+        std::thread::sleep(Duration::from_millis(80));
+        assert_eq!(cache.get(&"nobody"), None);
+    }
+
+    #[test]
+    fn zero_ttl_turns_caching_off() {
+        let mut cache = TtlCache::new();
+        cache.insert("tj", Some(1000), ttls(0, 60_000));
+        cache.insert("nobody", None, ttls(60_000, 0));
+        assert_eq!(cache.get(&"tj"), None);
+        assert_eq!(cache.get(&"nobody"), None);
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[test]
+    fn expired_entries_go_when_full() {
+        let mut cache = TtlCache::new();
+        for uid in 0..MAX_ENTRIES as u32 {
+            cache.insert(uid, Some(uid), ttls(1, 1));
+        }
+        std::thread::sleep(Duration::from_millis(10));
+        cache.insert(u32::MAX, Some(0), ttls(60_000, 60_000));
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn never_grows_past_max_entries() {
+        let mut cache = TtlCache::new();
+        for uid in 0..2 * MAX_ENTRIES as u32 {
+            cache.insert(uid, Some(uid), ttls(60_000, 60_000));
+        }
+        assert_eq!(cache.len(), MAX_ENTRIES);
+        assert_eq!(
+            cache.get(&(2 * MAX_ENTRIES as u32 - 1)),
+            Some(Some(2 * MAX_ENTRIES as u32 - 1))
+        );
+    }
+}
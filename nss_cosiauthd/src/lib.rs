@@ -1,3 +1,5 @@
+use authd::nss::NssConfig;
+use authd::rpc::RpcError;
 use authd::types::ToNSS;
 use futures::executor::block_on;
 use libc::c_int;
@@ -12,18 +14,17 @@ use tarpc::context;
 use tokio::runtime::{self, Runtime};
 use tokio::time::sleep_until;
 
+mod cache;
+mod netgroup;
+use cache::{TtlCache, Ttls};
+use netgroup::{CNetgrent, NetgroupIterator};
+
 #[derive(Default)]
 struct ClientAccessControl {
     client: Arc<Mutex<Option<authd::rpc::AuthdClient>>>,
     latest_ts: Arc<Mutex<Option<Instant>>>,
 }
 
-#[derive(serde::Deserialize)]
-struct NssConfig {
-    host: authd::SocketName,
-    cert: String,
-}
-
 impl ClientAccessControl {
     fn with_client<O>(&mut self, f: impl FnOnce(&mut authd::rpc::AuthdClient) -> O) -> O {
         use trust_dns_resolver::TokioAsyncResolver;
@@ -68,31 +69,114 @@ impl ClientAccessControl {
         );
         let mut client = self.client.lock().unwrap();
         if client.is_none() {
-            *client = Some(
-                RT.block_on(authd::client_connect(
+            let new_client = RT
+                .block_on(authd::client_connect(
                     final_sockaddr.expect("no host found"),
-                    &rustls::Certificate(std::fs::read(&CFG.cert).expect("reading cert")),
-                    "localhost",
+                    &CFG.trust,
+                    &CFG.trust.server_name_for(&CFG.host),
                 ))
-                .unwrap(),
-            );
+                .unwrap();
+            if let (Some(principal), Some(secret)) = (&CFG.host_principal, &CFG.host_secret) {
+                // not being able to read it is normal for unprivileged processes
+                if let Ok(secret) = std::fs::read_to_string(secret) {
+                    if let Err(e) = RT.block_on(authd::client_login(
+                        &new_client,
+                        principal,
+                        secret.trim_end().as_bytes(),
+                    )) {
+                        eprintln!("nss_cosiauthd: host login as {} failed: {}", principal, e);
+                    }
+                }
+            }
+            *client = Some(new_client);
         }
         f(client.as_mut().unwrap())
     }
+
+    /// Work out the cache TTLs: our config wins, then whatever authd recommends, then defaults.
+    ///
+    /// authd is only asked once per process, unless asking fails.
+    fn ttls(&mut self) -> Ttls {
+        let mut remembered = TTLS.lock().unwrap();
+        if let Some(ttls) = *remembered {
+            return ttls;
+        }
+        let advertised = match (CFG.positive_ttl, CFG.negative_ttl) {
+            (Some(_), Some(_)) => Ok(Default::default()),
+            _ => self.with_client(|client| block_on(client.get_cache_ttls(context::current()))),
+        };
+        let recommended = advertised.as_ref().copied().unwrap_or_default();
+        let ttls = Ttls {
+            positive: Duration::from_secs(
+                CFG.positive_ttl
+                    .or(recommended.positive)
+                    .unwrap_or(cache::DEFAULT_POSITIVE_TTL),
+            ),
+            negative: Duration::from_secs(
+                CFG.negative_ttl
+                    .or(recommended.negative)
+                    .unwrap_or(cache::DEFAULT_NEGATIVE_TTL),
+            ),
+        };
+        if advertised.is_ok() {
+            *remembered = Some(ttls);
+        }
+        ttls
+    }
+}
+
+/// Answer from `cache` if we can, otherwise ask authd with `fetch` and remember the result.
+///
+/// The connection is only locked long enough to get a handle on it, so one slow lookup doesn't
+/// hold up every other thread in the process.
+fn cached<K, V, E>(
+    cache: &Mutex<TtlCache<K, V>>,
+    key: K,
+    fetch: impl FnOnce(&authd::rpc::AuthdClient) -> Result<Result<Option<V>, RpcError>, E>,
+) -> Response<V::Target>
+where
+    K: std::hash::Hash + Eq + Clone,
+    V: ToNSS + Clone,
+{
+    if let Some(hit) = cache.lock().unwrap().get(&key) {
+        return match hit {
+            Some(v) => Response::Success(v.to_nss()),
+            None => Response::NotFound,
+        };
+    }
+    let (client, ttls) = {
+        let mut cl = RPC.lock().unwrap();
+        let ttls = cl.ttls();
+        (cl.with_client(|client| client.clone()), ttls)
+    };
+    let _guard = RT.enter();
+    match fetch(&client) {
+        Ok(Ok(found)) => {
+            let response = match &found {
+                Some(v) => Response::Success(v.to_nss()),
+                None => Response::NotFound,
+            };
+            cache.lock().unwrap().insert(key, found, ttls);
+            response
+        }
+        Ok(Err(_e)) | Err(_) => Response::Unavail,
+    }
 }
 
 lazy_static::lazy_static! {
     static ref PASSWD_ITERATOR: Mutex<Iterator<Passwd>> = Mutex::new(Iterator::<Passwd>::new());
     static ref GROUP_ITERATOR: Mutex<Iterator<Group>> = Mutex::new(Iterator::<Group>::new());
     static ref SHADOW_ITERATOR: Mutex<Iterator<libnss::shadow::Shadow>> = Mutex::new(Iterator::<libnss::shadow::Shadow>::new());
+    static ref NETGROUP_ITERATOR: Mutex<NetgroupIterator> = Mutex::new(NetgroupIterator::default());
 
     static ref RPC: Mutex<ClientAccessControl> = Mutex::new(ClientAccessControl::default());
+    static ref TTLS: Mutex<Option<Ttls>> = Mutex::new(None);
+    static ref PASSWD_BY_NAME: Mutex<TtlCache<String, authd::types::Passwd>> = Mutex::new(TtlCache::new());
+    static ref PASSWD_BY_UID: Mutex<TtlCache<u32, authd::types::Passwd>> = Mutex::new(TtlCache::new());
+    static ref GROUP_BY_NAME: Mutex<TtlCache<String, authd::types::Group>> = Mutex::new(TtlCache::new());
+    static ref GROUP_BY_GID: Mutex<TtlCache<u32, authd::types::Group>> = Mutex::new(TtlCache::new());
     static ref RT: Runtime = runtime::Builder::new_multi_thread().worker_threads(2).enable_io().enable_time().build().expect("could not initialize tokio runtime");
-    static ref CFG: NssConfig = {
-        let mut cfg: NssConfig =  toml::from_slice(std::fs::read(authd::find_config_dir().map(|cd| cd.join("nss_cosiauthd.toml")).expect("no nss_cosiauthd.toml found!")).unwrap().as_slice()).unwrap();
-        cfg.cert = shellexpand::full(&cfg.cert).unwrap().to_string();
-        cfg
-    };
+    static ref CFG: NssConfig = NssConfig::load().expect("loading nss_cosiauthd.toml");
 }
 
 struct CauthdPasswd;
@@ -101,35 +185,23 @@ impl libnss::passwd::PasswdHooks for CauthdPasswd {
         let mut cl = RPC.lock().unwrap();
         cl.with_client(
             |client| match block_on(client.get_all_passwd(context::current())) {
-                Ok(passwds) => Response::Success(passwds.into_iter().map(|x| x.to_nss()).collect()),
-                Err(_e) => Response::Unavail,
+                Ok(Ok(passwds)) => {
+                    Response::Success(passwds.into_iter().map(|x| x.to_nss()).collect())
+                }
+                Ok(Err(_)) | Err(_) => Response::Unavail,
             },
         )
     }
 
     fn get_entry_by_uid(uid: libc::uid_t) -> libnss::interop::Response<libnss::passwd::Passwd> {
-        let mut cl = RPC.lock().unwrap();
-        cl.with_client(
-            |client| match block_on(client.get_passwd_by_uid(context::current(), uid)) {
-                Ok(passwd) => match passwd {
-                    Some(p) => Response::Success(p.to_nss()),
-                    None => Response::NotFound,
-                },
-                Err(_e) => Response::Unavail,
-            },
-        )
+        cached(&PASSWD_BY_UID, uid, |client| {
+            block_on(client.get_passwd_by_uid(context::current(), uid))
+        })
     }
 
     fn get_entry_by_name(name: String) -> libnss::interop::Response<libnss::passwd::Passwd> {
-        let mut cl = RPC.lock().unwrap();
-        cl.with_client(|client| {
-            match block_on(client.get_passwd_by_name(context::current(), name)) {
-                Ok(passwd) => match passwd {
-                    Some(p) => Response::Success(p.to_nss()),
-                    None => Response::NotFound,
-                },
-                Err(_e) => Response::Unavail,
-            }
+        cached(&PASSWD_BY_NAME, name.clone(), |client| {
+            block_on(client.get_passwd_by_name(context::current(), name))
         })
     }
 }
@@ -139,8 +211,21 @@ impl libnss::shadow::ShadowHooks for CauthdShadow {
         let mut cl = RPC.lock().unwrap();
         cl.with_client(
             |client| match block_on(client.get_all_shadow(context::current())) {
-                Ok(passwds) => Response::Success(passwds.into_iter().map(|x| x.to_nss()).collect()),
-                Err(_e) => Response::Unavail,
+                Ok(Ok(shadows)) => {
+                    Response::Success(shadows.into_iter().map(|x| x.to_nss()).collect())
+                }
+                Ok(Err(RpcError::NotAuthorized)) => {
+                    match block_on(client.get_all_passwd(context::current())) {
+                        Ok(Ok(passwds)) => Response::Success(
+                            passwds
+                                .into_iter()
+                                .map(|p| placeholder_shadow(p.name))
+                                .collect(),
+                        ),
+                        Ok(Err(_)) | Err(_) => Response::Unavail,
+                    }
+                }
+                Ok(Err(_)) | Err(_) => Response::Unavail,
             },
         )
     }
@@ -148,52 +233,63 @@ impl libnss::shadow::ShadowHooks for CauthdShadow {
     fn get_entry_by_name(name: String) -> libnss::interop::Response<libnss::shadow::Shadow> {
         let mut cl = RPC.lock().unwrap();
         cl.with_client(|client| {
-            match block_on(client.get_shadow_by_name(context::current(), name)) {
-                Ok(passwd) => match passwd {
+            match block_on(client.get_shadow_by_name(context::current(), name.clone())) {
+                Ok(Ok(shadow)) => match shadow {
                     Some(p) => Response::Success(p.to_nss()),
                     None => Response::NotFound,
                 },
-                Err(_e) => Response::Unavail,
+                Ok(Err(RpcError::NotAuthorized)) => {
+                    match block_on(client.get_passwd_by_name(context::current(), name)) {
+                        Ok(Ok(Some(p))) => Response::Success(placeholder_shadow(p.name)),
+                        Ok(Ok(None)) => Response::NotFound,
+                        Ok(Err(_)) | Err(_) => Response::Unavail,
+                    }
+                }
+                Ok(Err(_)) | Err(_) => Response::Unavail,
             }
         })
     }
 }
 
+/// What callers that aren't allowed to see shadow data get instead: an entry that exists, but
+/// whose password can never match and which has no aging information.
+fn placeholder_shadow(name: String) -> libnss::shadow::Shadow {
+    libnss::shadow::Shadow {
+        name,
+        passwd: "*".to_string(),
+        last_change: -1,
+        change_min_days: -1,
+        change_max_days: -1,
+        change_warn_days: -1,
+        change_inactive_days: -1,
+        expire_date: -1,
+        reserved: 0,
+    }
+}
+
 struct CauthdGroup;
 impl libnss::group::GroupHooks for CauthdGroup {
     fn get_all_entries() -> libnss::interop::Response<Vec<libnss::group::Group>> {
         let mut cl = RPC.lock().unwrap();
         cl.with_client(
             |client| match block_on(client.get_all_groups(context::current())) {
-                Ok(passwds) => Response::Success(passwds.into_iter().map(|x| x.to_nss()).collect()),
-                Err(_e) => Response::Unavail,
+                Ok(Ok(passwds)) => {
+                    Response::Success(passwds.into_iter().map(|x| x.to_nss()).collect())
+                }
+                Ok(Err(_)) | Err(_) => Response::Unavail,
             },
         )
     }
 
     fn get_entry_by_gid(gid: libc::gid_t) -> libnss::interop::Response<libnss::group::Group> {
-        let mut cl = RPC.lock().unwrap();
-        cl.with_client(
-            |client| match block_on(client.get_group_by_gid(context::current(), gid)) {
-                Ok(passwd) => match passwd {
-                    Some(p) => Response::Success(p.to_nss()),
-                    None => Response::NotFound,
-                },
-                Err(_e) => Response::Unavail,
-            },
-        )
+        cached(&GROUP_BY_GID, gid, |client| {
+            block_on(client.get_group_by_gid(context::current(), gid))
+        })
     }
 
     fn get_entry_by_name(name: String) -> libnss::interop::Response<libnss::group::Group> {
-        let mut cl = RPC.lock().unwrap();
-        cl.with_client(|client| {
-            match block_on(client.get_group_by_name(context::current(), name)) {
-                Ok(passwd) => match passwd {
-                    Some(p) => Response::Success(p.to_nss()),
-                    None => Response::NotFound,
-                },
-                Err(_e) => Response::Unavail,
-            }
+        cached(&GROUP_BY_NAME, name.clone(), |client| {
+            block_on(client.get_group_by_name(context::current(), name))
         })
     }
 }
@@ -240,7 +336,7 @@ unsafe extern "C" fn _nss_cosiauthd_getpwuid_r(
 }
 
 #[no_mangle]
-unsafe extern "C" fn nss_cosiauthd_getpwnam_r(
+unsafe extern "C" fn _nss_cosiauthd_getpwnam_r(
     name_: *const libc::c_char,
     result: *mut CPasswd,
     buf: *mut libc::c_char,
@@ -297,7 +393,7 @@ unsafe extern "C" fn _nss_cosiauthd_getgrgid_r(
 }
 
 #[no_mangle]
-unsafe extern "C" fn nss_cosiauthd_getgrnam_r(
+unsafe extern "C" fn _nss_cosiauthd_getgrnam_r(
     name_: *const libc::c_char,
     result: *mut CGroup,
     buf: *mut libc::c_char,
@@ -346,7 +442,7 @@ unsafe extern "C" fn _nss_cosiauthd_getspent_r(
 }
 
 #[no_mangle]
-unsafe extern "C" fn nss_cosiauthd_getspnam_r(
+unsafe extern "C" fn _nss_cosiauthd_getspnam_r(
     name_: *const libc::c_char,
     result: *mut CShadow,
     buf: *mut libc::c_char,
@@ -362,3 +458,45 @@ unsafe extern "C" fn nss_cosiauthd_getspnam_r(
 
     response.to_c(result, buf, buflen, errnop) as c_int
 }
+
+#[no_mangle]
+unsafe extern "C" fn _nss_cosiauthd_setnetgrent(
+    name_: *const libc::c_char,
+    _result: *mut CNetgrent,
+) -> c_int {
+    let name = match CStr::from_ptr(name_).to_str() {
+        Ok(name) => name.to_string(),
+        Err(_) => return Response::<()>::NotFound.to_status() as c_int,
+    };
+    let mut cl = RPC.lock().unwrap();
+    let response = cl.with_client(|client| {
+        match block_on(client.get_netgroup_by_name(context::current(), name)) {
+            Ok(Ok(Some(triples))) => Response::Success(triples),
+            Ok(Ok(None)) => Response::NotFound,
+            Ok(Err(_)) | Err(_) => Response::Unavail,
+        }
+    });
+    let mut iter = NETGROUP_ITERATOR.lock().unwrap();
+    let status = match response {
+        Response::Success(triples) => iter.open(triples),
+        response => response.to_status(),
+    };
+    status as c_int
+}
+
+#[no_mangle]
+unsafe extern "C" fn _nss_cosiauthd_getnetgrent_r(
+    result: *mut CNetgrent,
+    buf: *mut libc::c_char,
+    buflen: libc::size_t,
+    errnop: *mut c_int,
+) -> c_int {
+    let mut iter = NETGROUP_ITERATOR.lock().unwrap();
+    iter.next(result, buf, buflen, errnop) as c_int
+}
+
+#[no_mangle]
+extern "C" fn _nss_cosiauthd_endnetgrent(_result: *mut CNetgrent) -> c_int {
+    let mut iter = NETGROUP_ITERATOR.lock().unwrap();
+    iter.close() as c_int
+}
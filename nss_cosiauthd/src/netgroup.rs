@@ -0,0 +1,96 @@
+//! The netgroup database, which libnss doesn't know about, so we speak glibc's ABI ourselves.
+
+use authd::types::NetgroupTriple;
+use libc::{c_char, c_int, c_void, size_t};
+use libnss::interop::NssStatus;
+
+/// glibc's `struct __netgrent` from `netgroup.h`.
+///
+/// We only ever hand back triples, because authd has already expanded nested netgroups. Our own
+/// iteration state lives in [`NetgroupIterator`], so the bookkeeping fields are left alone.
+#[repr(C)]
+pub struct CNetgrent {
+    /// `triple_val` (0) or `group_val` (1)
+    pub kind: c_int,
+    pub host: *const c_char,
+    pub user: *const c_char,
+    pub domain: *const c_char,
+    pub data: *mut c_char,
+    pub data_size: size_t,
+    pub cursor: *mut c_char,
+    pub first: c_int,
+    pub known_groups: *mut c_void,
+    pub needed_groups: *mut c_void,
+    pub nip: *mut c_void,
+}
+
+const TRIPLE_VAL: c_int = 0;
+
+#[derive(Default)]
+pub struct NetgroupIterator {
+    triples: Vec<NetgroupTriple>,
+    position: usize,
+}
+
+impl NetgroupIterator {
+    pub fn open(&mut self, triples: Vec<NetgroupTriple>) -> NssStatus {
+        self.triples = triples;
+        self.position = 0;
+        NssStatus::Success
+    }
+
+    pub fn close(&mut self) -> NssStatus {
+        self.triples.clear();
+        self.position = 0;
+        NssStatus::Success
+    }
+
+    /// Write the next triple into `result`, with its strings in `buf`.
+    ///
+    /// # Safety
+    ///
+    /// `result`, `errnop` and `buflen` bytes at `buf` must be valid for writes.
+    pub unsafe fn next(
+        &mut self,
+        result: *mut CNetgrent,
+        buf: *mut c_char,
+        buflen: size_t,
+        errnop: *mut c_int,
+    ) -> NssStatus {
+        let triple = match self.triples.get(self.position) {
+            Some(t) => t,
+            None => {
+                *errnop = libc::ENOENT;
+                return NssStatus::NotFound;
+            }
+        };
+        let fields = [&triple.host, &triple.user, &triple.domain];
+        let needed: usize = fields
+            .iter()
+            .map(|f| f.as_ref().map(|f| f.len() + 1).unwrap_or(0))
+            .sum();
+        if needed > buflen {
+            // don't move on, glibc will call again with a bigger buffer
+            *errnop = libc::ERANGE;
+            return NssStatus::TryAgain;
+        }
+
+        let mut cursor = buf;
+        let mut ptrs = [std::ptr::null(); 3];
+        for (field, ptr) in fields.iter().zip(ptrs.iter_mut()) {
+            // wildcards are NULL
+            if let Some(field) = field {
+                std::ptr::copy_nonoverlapping(field.as_ptr() as *const c_char, cursor, field.len());
+                *cursor.add(field.len()) = 0;
+                *ptr = cursor;
+                cursor = cursor.add(field.len() + 1);
+            }
+        }
+        (*result).kind = TRIPLE_VAL;
+        (*result).host = ptrs[0];
+        (*result).user = ptrs[1];
+        (*result).domain = ptrs[2];
+        self.position += 1;
+        NssStatus::Success
+    }
+}
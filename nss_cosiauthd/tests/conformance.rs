@@ -0,0 +1,349 @@
+//! Load the built `libnss_cosiauthd.so` the way glibc would and poke every symbol it should export,
+//! against a real authd running on an ephemeral port.
+
+use libc::{c_char, c_int, size_t};
+use std::ffi::{CStr, CString};
+use std::path::{Path, PathBuf};
+
+const NSS_STATUS_TRYAGAIN: c_int = -2;
+const NSS_STATUS_NOTFOUND: c_int = 0;
+const NSS_STATUS_SUCCESS: c_int = 1;
+
+type SetEnt = unsafe extern "C" fn() -> c_int;
+type GetEnt<T> = unsafe extern "C" fn(*mut T, *mut c_char, size_t, *mut c_int) -> c_int;
+type GetById<T> = unsafe extern "C" fn(u32, *mut T, *mut c_char, size_t, *mut c_int) -> c_int;
+type GetByName<T> =
+    unsafe extern "C" fn(*const c_char, *mut T, *mut c_char, size_t, *mut c_int) -> c_int;
+
+const PASSWD: &str = "alice:x:2001:2001:Alice:/home/alice:/bin/bash
+bob:x:2002:2002:Bob:/home/bob:/bin/sh
+";
+const GROUP: &str = "auth-admins:x:3001:alice
+cosi:x:3000:alice,bob
+";
+const SHADOW: &str = "alice:!:19000:0:99999:7:::
+bob:!:19000:0:99999:7:::
+";
+const NETGROUP: &str = "# lab machines
+labs (lab1,,cosi) \\
+     (lab2,,cosi)
+admins (,alice,cosi)
+everything labs admins (lab1,,cosi)
+loop1 loop2
+loop2 loop1 (x,y,z)
+";
+
+/// Mirror of glibc's `struct __netgrent`.
+#[repr(C)]
+struct Netgrent {
+    kind: c_int,
+    host: *const c_char,
+    user: *const c_char,
+    domain: *const c_char,
+    data: *mut c_char,
+    data_size: size_t,
+    cursor: *mut c_char,
+    first: c_int,
+    known_groups: *mut libc::c_void,
+    needed_groups: *mut libc::c_void,
+    nip: *mut libc::c_void,
+}
+
+fn example_state_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("../example_configs/state-dir")
+}
+
+/// Write out a state-dir and configs for both authd and the NSS module, and start authd.
+fn start_authd(dir: &Path) -> authd::AuthdConfig {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    for f in ["cert.der", "key.der", "opaque"] {
+        std::fs::copy(example_state_dir().join(f), dir.join(f)).unwrap();
+    }
+    std::fs::create_dir(dir.join("cookies")).unwrap();
+    std::fs::write(dir.join("passwd"), PASSWD).unwrap();
+    std::fs::write(dir.join("group"), GROUP).unwrap();
+    std::fs::write(dir.join("shadow"), SHADOW).unwrap();
+    std::fs::write(dir.join("netgroup"), NETGROUP).unwrap();
+    std::fs::write(
+        dir.join("nss_cosiauthd.toml"),
+        format!(
+            "host = '127.0.0.1:{}'\ncert = '{}'\n",
+            port,
+            dir.join("cert.der").display()
+        ),
+    )
+    .unwrap();
+
+    let config: authd::AuthdConfig = toml::from_str(&format!(
+        "bind_addrs = ['127.0.0.1:{port}']
+opaque_server_setup = '{dir}/opaque'
+opaque_cookies = '{dir}/cookies'
+authoritative_name = 'localhost'
+passwd_file = '{dir}/passwd'
+group_file = '{dir}/group'
+shadow_file = '{dir}/shadow'
+netgroup_file = '{dir}/netgroup'
+cert = '{dir}/cert.der'
+key = '{dir}/key.der'
+",
+        port = port,
+        dir = dir.display()
+    ))
+    .unwrap();
+
+    let serve_config = config.clone();
+    std::thread::spawn(move || {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(authd::rpc::serve(serve_config))
+            .unwrap()
+    });
+    while std::net::TcpStream::connect(("127.0.0.1", port)).is_err() {
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    config
+}
+
+fn library_path() -> PathBuf {
+    // target/debug/deps/conformance-xxxx -> target/debug/libnss_cosiauthd.so
+    let exe = std::env::current_exe().unwrap();
+    exe.parent()
+        .and_then(Path::parent)
+        .unwrap()
+        .join("libnss_cosiauthd.so")
+}
+
+struct Nss(libloading::Library);
+
+impl Nss {
+    fn get<T: Copy>(&self, name: &str) -> T {
+        let symbol = format!("_nss_cosiauthd_{}\0", name);
+        unsafe {
+            *self
+                .0
+                .get::<T>(symbol.as_bytes())
+                .unwrap_or_else(|e| panic!("missing symbol _nss_cosiauthd_{}: {}", name, e))
+        }
+    }
+
+    /// Call a `get*_r` function the way glibc does: start with a tiny buffer, and grow it every
+    /// time the module says ERANGE.
+    fn call_r<T>(
+        &self,
+        mut f: impl FnMut(*mut T, *mut c_char, size_t, *mut c_int) -> c_int,
+    ) -> (c_int, T, Vec<u8>) {
+        let mut buflen = 1;
+        let mut saw_erange = false;
+        loop {
+            let mut result: T = unsafe { std::mem::zeroed() };
+            let mut buf = vec![0u8; buflen];
+            let mut errno = 0;
+            let status = f(
+                &mut result,
+                buf.as_mut_ptr() as *mut c_char,
+                buflen,
+                &mut errno,
+            );
+            if status == NSS_STATUS_TRYAGAIN {
+                assert_eq!(errno, libc::ERANGE, "TRYAGAIN should only mean ERANGE here");
+                saw_erange = true;
+                buflen *= 2;
+                assert!(buflen < 1 << 20, "buffer grew without bound");
+                continue;
+            }
+            if status == NSS_STATUS_SUCCESS {
+                assert!(saw_erange, "a 1-byte buffer should never be enough");
+            }
+            return (status, result, buf);
+        }
+    }
+}
+
+unsafe fn s(p: *const c_char) -> String {
+    CStr::from_ptr(p).to_str().unwrap().to_owned()
+}
+
+unsafe fn s_or_wildcard(p: *const c_char) -> Option<String> {
+    if p.is_null() {
+        None
+    } else {
+        Some(s(p))
+    }
+}
+
+unsafe fn members(mut p: *mut *mut c_char) -> Vec<String> {
+    let mut out = vec![];
+    while !(*p).is_null() {
+        out.push(s(*p));
+        p = p.add(1);
+    }
+    out
+}
+
+#[test]
+fn every_symbol_behaves() {
+    let dir = tempfile::tempdir().unwrap();
+    start_authd(dir.path());
+    std::env::set_var("AUTH_CONFIG_DIR", dir.path());
+
+    let nss = Nss(unsafe { libloading::Library::new(library_path()) }
+        .expect("loading libnss_cosiauthd.so, was it built?"));
+
+    // passwd
+    let getpwnam: GetByName<libc::passwd> = nss.get("getpwnam_r");
+    let getpwuid: GetById<libc::passwd> = nss.get("getpwuid_r");
+    let alice = CString::new("alice").unwrap();
+    let (status, pw, _buf) =
+        nss.call_r(|r, b, l, e| unsafe { getpwnam(alice.as_ptr(), r, b, l, e) });
+    assert_eq!(status, NSS_STATUS_SUCCESS);
+    unsafe {
+        assert_eq!(s(pw.pw_name), "alice");
+        assert_eq!(s(pw.pw_dir), "/home/alice");
+        assert_eq!(s(pw.pw_shell), "/bin/bash");
+    }
+    assert_eq!(pw.pw_uid, 2001);
+    let (status, pw, _buf) = nss.call_r(|r, b, l, e| unsafe { getpwuid(2002, r, b, l, e) });
+    assert_eq!(status, NSS_STATUS_SUCCESS);
+    assert_eq!(unsafe { s(pw.pw_name) }, "bob");
+    let nobody = CString::new("nobody-at-all").unwrap();
+    let (status, _, _) = nss.call_r(|r, b, l, e| unsafe { getpwnam(nobody.as_ptr(), r, b, l, e) });
+    assert_eq!(status, NSS_STATUS_NOTFOUND);
+    let (status, _, _) = nss.call_r(|r, b, l, e| unsafe { getpwuid(4242, r, b, l, e) });
+    assert_eq!(status, NSS_STATUS_NOTFOUND);
+
+    let setpwent: SetEnt = nss.get("setpwent");
+    let getpwent: GetEnt<libc::passwd> = nss.get("getpwent_r");
+    let endpwent: SetEnt = nss.get("endpwent");
+    assert_eq!(unsafe { setpwent() }, NSS_STATUS_SUCCESS);
+    let mut names = vec![];
+    loop {
+        let (status, pw, _buf) = nss.call_r(|r, b, l, e| unsafe { getpwent(r, b, l, e) });
+        if status != NSS_STATUS_SUCCESS {
+            assert_eq!(status, NSS_STATUS_NOTFOUND);
+            break;
+        }
+        names.push(unsafe { s(pw.pw_name) });
+    }
+    assert_eq!(names, ["alice", "bob"]);
+    assert_eq!(unsafe { endpwent() }, NSS_STATUS_SUCCESS);
+
+    // group
+    let getgrnam: GetByName<libc::group> = nss.get("getgrnam_r");
+    let getgrgid: GetById<libc::group> = nss.get("getgrgid_r");
+    let cosi = CString::new("cosi").unwrap();
+    let (status, gr, _buf) =
+        nss.call_r(|r, b, l, e| unsafe { getgrnam(cosi.as_ptr(), r, b, l, e) });
+    assert_eq!(status, NSS_STATUS_SUCCESS);
+    assert_eq!(gr.gr_gid, 3000);
+    assert_eq!(unsafe { members(gr.gr_mem) }, ["alice", "bob"]);
+    let (status, gr, _buf) = nss.call_r(|r, b, l, e| unsafe { getgrgid(3001, r, b, l, e) });
+    assert_eq!(status, NSS_STATUS_SUCCESS);
+    assert_eq!(unsafe { s(gr.gr_name) }, "auth-admins");
+    let (status, _, _) = nss.call_r(|r, b, l, e| unsafe { getgrgid(4242, r, b, l, e) });
+    assert_eq!(status, NSS_STATUS_NOTFOUND);
+
+    let setgrent: SetEnt = nss.get("setgrent");
+    let getgrent: GetEnt<libc::group> = nss.get("getgrent_r");
+    let endgrent: SetEnt = nss.get("endgrent");
+    assert_eq!(unsafe { setgrent() }, NSS_STATUS_SUCCESS);
+    let mut names = vec![];
+    loop {
+        let (status, gr, _buf) = nss.call_r(|r, b, l, e| unsafe { getgrent(r, b, l, e) });
+        if status != NSS_STATUS_SUCCESS {
+            assert_eq!(status, NSS_STATUS_NOTFOUND);
+            break;
+        }
+        names.push(unsafe { s(gr.gr_name) });
+    }
+    assert_eq!(names, ["auth-admins", "cosi"]);
+    assert_eq!(unsafe { endgrent() }, NSS_STATUS_SUCCESS);
+
+    // shadow
+    let getspnam: GetByName<libc::spwd> = nss.get("getspnam_r");
+    let (status, sp, _buf) =
+        nss.call_r(|r, b, l, e| unsafe { getspnam(alice.as_ptr(), r, b, l, e) });
+    assert_eq!(status, NSS_STATUS_SUCCESS);
+    assert_eq!(unsafe { s(sp.sp_namp) }, "alice");
+    // we aren't an enrolled host, so the default read policy hides the real entry
+    assert_eq!(unsafe { s(sp.sp_pwdp) }, "*");
+    assert_eq!(sp.sp_lstchg, -1);
+    let (status, _, _) = nss.call_r(|r, b, l, e| unsafe { getspnam(nobody.as_ptr(), r, b, l, e) });
+    assert_eq!(status, NSS_STATUS_NOTFOUND);
+
+    let setspent: SetEnt = nss.get("setspent");
+    let getspent: GetEnt<libc::spwd> = nss.get("getspent_r");
+    let endspent: SetEnt = nss.get("endspent");
+    assert_eq!(unsafe { setspent() }, NSS_STATUS_SUCCESS);
+    let mut names = vec![];
+    loop {
+        let (status, sp, _buf) = nss.call_r(|r, b, l, e| unsafe { getspent(r, b, l, e) });
+        if status != NSS_STATUS_SUCCESS {
+            assert_eq!(status, NSS_STATUS_NOTFOUND);
+            break;
+        }
+        names.push(unsafe { s(sp.sp_namp) });
+    }
+    assert_eq!(names, ["alice", "bob"]);
+    assert_eq!(unsafe { endspent() }, NSS_STATUS_SUCCESS);
+
+    // netgroup
+    let setnetgrent: unsafe extern "C" fn(*const c_char, *mut Netgrent) -> c_int =
+        nss.get("setnetgrent");
+    let getnetgrent: GetEnt<Netgrent> = nss.get("getnetgrent_r");
+    let endnetgrent: unsafe extern "C" fn(*mut Netgrent) -> c_int = nss.get("endnetgrent");
+    let netgroup = |name: &str| {
+        let name = CString::new(name).unwrap();
+        let mut ng: Netgrent = unsafe { std::mem::zeroed() };
+        let status = unsafe { setnetgrent(name.as_ptr(), &mut ng) };
+        if status != NSS_STATUS_SUCCESS {
+            return (status, vec![]);
+        }
+        let mut triples = vec![];
+        loop {
+            let (status, ng, _buf) = nss.call_r(|r, b, l, e| unsafe { getnetgrent(r, b, l, e) });
+            if status != NSS_STATUS_SUCCESS {
+                assert_eq!(status, NSS_STATUS_NOTFOUND);
+                break;
+            }
+            assert_eq!(ng.kind, 0, "nested groups should already be expanded");
+            triples.push(unsafe {
+                (
+                    s_or_wildcard(ng.host),
+                    s_or_wildcard(ng.user),
+                    s_or_wildcard(ng.domain),
+                )
+            });
+        }
+        assert_eq!(unsafe { endnetgrent(&mut ng) }, NSS_STATUS_SUCCESS);
+        triples.sort();
+        (status, triples)
+    };
+    let t = |h: Option<&str>, u: Option<&str>, d: &str| {
+        (
+            h.map(String::from),
+            u.map(String::from),
+            Some(d.to_string()),
+        )
+    };
+    assert_eq!(
+        netgroup("everything"),
+        (
+            NSS_STATUS_SUCCESS,
+            vec![
+                t(None, Some("alice"), "cosi"),
+                t(Some("lab1"), None, "cosi"),
+                t(Some("lab2"), None, "cosi"),
+            ]
+        )
+    );
+    assert_eq!(
+        netgroup("loop1"),
+        (NSS_STATUS_SUCCESS, vec![t(Some("x"), Some("y"), "z")])
+    );
+    assert_eq!(netgroup("no-such-netgroup").0, NSS_STATUS_NOTFOUND);
+}
@@ -0,0 +1,219 @@
+//! Just enough of Linux-PAM's module interface (man pam_get_item(3), pam_conv(3)) to get by.
+
+use libc::{c_char, c_int, c_void};
+use std::ffi::{CStr, CString};
+use zeroize::Zeroizing;
+
+pub const PAM_SUCCESS: c_int = 0;
+pub const PAM_SERVICE_ERR: c_int = 3;
+pub const PAM_SYSTEM_ERR: c_int = 4;
+pub const PAM_BUF_ERR: c_int = 5;
+pub const PAM_PERM_DENIED: c_int = 6;
+pub const PAM_AUTH_ERR: c_int = 7;
+pub const PAM_AUTHINFO_UNAVAIL: c_int = 9;
+pub const PAM_USER_UNKNOWN: c_int = 10;
+pub const PAM_NEW_AUTHTOK_REQD: c_int = 12;
+pub const PAM_ACCT_EXPIRED: c_int = 13;
+pub const PAM_CONV_ERR: c_int = 19;
+pub const PAM_AUTHTOK_ERR: c_int = 20;
+pub const PAM_AUTHTOK_RECOVERY_ERR: c_int = 21;
+
+pub const PAM_SILENT: c_int = 0x8000;
+pub const PAM_PRELIM_CHECK: c_int = 0x4000;
+pub const PAM_UPDATE_AUTHTOK: c_int = 0x2000;
+
+const PAM_CONV: c_int = 5;
+pub const PAM_AUTHTOK: c_int = 6;
+pub const PAM_OLDAUTHTOK: c_int = 7;
+
+const PAM_PROMPT_ECHO_OFF: c_int = 1;
+const PAM_PROMPT_ECHO_ON: c_int = 2;
+const PAM_ERROR_MSG: c_int = 3;
+const PAM_TEXT_INFO: c_int = 4;
+
+/// `pam_handle_t`, which modules only ever see through a pointer.
+#[repr(C)]
+pub struct PamHandle {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct PamMessage {
+    msg_style: c_int,
+    msg: *const c_char,
+}
+
+#[repr(C)]
+struct PamResponse {
+    resp: *mut c_char,
+    resp_retcode: c_int,
+}
+
+#[repr(C)]
+struct PamConv {
+    conv: Option<
+        unsafe extern "C" fn(
+            num_msg: c_int,
+            msg: *mut *const PamMessage,
+            resp: *mut *mut PamResponse,
+            appdata_ptr: *mut c_void,
+        ) -> c_int,
+    >,
+    appdata_ptr: *mut c_void,
+}
+
+#[link(name = "pam")]
+extern "C" {
+    fn pam_get_user(pamh: *mut PamHandle, user: *mut *const c_char, prompt: *const c_char)
+        -> c_int;
+    fn pam_get_item(pamh: *const PamHandle, item_type: c_int, item: *mut *const c_void) -> c_int;
+    fn pam_set_item(pamh: *mut PamHandle, item_type: c_int, item: *const c_void) -> c_int;
+}
+
+/// The handle PAM gave us, with the calls we need. Errors are PAM return codes.
+pub struct Pam(*mut PamHandle);
+
+impl Pam {
+    /// # Safety
+    ///
+    /// `pamh` must be the handle PAM passed to the `pam_sm_*` function we are in.
+    pub unsafe fn new(pamh: *mut PamHandle) -> Self {
+        Pam(pamh)
+    }
+
+    /// The user being authenticated, asking for it if the application hasn't said.
+    pub fn user(&self) -> Result<String, c_int> {
+        let mut user = std::ptr::null();
+        let ret = unsafe { pam_get_user(self.0, &mut user, std::ptr::null()) };
+        if ret != PAM_SUCCESS {
+            return Err(ret);
+        }
+        if user.is_null() {
+            return Err(PAM_USER_UNKNOWN);
+        }
+        unsafe { CStr::from_ptr(user) }
+            .to_str()
+            .map(ToOwned::to_owned)
+            .map_err(|_| PAM_USER_UNKNOWN)
+    }
+
+    /// A string item such as [`PAM_AUTHTOK`], if it has been set.
+    pub fn get_item(&self, item_type: c_int) -> Result<Option<Zeroizing<Vec<u8>>>, c_int> {
+        let mut item = std::ptr::null();
+        let ret = unsafe { pam_get_item(self.0, item_type, &mut item) };
+        if ret != PAM_SUCCESS {
+            return Err(ret);
+        }
+        if item.is_null() {
+            return Ok(None);
+        }
+        let bytes = unsafe { CStr::from_ptr(item as *const c_char) }.to_bytes();
+        Ok(Some(Zeroizing::new(bytes.to_vec())))
+    }
+
+    /// Set a string item. PAM keeps its own copy.
+    pub fn set_item(&self, item_type: c_int, value: &[u8]) -> Result<(), c_int> {
+        let value = Zeroizing::new(
+            CString::new(value)
+                .map_err(|_| PAM_BUF_ERR)?
+                .into_bytes_with_nul(),
+        );
+        let ret = unsafe { pam_set_item(self.0, item_type, value.as_ptr() as *const c_void) };
+        if ret != PAM_SUCCESS {
+            return Err(ret);
+        }
+        Ok(())
+    }
+
+    /// Ask the user something through the application's conversation function.
+    fn converse(&self, style: c_int, msg: &str) -> Result<Option<Zeroizing<Vec<u8>>>, c_int> {
+        let mut conv: *const c_void = std::ptr::null();
+        let ret = unsafe { pam_get_item(self.0, PAM_CONV, &mut conv) };
+        if ret != PAM_SUCCESS {
+            return Err(ret);
+        }
+        let conv = match unsafe { (conv as *const PamConv).as_ref() } {
+            Some(PamConv {
+                conv: Some(f),
+                appdata_ptr,
+            }) => (f, *appdata_ptr),
+            _ => return Err(PAM_CONV_ERR),
+        };
+
+        let text = CString::new(msg).map_err(|_| PAM_BUF_ERR)?;
+        let message = PamMessage {
+            msg_style: style,
+            msg: text.as_ptr(),
+        };
+        let mut messages = [&message as *const PamMessage];
+        let mut resp: *mut PamResponse = std::ptr::null_mut();
+        let ret = unsafe { (conv.0)(1, messages.as_mut_ptr(), &mut resp, conv.1) };
+        if ret != PAM_SUCCESS {
+            return Err(ret);
+        }
+        if resp.is_null() {
+            return Ok(None);
+        }
+
+        // the application malloc'd both the array and the string, and we have to free them
+        unsafe {
+            let answer = if (*resp).resp.is_null() {
+                None
+            } else {
+                let answer = CStr::from_ptr((*resp).resp).to_bytes();
+                let copy = Zeroizing::new(answer.to_vec());
+                std::ptr::write_bytes((*resp).resp, 0, answer.len());
+                libc::free((*resp).resp as *mut c_void);
+                Some(copy)
+            };
+            libc::free(resp as *mut c_void);
+            Ok(answer)
+        }
+    }
+
+    /// Prompt for a secret without echoing it.
+    pub fn prompt_secret(&self, prompt: &str) -> Result<Zeroizing<Vec<u8>>, c_int> {
+        self.converse(PAM_PROMPT_ECHO_OFF, prompt)?
+            .ok_or(PAM_CONV_ERR)
+    }
+
+    /// Prompt for something that may be shown as it is typed.
+    pub fn prompt(&self, prompt: &str) -> Result<Zeroizing<Vec<u8>>, c_int> {
+        self.converse(PAM_PROMPT_ECHO_ON, prompt)?
+            .ok_or(PAM_CONV_ERR)
+    }
+
+    /// Tell the user something. Failing to is not worth failing the whole stack over.
+    pub fn info(&self, msg: &str) {
+        let _ = self.converse(PAM_TEXT_INFO, msg);
+    }
+
+    /// Tell the user what went wrong.
+    pub fn error(&self, msg: &str) {
+        let _ = self.converse(PAM_ERROR_MSG, msg);
+    }
+}
+
+/// The module arguments from the PAM config line.
+///
+/// # Safety
+///
+/// `argv` must point to `argc` valid C strings.
+pub unsafe fn args(argc: c_int, argv: *const *const c_char) -> Vec<String> {
+    (0..argc as usize)
+        .map(|i| CStr::from_ptr(*argv.add(i)).to_string_lossy().into_owned())
+        .collect()
+}
+
+/// Log to the auth facility of syslog, where PAM modules are expected to complain.
+pub fn log(priority: c_int, msg: &str) {
+    if let Ok(msg) = CString::new(format!("pam_cosiauthd: {}", msg)) {
+        unsafe {
+            libc::syslog(
+                libc::LOG_AUTHPRIV | priority,
+                b"%s\0".as_ptr() as *const c_char,
+                msg.as_ptr(),
+            )
+        };
+    }
+}
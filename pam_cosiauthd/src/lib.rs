@@ -0,0 +1,649 @@
+//! PAM module that checks passwords against authd with OPAQUE, so authd users can log in.
+
+use authd::rpc::{AuthdClient, RpcError};
+use authd::types::AccountStatus;
+use authd::LoginError;
+use ffi::*;
+use libc::{c_char, c_int};
+use std::time::Duration;
+use tarpc::context;
+use zeroize::Zeroizing;
+
+mod ffi;
+
+#[derive(serde::Deserialize)]
+struct PamConfig {
+    host: authd::SocketName,
+    /// `cert`, `certs`, `spki_pins`, `ca_bundle` and `server_name`.
+    #[serde(flatten)]
+    trust: authd::tls::ServerTrust,
+    /// Give up on authd after this many seconds.
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+    /// Name this host logs in to authd as, see `auth enroll-host`. Account management needs it,
+    /// since account status comes from shadow.
+    host_principal: Option<String>,
+    /// File holding the host's password.
+    host_secret: Option<String>,
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+impl PamConfig {
+    fn load() -> anyhow::Result<Self> {
+        let path = authd::find_config_dir()?.join("pam_cosiauthd.toml");
+        let mut cfg: PamConfig = toml::from_slice(&std::fs::read(path)?)?;
+        cfg.trust.expand();
+        cfg.host_secret = cfg
+            .host_secret
+            .map(|p| shellexpand::full(&p).expect("expanding host_secret").into());
+        Ok(cfg)
+    }
+}
+
+/// The options from the PAM config line that we understand.
+#[derive(Default)]
+struct Options {
+    /// Try the password an earlier module collected before asking for one.
+    try_first_pass: bool,
+    /// Only ever use the password an earlier module collected.
+    use_first_pass: bool,
+    /// When changing passwords, take the new one from an earlier module (e.g. pam_pwquality).
+    use_authtok: bool,
+    /// Move accounts that only have a crypt(3) hash to OPAQUE as they log in.
+    migrate_legacy: bool,
+    debug: bool,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Self {
+        let mut opts = Options::default();
+        for arg in args {
+            match arg.as_str() {
+                "try_first_pass" => opts.try_first_pass = true,
+                "use_first_pass" => opts.use_first_pass = true,
+                "use_authtok" => opts.use_authtok = true,
+                "migrate_legacy" => opts.migrate_legacy = true,
+                "debug" => opts.debug = true,
+                other => log(libc::LOG_WARNING, &format!("unknown option {}", other)),
+            }
+        }
+        opts
+    }
+
+    fn debug(&self, msg: &str) {
+        if self.debug {
+            log(libc::LOG_DEBUG, msg);
+        }
+    }
+}
+
+/// What PAM should hear when authd says no.
+fn rpc_error_to_pam(e: &RpcError) -> c_int {
+    match e {
+        RpcError::NotAuthorized => PAM_PERM_DENIED,
+        RpcError::AuthenticationFailure => PAM_AUTH_ERR,
+        RpcError::NotConfigured => PAM_SERVICE_ERR,
+        RpcError::Invalid(_) => PAM_SYSTEM_ERR,
+        RpcError::PasswordChangeTooSoon => PAM_AUTHTOK_ERR,
+    }
+}
+
+fn login_error_to_pam(e: &LoginError) -> c_int {
+    match e {
+        LoginError::Unavailable(_) => PAM_AUTHINFO_UNAVAIL,
+        LoginError::Rejected(e) => rpc_error_to_pam(e),
+        LoginError::BadPassword => PAM_AUTH_ERR,
+        LoginError::TotpRequired => PAM_AUTH_ERR,
+        // there's no enrolling from a login prompt
+        LoginError::TotpEnrollmentRequired => PAM_PERM_DENIED,
+        LoginError::Protocol(_) => PAM_SYSTEM_ERR,
+    }
+}
+
+/// What PAM should hear when this host can't log in. A bad host secret, or a host account that
+/// wants a TOTP code, is our problem and not the user's.
+fn host_login_error_to_pam(e: &LoginError) -> c_int {
+    match login_error_to_pam(e) {
+        PAM_AUTH_ERR | PAM_PERM_DENIED => PAM_AUTHINFO_UNAVAIL,
+        code => code,
+    }
+}
+
+/// Everything a `pam_sm_*` call needs: the handle, options, config and a way to run async code.
+struct Module {
+    pam: Pam,
+    /// PAM_SILENT: don't say anything to the user.
+    silent: bool,
+    opts: Options,
+    cfg: PamConfig,
+    rt: tokio::runtime::Runtime,
+}
+
+impl Module {
+    unsafe fn new(
+        pamh: *mut PamHandle,
+        flags: c_int,
+        argc: c_int,
+        argv: *const *const c_char,
+    ) -> Result<Self, c_int> {
+        let opts = Options::parse(&args(argc, argv));
+        let cfg = PamConfig::load().map_err(|e| {
+            log(libc::LOG_ERR, &format!("loading pam_cosiauthd.toml: {}", e));
+            PAM_SERVICE_ERR
+        })?;
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|_| PAM_SYSTEM_ERR)?;
+        Ok(Module {
+            pam: Pam::new(pamh),
+            silent: flags & PAM_SILENT != 0,
+            opts,
+            cfg,
+            rt,
+        })
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(self.cfg.timeout)
+    }
+
+    /// Connect to authd, giving up after the timeout rather than leaving the user at a hung login
+    /// prompt.
+    fn connect(&self) -> Result<AuthdClient, c_int> {
+        use std::net::ToSocketAddrs;
+        let addr = self
+            .cfg
+            .host
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .ok_or(PAM_AUTHINFO_UNAVAIL)?;
+        let server_name = self.cfg.trust.server_name_for(&self.cfg.host);
+        let connect = authd::client_connect(addr, &self.cfg.trust, &server_name);
+        match self
+            .rt
+            .block_on(tokio::time::timeout(self.timeout(), connect))
+        {
+            Ok(Ok(client)) => Ok(client),
+            Ok(Err(e)) => {
+                log(libc::LOG_ERR, &format!("connecting to authd: {}", e));
+                Err(PAM_AUTHINFO_UNAVAIL)
+            }
+            Err(_) => {
+                log(libc::LOG_ERR, "timed out connecting to authd");
+                Err(PAM_AUTHINFO_UNAVAIL)
+            }
+        }
+    }
+
+    fn ctx(&self) -> context::Context {
+        let mut ctx = context::current();
+        ctx.deadline = std::time::SystemTime::now() + self.timeout();
+        ctx
+    }
+
+    /// Does authd know this user? Not being allowed to look is not a no.
+    fn check_user_exists(&self, client: &AuthdClient, user: &str) -> Result<(), c_int> {
+        match self
+            .rt
+            .block_on(client.get_passwd_by_name(self.ctx(), user.to_owned()))
+        {
+            Ok(Ok(Some(_))) | Ok(Err(RpcError::NotAuthorized)) => Ok(()),
+            Ok(Ok(None)) => Err(PAM_USER_UNKNOWN),
+            Ok(Err(e)) => Err(rpc_error_to_pam(&e)),
+            Err(_) => Err(PAM_AUTHINFO_UNAVAIL),
+        }
+    }
+
+    fn login(&self, client: &AuthdClient, user: &str, password: &[u8]) -> Result<(), c_int> {
+        if self.opts.migrate_legacy {
+            self.migrate_legacy(client, user, password)?;
+        }
+        let login = authd::client_login(client, user, password);
+        match self
+            .rt
+            .block_on(tokio::time::timeout(self.timeout(), login))
+        {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(LoginError::TotpRequired)) => self.verify_totp(client),
+            Ok(Err(e)) => {
+                if let LoginError::TotpEnrollmentRequired = e {
+                    self.error("Enroll in TOTP with `auth totp enroll` before logging in here.");
+                }
+                self.opts
+                    .debug(&format!("login for {} failed: {}", user, e));
+                Err(login_error_to_pam(&e))
+            }
+            Err(_) => Err(PAM_AUTHINFO_UNAVAIL),
+        }
+    }
+
+    /// Move `user` to OPAQUE with `password` if they only have a crypt(3) hash, so the login
+    /// after this can work.
+    fn migrate_legacy(
+        &self,
+        client: &AuthdClient,
+        user: &str,
+        password: &[u8],
+    ) -> Result<(), c_int> {
+        if !self.legacy_login_available(user) {
+            return Ok(());
+        }
+        let migrate = authd::client_migrate_legacy(client, user, password);
+        match self
+            .rt
+            .block_on(tokio::time::timeout(self.timeout(), migrate))
+        {
+            Ok(Ok(())) => {
+                log(libc::LOG_INFO, &format!("moved {} to OPAQUE", user));
+                Ok(())
+            }
+            Ok(Err(e)) => {
+                self.opts
+                    .debug(&format!("migrating {} failed: {}", user, e));
+                Err(login_error_to_pam(&e))
+            }
+            Err(_) => Err(PAM_AUTHINFO_UNAVAIL),
+        }
+    }
+
+    /// Whether `user` still has a crypt(3) hash to move off. authd only tells hosts, so this asks
+    /// on a connection of its own logged in as this one. If that doesn't work, the login goes on
+    /// as if there were nothing to move.
+    fn legacy_login_available(&self, user: &str) -> bool {
+        if self.cfg.host_principal.is_none() || self.cfg.host_secret.is_none() {
+            log(
+                libc::LOG_WARNING,
+                "migrate_legacy needs host_principal and host_secret",
+            );
+            return false;
+        }
+        let host = match self.connect().and_then(|host| {
+            self.login_as_host(&host)?;
+            Ok(host)
+        }) {
+            Ok(host) => host,
+            Err(_) => return false,
+        };
+        match self
+            .rt
+            .block_on(host.legacy_login_available(self.ctx(), user.to_owned()))
+        {
+            Ok(Ok(available)) => available,
+            Ok(Err(e)) => {
+                log(
+                    libc::LOG_ERR,
+                    &format!("asking whether {} can migrate: {}", user, e),
+                );
+                false
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Finish a login that needs a second factor by asking for a TOTP code.
+    fn verify_totp(&self, client: &AuthdClient) -> Result<(), c_int> {
+        let code = self.pam.prompt("Verification code: ")?;
+        let code = String::from_utf8_lossy(&code).into_owned();
+        match self.rt.block_on(client.verify_totp(self.ctx(), code)) {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => Err(rpc_error_to_pam(&e)),
+            Err(_) => Err(PAM_AUTHINFO_UNAVAIL),
+        }
+    }
+
+    /// Log in as this host, so that we may ask about accounts. This is a plain OPAQUE login with
+    /// the host secret: it never migrates or prompts for anything.
+    fn login_as_host(&self, client: &AuthdClient) -> Result<(), c_int> {
+        let (principal, secret) = match (&self.cfg.host_principal, &self.cfg.host_secret) {
+            (Some(principal), Some(secret)) => (principal, secret),
+            _ => return Ok(()),
+        };
+        let secret = Zeroizing::new(std::fs::read_to_string(secret).map_err(|e| {
+            log(libc::LOG_ERR, &format!("reading host_secret: {}", e));
+            PAM_AUTHINFO_UNAVAIL
+        })?);
+        let login = authd::client_login(client, principal, secret.trim_end().as_bytes());
+        match self
+            .rt
+            .block_on(tokio::time::timeout(self.timeout(), login))
+        {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => {
+                log(
+                    libc::LOG_ERR,
+                    &format!("host login as {} failed: {}", principal, e),
+                );
+                Err(host_login_error_to_pam(&e))
+            }
+            Err(_) => Err(PAM_AUTHINFO_UNAVAIL),
+        }
+    }
+
+    /// The name authd knows this host by.
+    fn host_name(&self) -> String {
+        if let Some(principal) = &self.cfg.host_principal {
+            return principal.clone();
+        }
+        let mut buf = [0u8; 256];
+        let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut c_char, buf.len()) };
+        if ret != 0 {
+            return String::new();
+        }
+        let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+        String::from_utf8_lossy(&buf[..len]).into_owned()
+    }
+
+    fn account_status(&self, client: &AuthdClient, user: &str) -> Result<AccountStatus, c_int> {
+        match self
+            .rt
+            .block_on(client.account_status(self.ctx(), user.to_owned(), self.host_name()))
+        {
+            Ok(Ok(status)) => Ok(status),
+            Ok(Err(e)) => Err(rpc_error_to_pam(&e)),
+            Err(_) => Err(PAM_AUTHINFO_UNAVAIL),
+        }
+    }
+
+    fn info(&self, msg: &str) {
+        if !self.silent {
+            self.pam.info(msg);
+        }
+    }
+
+    fn error(&self, msg: &str) {
+        if !self.silent {
+            self.pam.error(msg);
+        }
+    }
+
+    /// Log `user` in on `client`, getting the password the way the options say to: from the
+    /// `item` an earlier module left, or by asking with `prompt`.
+    fn authenticate(
+        &self,
+        client: &AuthdClient,
+        user: &str,
+        item: c_int,
+        prompt: &str,
+    ) -> Result<(), c_int> {
+        if self.opts.try_first_pass || self.opts.use_first_pass {
+            match self.pam.get_item(item)? {
+                Some(password) => match self.login(client, user, &password) {
+                    Ok(()) => return Ok(()),
+                    Err(e) if self.opts.use_first_pass || e != PAM_AUTH_ERR => return Err(e),
+                    // try_first_pass falls back to asking
+                    Err(_) => {}
+                },
+                None if self.opts.use_first_pass => return Err(PAM_AUTH_ERR),
+                None => {}
+            }
+        }
+
+        let password = self.pam.prompt_secret(prompt)?;
+        self.login(client, user, &password)?;
+        // let later modules in the stack use it too
+        self.pam.set_item(item, &password)?;
+        Ok(())
+    }
+
+    /// The password to change to, from an earlier module with `use_authtok`, otherwise asked for
+    /// twice.
+    fn new_password(&self) -> Result<Zeroizing<Vec<u8>>, c_int> {
+        if self.opts.use_authtok {
+            return self.pam.get_item(PAM_AUTHTOK)?.ok_or(PAM_AUTHTOK_ERR);
+        }
+        let password = self.pam.prompt_secret("New password: ")?;
+        if password.is_empty() {
+            self.error("No password has been supplied.");
+            return Err(PAM_AUTHTOK_ERR);
+        }
+        let again = self.pam.prompt_secret("Retype new password: ")?;
+        if password != again {
+            self.error("Sorry, passwords do not match.");
+            return Err(PAM_AUTHTOK_ERR);
+        }
+        self.pam.set_item(PAM_AUTHTOK, &password)?;
+        Ok(password)
+    }
+
+    /// Re-register the credential of whoever is logged in on `client`.
+    fn change_password(&self, client: &AuthdClient, password: &[u8]) -> Result<(), c_int> {
+        let change = authd::client_change_password(client, password);
+        match self
+            .rt
+            .block_on(tokio::time::timeout(self.timeout(), change))
+        {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(LoginError::Rejected(RpcError::PasswordChangeTooSoon))) => {
+                self.error("You must wait longer to change your password.");
+                Err(PAM_AUTHTOK_ERR)
+            }
+            Ok(Err(e)) => {
+                log(libc::LOG_ERR, &format!("changing password: {}", e));
+                Err(login_error_to_pam(&e))
+            }
+            Err(_) => Err(PAM_AUTHINFO_UNAVAIL),
+        }
+    }
+}
+
+fn status(result: Result<(), c_int>) -> c_int {
+    match result {
+        Ok(()) => PAM_SUCCESS,
+        Err(e) => e,
+    }
+}
+
+unsafe fn sm_authenticate(
+    pamh: *mut PamHandle,
+    flags: c_int,
+    argc: c_int,
+    argv: *const *const c_char,
+) -> Result<(), c_int> {
+    let module = Module::new(pamh, flags, argc, argv)?;
+    let user = module.pam.user()?;
+    let client = module.connect()?;
+    module.check_user_exists(&client, &user)?;
+    let result = module.authenticate(&client, &user, PAM_AUTHTOK, "Password: ");
+    module
+        .opts
+        .debug(&format!("authenticate {}: {:?}", user, result));
+    result
+}
+
+#[no_mangle]
+unsafe extern "C" fn pam_sm_authenticate(
+    pamh: *mut PamHandle,
+    flags: c_int,
+    argc: c_int,
+    argv: *const *const c_char,
+) -> c_int {
+    status(sm_authenticate(pamh, flags, argc, argv))
+}
+
+/// We don't hand out any credentials, but PAM wants this to exist next to `pam_sm_authenticate`.
+#[no_mangle]
+extern "C" fn pam_sm_setcred(
+    _pamh: *mut PamHandle,
+    _flags: c_int,
+    _argc: c_int,
+    _argv: *const *const c_char,
+) -> c_int {
+    PAM_SUCCESS
+}
+
+unsafe fn sm_acct_mgmt(
+    pamh: *mut PamHandle,
+    flags: c_int,
+    argc: c_int,
+    argv: *const *const c_char,
+) -> Result<(), c_int> {
+    let module = Module::new(pamh, flags, argc, argv)?;
+    let user = module.pam.user()?;
+    let client = module.connect()?;
+    module.login_as_host(&client)?;
+    let status = module.account_status(&client, &user)?;
+    module
+        .opts
+        .debug(&format!("account status of {}: {:?}", user, status));
+    match status {
+        AccountStatus::Ok => Ok(()),
+        AccountStatus::PasswordExpiresSoon(days) => {
+            module.info(&format!("Your password will expire in {} day(s).", days));
+            Ok(())
+        }
+        AccountStatus::PasswordExpired => {
+            module.info("You are required to change your password immediately.");
+            Err(PAM_NEW_AUTHTOK_REQD)
+        }
+        AccountStatus::AccountExpired => {
+            module.error("Your account has expired; please contact your system administrator.");
+            Err(PAM_ACCT_EXPIRED)
+        }
+        AccountStatus::Locked | AccountStatus::HostDenied => Err(PAM_PERM_DENIED),
+        AccountStatus::UnknownUser => Err(PAM_USER_UNKNOWN),
+    }
+}
+
+#[no_mangle]
+unsafe extern "C" fn pam_sm_acct_mgmt(
+    pamh: *mut PamHandle,
+    flags: c_int,
+    argc: c_int,
+    argv: *const *const c_char,
+) -> c_int {
+    status(sm_acct_mgmt(pamh, flags, argc, argv))
+}
+
+unsafe fn sm_chauthtok(
+    pamh: *mut PamHandle,
+    flags: c_int,
+    argc: c_int,
+    argv: *const *const c_char,
+) -> Result<(), c_int> {
+    let module = Module::new(pamh, flags, argc, argv)?;
+    let user = module.pam.user()?;
+    let client = module.connect()?;
+    module.check_user_exists(&client, &user)?;
+    // the first pass only checks that we will be able to change the password
+    if flags & PAM_PRELIM_CHECK != 0 {
+        return Ok(());
+    }
+    if flags & PAM_UPDATE_AUTHTOK == 0 {
+        return Err(PAM_SERVICE_ERR);
+    }
+
+    // OPAQUE needs the old password to prove it's really them
+    module
+        .authenticate(&client, &user, PAM_OLDAUTHTOK, "Current password: ")
+        .map_err(|e| {
+            if e == PAM_AUTH_ERR {
+                PAM_AUTHTOK_RECOVERY_ERR
+            } else {
+                e
+            }
+        })?;
+    let password = module.new_password()?;
+    module.change_password(&client, &password)?;
+    module.opts.debug(&format!("changed password for {}", user));
+    Ok(())
+}
+
+#[no_mangle]
+unsafe extern "C" fn pam_sm_chauthtok(
+    pamh: *mut PamHandle,
+    flags: c_int,
+    argc: c_int,
+    argv: *const *const c_char,
+) -> c_int {
+    status(sm_chauthtok(pamh, flags, argc, argv))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Options {
+        Options::parse(&args.iter().map(|a| a.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn rpc_errors_map_to_pam_codes() {
+        assert_eq!(rpc_error_to_pam(&RpcError::NotAuthorized), PAM_PERM_DENIED);
+        assert_eq!(
+            rpc_error_to_pam(&RpcError::AuthenticationFailure),
+            PAM_AUTH_ERR
+        );
+        assert_eq!(rpc_error_to_pam(&RpcError::NotConfigured), PAM_SERVICE_ERR);
+        assert_eq!(
+            rpc_error_to_pam(&RpcError::Invalid("nope".into())),
+            PAM_SYSTEM_ERR
+        );
+        assert_eq!(
+            rpc_error_to_pam(&RpcError::PasswordChangeTooSoon),
+            PAM_AUTHTOK_ERR
+        );
+    }
+
+    #[test]
+    fn login_errors_map_to_pam_codes() {
+        let unavailable = LoginError::Unavailable(tarpc::client::RpcError::DeadlineExceeded);
+        assert_eq!(login_error_to_pam(&unavailable), PAM_AUTHINFO_UNAVAIL);
+        assert_eq!(
+            login_error_to_pam(&LoginError::Rejected(RpcError::NotAuthorized)),
+            PAM_PERM_DENIED
+        );
+        assert_eq!(login_error_to_pam(&LoginError::BadPassword), PAM_AUTH_ERR);
+        assert_eq!(login_error_to_pam(&LoginError::TotpRequired), PAM_AUTH_ERR);
+        assert_eq!(
+            login_error_to_pam(&LoginError::TotpEnrollmentRequired),
+            PAM_PERM_DENIED
+        );
+        assert_eq!(
+            login_error_to_pam(&LoginError::Protocol("oops".into())),
+            PAM_SYSTEM_ERR
+        );
+    }
+
+    #[test]
+    fn host_login_failures_are_not_the_users_fault() {
+        for e in [
+            LoginError::BadPassword,
+            LoginError::TotpRequired,
+            LoginError::TotpEnrollmentRequired,
+            LoginError::Rejected(RpcError::NotAuthorized),
+        ] {
+            assert_eq!(host_login_error_to_pam(&e), PAM_AUTHINFO_UNAVAIL, "{}", e);
+        }
+        assert_eq!(
+            host_login_error_to_pam(&LoginError::Protocol("oops".into())),
+            PAM_SYSTEM_ERR
+        );
+    }
+
+    #[test]
+    fn parses_options() {
+        let opts = parse(&[]);
+        assert!(!opts.try_first_pass && !opts.use_first_pass);
+        assert!(!opts.use_authtok && !opts.migrate_legacy && !opts.debug);
+
+        let opts = parse(&["try_first_pass", "use_authtok"]);
+        assert!(opts.try_first_pass && opts.use_authtok);
+        assert!(!opts.use_first_pass && !opts.migrate_legacy);
+
+        let opts = parse(&["use_first_pass", "migrate_legacy", "debug"]);
+        assert!(opts.use_first_pass && opts.migrate_legacy && opts.debug);
+        assert!(!opts.try_first_pass && !opts.use_authtok);
+    }
+
+    #[test]
+    fn unknown_options_are_ignored() {
+        let opts = parse(&["nullok", "try_first_pass", "audit"]);
+        assert!(opts.try_first_pass);
+        assert!(!opts.use_first_pass && !opts.use_authtok && !opts.migrate_legacy);
+    }
+}